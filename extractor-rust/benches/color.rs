@@ -0,0 +1,31 @@
+#![feature(test)]
+
+extern crate test;
+
+use extractor_rust::color::{CachedColor, Color, RGB};
+use test::{Bencher, black_box};
+
+// Background colors are queried once for every pixel that is compared with the background, the
+// benchmarks compare converting a lazy color on every query with reading the cached conversion.
+
+const QUERIES: usize = 1000;
+
+#[bench]
+fn lab_of_lazy_color(b: &mut Bencher) {
+    let color = Color::from(RGB::new(120, 130, 140));
+    b.iter(|| {
+        for _ in 0..QUERIES {
+            black_box(black_box(&color).lab());
+        }
+    });
+}
+
+#[bench]
+fn lab_of_cached_color(b: &mut Bencher) {
+    let color = CachedColor::from(Color::from(RGB::new(120, 130, 140)));
+    b.iter(|| {
+        for _ in 0..QUERIES {
+            black_box(black_box(&color).lab());
+        }
+    });
+}
@@ -1,10 +1,22 @@
 use crate::errors::Result;
 use anyhow::anyhow;
+use serde::Deserialize;
 
 const REFERENCE_X: f32 = 109.850;
 const REFERENCE_Y: f32 = 100.000;
 const REFERENCE_Z: f32 = 35.585;
 
+// White point of the D65 illuminant which sRGB colors are converted to XYZ with. Neutral colors
+// only have a and b equal to 0 in LAB relative to this white point.
+const D65_REFERENCE_X: f32 = 95.047;
+const D65_REFERENCE_Y: f32 = 100.000;
+const D65_REFERENCE_Z: f32 = 108.883;
+
+// Colors whose linear sRGB channels are at most this far outside of [0, 1] are considered to
+// be inside of the gamut, the chroma is reduced in this many bisection steps.
+const GAMUT_TOLERANCE: f32 = 0.001;
+const GAMUT_CLIP_ITERATIONS: usize = 20;
+
 #[derive(Clone)]
 pub struct AlphaColor {
     color: Color,
@@ -38,6 +50,84 @@ impl AlphaColor {
     pub fn is_transparent(&self) -> bool {
         self.alpha == 0
     }
+
+    pub fn is_opaque(&self) -> bool {
+        self.alpha == 255
+    }
+
+    // Returns the same color with a different alpha. Fully transparent colors are always black
+    // so that they compare equal to the ones created with new_transparent.
+    pub fn with_alpha(&self, alpha: u8) -> AlphaColor {
+        if alpha == 0 {
+            return AlphaColor::new_transparent();
+        }
+
+        AlphaColor::new(self.color.clone(), alpha)
+    }
+
+    // Returns the color multiplied by its alpha i.e. the color it has over a black background.
+    pub fn premultiplied(&self) -> RGB {
+        let rgb = self.color.rgb();
+        let multiply = |v: u8| (v as f32 * self.alpha as f32 / 255.0).round() as u8;
+        RGB::new(multiply(rgb.r()), multiply(rgb.g()), multiply(rgb.b()))
+    }
+
+    // Averages the colors in the LAB color space weighting each color by its alpha so that semi
+    // transparent colors e.g. the antialiased edges of a sticker, which are partially mixed
+    // with what was behind them, contribute proportionally less than the opaque ones. Returns
+    // None if all colors are transparent.
+    pub fn average_lab<'a>(colors: impl IntoIterator<Item = &'a AlphaColor>) -> Option<LAB> {
+        let mut sum = [0.0f64; 3];
+        let mut weights = 0.0f64;
+
+        for color in colors {
+            let weight = color.alpha as f64 / 255.0;
+            if weight == 0.0 {
+                continue;
+            }
+
+            let lab = color.color.lab();
+            sum[0] += lab.l as f64 * weight;
+            sum[1] += lab.a as f64 * weight;
+            sum[2] += lab.b as f64 * weight;
+            weights += weight;
+        }
+
+        if weights == 0.0 {
+            return None;
+        }
+
+        Some(LAB {
+            l: (sum[0] / weights) as f32,
+            a: (sum[1] / weights) as f32,
+            b: (sum[2] / weights) as f32,
+        })
+    }
+
+    // Composites this color over the given background color using the standard "over"
+    // operator.
+    pub fn over(&self, background: &AlphaColor) -> AlphaColor {
+        let alpha = self.alpha as f32 / 255.0;
+        let background_alpha = background.alpha as f32 / 255.0;
+        let result_alpha = alpha + background_alpha * (1.0 - alpha);
+        if result_alpha == 0.0 {
+            return AlphaColor::new_transparent();
+        }
+
+        let foreground = self.color.rgb();
+        let background = background.color.rgb();
+        let blend = |f: u8, b: u8| {
+            let v = (f as f32 * alpha + b as f32 * background_alpha * (1.0 - alpha)) / result_alpha;
+            v.round().clamp(0.0, 255.0) as u8
+        };
+
+        let rgb = RGB::new(
+            blend(foreground.r(), background.r()),
+            blend(foreground.g(), background.g()),
+            blend(foreground.b(), background.b()),
+        );
+        AlphaColor::new(rgb.into(), (result_alpha * 255.0).round() as u8)
+    }
 }
 
 #[derive(Clone)]
@@ -88,9 +178,170 @@ impl Color {
         }
     }
 
+    pub fn hsv(&self) -> HSV {
+        (&self.rgb()).into()
+    }
+
     pub fn opaque(&self) -> AlphaColor {
         AlphaColor::new_opaque(self.clone())
     }
+
+    // Formats the color as #rrggbb.
+    pub fn hex(&self) -> String {
+        let rgb = self.rgb();
+        format!("#{:02x}{:02x}{:02x}", rgb.r, rgb.g, rgb.b)
+    }
+
+    // Parses a color formatted as #rrggbb.
+    pub fn from_hex(hex: &str) -> Result<Color> {
+        let digits = hex
+            .strip_prefix('#')
+            .ok_or_else(|| anyhow!("hex color must start with #"))?;
+
+        if digits.len() != 6 || !digits.chars().all(|v| v.is_ascii_hexdigit()) {
+            return Err(anyhow!("hex color must consist of six hex digits"));
+        }
+
+        let r = u8::from_str_radix(&digits[0..2], 16)?;
+        let g = u8::from_str_radix(&digits[2..4], 16)?;
+        let b = u8::from_str_radix(&digits[4..6], 16)?;
+        Ok(RGB::new(r, g, b).into())
+    }
+
+    // Colorfulness of the color, the distance from the neutral axis in the LAB color space
+    // relative to the white point of sRGB so that all grays have a chroma of 0.
+    pub fn chroma(&self) -> f32 {
+        let xyz: XYZ = (&self.rgb()).into();
+        let lab = xyz.lab_with_reference(D65_REFERENCE_X, D65_REFERENCE_Y, D65_REFERENCE_Z);
+        (lab.a.powi(2) + lab.b.powi(2)).sqrt()
+    }
+
+    // Checks if the chroma of the color is below the threshold e.g. to tell white markers and
+    // gray backgrounds apart from colorful stickers.
+    pub fn is_near_gray(&self, threshold: f32) -> bool {
+        self.chroma() < threshold
+    }
+
+    // Returns the channels of the color in the given color space, used to calculate weighted
+    // averages of colors.
+    pub fn channels(&self, space: MixSpace) -> [f32; 3] {
+        match space {
+            MixSpace::Rgb => {
+                let rgb = self.rgb();
+                [rgb.r as f32, rgb.g as f32, rgb.b as f32]
+            }
+            MixSpace::Yuv => {
+                let yuv = self.yuv();
+                [yuv.y, yuv.u, yuv.v]
+            }
+            MixSpace::Lab => {
+                let lab = self.lab();
+                [lab.l, lab.a, lab.b]
+            }
+        }
+    }
+
+    pub fn from_channels(channels: [f32; 3], space: MixSpace) -> Result<Color> {
+        let [c0, c1, c2] = channels;
+        Ok(match space {
+            MixSpace::Rgb => RGB::new(
+                c0.round().clamp(0.0, 255.0) as u8,
+                c1.round().clamp(0.0, 255.0) as u8,
+                c2.round().clamp(0.0, 255.0) as u8,
+            )
+            .into(),
+            MixSpace::Yuv => YUV::new(c0, c1, c2)?.into(),
+            MixSpace::Lab => LAB::new(c0, c1, c2)?.into(),
+        })
+    }
+
+    // Linearly interpolates between this color (t=0) and the other color (t=1) in the given
+    // color space.
+    pub fn mix(&self, other: &Color, t: f32, space: MixSpace) -> Color {
+        let t = t.clamp(0.0, 1.0);
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+
+        match space {
+            MixSpace::Rgb => {
+                let a = self.rgb();
+                let b = other.rgb();
+                RGB {
+                    r: lerp(a.r as f32, b.r as f32).round() as u8,
+                    g: lerp(a.g as f32, b.g as f32).round() as u8,
+                    b: lerp(a.b as f32, b.b as f32).round() as u8,
+                }
+                .into()
+            }
+            MixSpace::Yuv => {
+                let a = self.yuv();
+                let b = other.yuv();
+                YUV {
+                    y: lerp(a.y, b.y),
+                    u: lerp(a.u, b.u),
+                    v: lerp(a.v, b.v),
+                }
+                .into()
+            }
+            MixSpace::Lab => {
+                let a = self.lab();
+                let b = other.lab();
+                LAB {
+                    l: lerp(a.l, b.l),
+                    a: lerp(a.a, b.a),
+                    b: lerp(a.b, b.b),
+                }
+                .into()
+            }
+        }
+    }
+}
+
+// Color with all of its representations calculated upfront so that they aren't converted again
+// every time they are needed. Used for the interpolated background colors which are compared
+// with many pixels, other colors are converted lazily to keep them small.
+#[derive(Clone)]
+pub struct CachedColor {
+    color: Color,
+    rgb: RGB,
+    yuv: YUV,
+    lab: LAB,
+}
+
+impl CachedColor {
+    pub fn color(&self) -> &Color {
+        &self.color
+    }
+
+    pub fn rgb(&self) -> RGB {
+        self.rgb.clone()
+    }
+
+    pub fn yuv(&self) -> YUV {
+        self.yuv.clone()
+    }
+
+    pub fn lab(&self) -> LAB {
+        self.lab.clone()
+    }
+}
+
+impl From<Color> for CachedColor {
+    fn from(value: Color) -> Self {
+        Self {
+            rgb: value.rgb(),
+            yuv: value.yuv(),
+            lab: value.lab(),
+            color: value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MixSpace {
+    Rgb,
+    Yuv,
+    Lab,
 }
 
 impl From<RGB> for Color {
@@ -140,17 +391,52 @@ impl RGB {
     pub fn b(&self) -> u8 {
         self.b
     }
+
+    // Creates a color from linear light values in range [0, 1] by applying the sRGB transfer
+    // function. Values outside of the range are clamped.
+    pub fn from_linear(r: f32, g: f32, b: f32) -> Self {
+        let convert = |v: f32| (linear_to_srgb(v.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        Self {
+            r: convert(r),
+            g: convert(g),
+            b: convert(b),
+        }
+    }
+
+    // Returns linear light values in range [0, 1] by undoing the sRGB transfer function.
+    pub fn to_linear(&self) -> (f32, f32, f32) {
+        let convert = |v: u8| srgb_to_linear(v as f32 / 255.0);
+        (convert(self.r), convert(self.g), convert(self.b))
+    }
+}
+
+// sRGB transfer function, both the input and the output are in range [0, 1].
+fn linear_to_srgb(v: f32) -> f32 {
+    if v > 0.0031308 {
+        1.055 * (v.powf(1.0 / 2.4)) - 0.055
+    } else {
+        v * 12.92
+    }
+}
+
+// Inverse of the sRGB transfer function, both the input and the output are in range [0, 1].
+fn srgb_to_linear(v: f32) -> f32 {
+    if v > 0.04045 {
+        ((v + 0.055) / 1.055).powf(2.4)
+    } else {
+        v / 12.92
+    }
 }
 
 impl From<&YUV> for RGB {
     fn from(value: &YUV) -> Self {
         let r = value.y + 1.14 * value.v;
-        let g = value.y - 0.395 * value.u * 0.581 * value.v;
+        let g = value.y - 0.395 * value.u - 0.581 * value.v;
         let b = value.y + 2.033 * value.u;
         RGB {
-            r: (r * 255.0) as u8,
-            g: (g * 255.0) as u8,
-            b: (b * 255.0) as u8,
+            r: (r * 255.0).round() as u8,
+            g: (g * 255.0).round() as u8,
+            b: (b * 255.0).round() as u8,
         }
     }
 }
@@ -160,38 +446,20 @@ impl From<&XYZ> for RGB {
         //X, Y and Z input refer to a D65/2° standard illuminant.
         //sr, sg and sb (standard RGB) output range = 0 ÷ 255
 
-        let var_x = value.x / 100.0;
-        let var_y = value.y / 100.0;
-        let var_z = value.z / 100.0;
-
-        let mut var_r = var_x * 3.2406 + var_y * -1.5372 + var_z * -0.4986;
-        let mut var_g = var_x * -0.9689 + var_y * 1.8758 + var_z * 0.0415;
-        let mut var_b = var_x * 0.0557 + var_y * -0.2040 + var_z * 1.0570;
+        let [mut var_r, mut var_g, mut var_b] = value.linear_rgb();
 
-        if var_r > 0.0031308 {
-            var_r = 1.055 * (var_r.powf(1.0 / 2.4)) - 0.055
-        } else {
-            var_r *= 12.92
-        }
-        if var_g > 0.0031308 {
-            var_g = 1.055 * (var_g.powf(1.0 / 2.4)) - 0.055
-        } else {
-            var_g *= 12.92
-        }
-        if var_b > 0.0031308 {
-            var_b = 1.055 * (var_b.powf(1.0 / 2.4)) - 0.055
-        } else {
-            var_b *= 12.92
-        }
+        var_r = linear_to_srgb(var_r);
+        var_g = linear_to_srgb(var_g);
+        var_b = linear_to_srgb(var_b);
 
         let sr = var_r * 255.0;
         let sg = var_g * 255.0;
         let sb = var_b * 255.0;
 
         Self {
-            r: sr as u8,
-            g: sg as u8,
-            b: sb as u8,
+            r: sr.round() as u8,
+            g: sg.round() as u8,
+            b: sb.round() as u8,
         }
     }
 }
@@ -271,6 +539,59 @@ impl From<&RGB> for YUV {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct HSV {
+    h: f32, // [0, 360)
+    s: f32, // [0, 1]
+    v: f32, // [0, 1]
+}
+
+impl HSV {
+    pub fn h(&self) -> f32 {
+        self.h
+    }
+
+    pub fn s(&self) -> f32 {
+        self.s
+    }
+
+    pub fn v(&self) -> f32 {
+        self.v
+    }
+
+    // Distance between the hues in degrees taking into account that the hue wraps around.
+    pub fn hue_distance(&self, other: &HSV) -> f32 {
+        let distance = (self.h - other.h).abs() % 360.0;
+        distance.min(360.0 - distance)
+    }
+}
+
+impl From<&RGB> for HSV {
+    fn from(value: &RGB) -> Self {
+        let r = value.r as f32 / 255.0;
+        let g = value.g as f32 / 255.0;
+        let b = value.b as f32 / 255.0;
+
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let h = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+
+        let s = if max == 0.0 { 0.0 } else { delta / max };
+
+        HSV { h, s, v: max }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LAB {
     l: f32,
@@ -283,11 +604,128 @@ impl LAB {
         Ok(Self { l, a, b })
     }
 
+    // Converts the color to sRGB reducing its chroma while keeping its lightness and hue until
+    // it fits into the sRGB gamut, instead of clipping each channel separately which shifts
+    // the hue of saturated colors e.g. extrapolated background colors.
+    pub fn clip_to_srgb(&self) -> RGB {
+        let l = self.l.clamp(0.0, 100.0);
+        let with_chroma = |k: f32| LAB {
+            l,
+            a: self.a * k,
+            b: self.b * k,
+        };
+        let in_gamut = |lab: &LAB| {
+            XYZ::from(lab)
+                .linear_rgb()
+                .iter()
+                .all(|v| (-GAMUT_TOLERANCE..=1.0 + GAMUT_TOLERANCE).contains(v))
+        };
+
+        let lab = with_chroma(1.0);
+        if in_gamut(&lab) {
+            return (&XYZ::from(&lab)).into();
+        }
+
+        // Even a color without any chroma can be out of gamut if its lightness is, then the
+        // channels are clipped after all.
+        let (mut inside, mut outside) = (0.0, 1.0);
+        for _ in 0..GAMUT_CLIP_ITERATIONS {
+            let k = (inside + outside) / 2.0;
+            if in_gamut(&with_chroma(k)) {
+                inside = k;
+            } else {
+                outside = k;
+            }
+        }
+
+        (&XYZ::from(&with_chroma(inside))).into()
+    }
+
     pub fn distance(&self, other: &LAB) -> f32 {
         ((other.l - self.l).powi(2) + (other.a - self.a).powi(2) + (other.b - self.b).powi(2))
             .sqrt()
     }
 
+    // Unlike in YUV::similar the tolerances are in the units of LAB as a and b aren't bounded.
+    // The difference of a and b is compared as the distance between the colors in the a-b
+    // plane.
+    pub fn similar(&self, other: &Self, epsilon_l: f32, epsilon_ab: f32) -> bool {
+        if (self.l - other.l).abs() > epsilon_l {
+            return false;
+        }
+
+        ((self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt() <= epsilon_ab
+    }
+
+    // CIEDE2000 color difference which unlike the euclidean distance corresponds well to the
+    // perceived difference between the colors.
+    pub fn delta_e_2000(&self, other: &LAB) -> f32 {
+        let (l1, a1, b1) = (self.l as f64, self.a as f64, self.b as f64);
+        let (l2, a2, b2) = (other.l as f64, other.a as f64, other.b as f64);
+
+        let c1 = (a1 * a1 + b1 * b1).sqrt();
+        let c2 = (a2 * a2 + b2 * b2).sqrt();
+        let c_mean = (c1 + c2) / 2.0;
+        let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt());
+
+        let a1 = a1 * (1.0 + g);
+        let a2 = a2 * (1.0 + g);
+        let c1 = (a1 * a1 + b1 * b1).sqrt();
+        let c2 = (a2 * a2 + b2 * b2).sqrt();
+
+        let hue = |a: f64, b: f64| {
+            if a == 0.0 && b == 0.0 {
+                0.0
+            } else {
+                b.atan2(a).to_degrees().rem_euclid(360.0)
+            }
+        };
+        let h1 = hue(a1, b1);
+        let h2 = hue(a2, b2);
+
+        let delta_l = l2 - l1;
+        let delta_c = c2 - c1;
+        let delta_h = if c1 * c2 == 0.0 {
+            0.0
+        } else if (h2 - h1).abs() <= 180.0 {
+            h2 - h1
+        } else if h2 - h1 > 180.0 {
+            h2 - h1 - 360.0
+        } else {
+            h2 - h1 + 360.0
+        };
+        let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h.to_radians() / 2.0).sin();
+
+        let l_mean = (l1 + l2) / 2.0;
+        let c_mean = (c1 + c2) / 2.0;
+        let h_mean = if c1 * c2 == 0.0 {
+            h1 + h2
+        } else if (h1 - h2).abs() <= 180.0 {
+            (h1 + h2) / 2.0
+        } else if h1 + h2 < 360.0 {
+            (h1 + h2 + 360.0) / 2.0
+        } else {
+            (h1 + h2 - 360.0) / 2.0
+        };
+
+        let t = 1.0 - 0.17 * (h_mean - 30.0).to_radians().cos()
+            + 0.24 * (2.0 * h_mean).to_radians().cos()
+            + 0.32 * (3.0 * h_mean + 6.0).to_radians().cos()
+            - 0.20 * (4.0 * h_mean - 63.0).to_radians().cos();
+
+        let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+        let r_c = 2.0 * (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt();
+        let s_l = 1.0 + (0.015 * (l_mean - 50.0).powi(2)) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+        let s_c = 1.0 + 0.045 * c_mean;
+        let s_h = 1.0 + 0.015 * c_mean * t;
+        let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+        let l = delta_l / s_l;
+        let c = delta_c / s_c;
+        let h = delta_h / s_h;
+        (l * l + c * c + h * h + r_t * c * h).sqrt() as f32
+    }
+
     pub fn l(&self) -> f32 {
         self.l
     }
@@ -303,35 +741,7 @@ impl LAB {
 
 impl From<&XYZ> for LAB {
     fn from(value: &XYZ) -> Self {
-        //Reference-X, Y and Z refer to specific illuminants and observers.
-        //Common reference values are available below in this same page.
-        let mut var_x = value.x / REFERENCE_X;
-        let mut var_y = value.y / REFERENCE_Y;
-        let mut var_z = value.z / REFERENCE_Z;
-
-        if var_x > 0.008856 {
-            var_x = var_x.powf(1.0 / 3.0);
-        } else {
-            var_x = (7.787 * var_x) + (16.0 / 116.0);
-        }
-
-        if var_y > 0.008856 {
-            var_y = var_y.powf(1.0 / 3.0);
-        } else {
-            var_y = (7.787 * var_y) + (16.0 / 116.0);
-        }
-
-        if var_z > 0.008856 {
-            var_z = var_z.powf(1.0 / 3.0);
-        } else {
-            var_z = (7.787 * var_z) + (16.0 / 116.0);
-        }
-
-        let l = (116.0 * var_y) - 16.0;
-        let a = 500.0 * (var_x - var_y);
-        let b = 200.0 * (var_y - var_z);
-
-        Self { l, a, b }
+        value.lab_with_reference(REFERENCE_X, REFERENCE_Y, REFERENCE_Z)
     }
 }
 
@@ -351,33 +761,66 @@ impl From<&RGB> for XYZ {
         let mut var_g = value.g as f32 / 255.0;
         let mut var_b = value.b as f32 / 255.0;
 
-        if var_r > 0.04045 {
-            var_r = ((var_r + 0.055) / 1.055).powf(2.4)
+        var_r = srgb_to_linear(var_r);
+        var_g = srgb_to_linear(var_g);
+        var_b = srgb_to_linear(var_b);
+
+        var_r *= 100.0;
+        var_g *= 100.0;
+        var_b *= 100.0;
+
+        let x = var_r * 0.4124 + var_g * 0.3576 + var_b * 0.1805;
+        let y = var_r * 0.2126 + var_g * 0.7152 + var_b * 0.0722;
+        let z = var_r * 0.0193 + var_g * 0.1192 + var_b * 0.9505;
+
+        Self { x, y, z }
+    }
+}
+
+impl XYZ {
+    fn lab_with_reference(&self, reference_x: f32, reference_y: f32, reference_z: f32) -> LAB {
+        //Reference-X, Y and Z refer to specific illuminants and observers.
+        //Common reference values are available below in this same page.
+        let mut var_x = self.x / reference_x;
+        let mut var_y = self.y / reference_y;
+        let mut var_z = self.z / reference_z;
+
+        if var_x > 0.008856 {
+            var_x = var_x.powf(1.0 / 3.0);
         } else {
-            var_r /= 12.92;
+            var_x = (7.787 * var_x) + (16.0 / 116.0);
         }
 
-        if var_g > 0.04045 {
-            var_g = ((var_g + 0.055) / 1.055).powf(2.4);
+        if var_y > 0.008856 {
+            var_y = var_y.powf(1.0 / 3.0);
         } else {
-            var_g /= 12.92;
+            var_y = (7.787 * var_y) + (16.0 / 116.0);
         }
 
-        if var_b > 0.04045 {
-            var_b = ((var_b + 0.055) / 1.055).powf(2.4);
+        if var_z > 0.008856 {
+            var_z = var_z.powf(1.0 / 3.0);
         } else {
-            var_b /= 12.92
+            var_z = (7.787 * var_z) + (16.0 / 116.0);
         }
 
-        var_r *= 100.0;
-        var_g *= 100.0;
-        var_b *= 100.0;
+        let l = (116.0 * var_y) - 16.0;
+        let a = 500.0 * (var_x - var_y);
+        let b = 200.0 * (var_y - var_z);
 
-        let x = var_r * 0.4124 + var_g * 0.3576 + var_b * 0.1805;
-        let y = var_r * 0.2126 + var_g * 0.7152 + var_b * 0.0722;
-        let z = var_r * 0.0193 + var_g * 0.1192 + var_b * 0.9505;
+        LAB { l, a, b }
+    }
 
-        Self { x, y, z }
+    // Linear sRGB channels which are in [0, 1] for colors inside of the sRGB gamut.
+    fn linear_rgb(&self) -> [f32; 3] {
+        let var_x = self.x / 100.0;
+        let var_y = self.y / 100.0;
+        let var_z = self.z / 100.0;
+
+        [
+            var_x * 3.2406 + var_y * -1.5372 + var_z * -0.4986,
+            var_x * -0.9689 + var_y * 1.8758 + var_z * 0.0415,
+            var_x * 0.0557 + var_y * -0.2040 + var_z * 1.0570,
+        ]
     }
 }
 
@@ -422,3 +865,182 @@ enum SomeColor {
     Yuv(YUV),
     Lab(LAB),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(rgb: RGB) -> (u8, u8, u8) {
+        (rgb.r(), rgb.g(), rgb.b())
+    }
+
+    #[test]
+    fn mix_interpolates_between_colors() {
+        let black: Color = RGB::new(0, 0, 0).into();
+        let white: Color = RGB::new(255, 255, 255).into();
+
+        assert_eq!(
+            channels(black.mix(&white, 0.5, MixSpace::Rgb).rgb()),
+            (128, 128, 128)
+        );
+        for space in [MixSpace::Rgb, MixSpace::Yuv, MixSpace::Lab] {
+            assert_eq!(channels(black.mix(&white, 0.0, space).rgb()), (0, 0, 0));
+            assert_eq!(
+                channels(black.mix(&white, 2.0, space).rgb()),
+                (255, 255, 255)
+            );
+        }
+
+        let red: Color = RGB::new(255, 0, 0).into();
+        let green: Color = RGB::new(0, 255, 0).into();
+        for space in [MixSpace::Rgb, MixSpace::Yuv, MixSpace::Lab] {
+            assert_eq!(channels(red.mix(&green, 0.0, space).rgb()), (255, 0, 0));
+            assert_eq!(channels(red.mix(&green, 1.0, space).rgb()), (0, 255, 0));
+        }
+        assert_ne!(
+            channels(red.mix(&green, 0.5, MixSpace::Lab).rgb()),
+            channels(red.mix(&green, 0.5, MixSpace::Rgb).rgb())
+        );
+    }
+
+    #[test]
+    fn linear_rgb_round_trip() {
+        for v in 0..=255u8 {
+            let (r, g, b) = RGB::new(v, 255 - v, v / 2).to_linear();
+            assert_eq!(channels(RGB::from_linear(r, g, b)), (v, 255 - v, v / 2));
+        }
+        assert_eq!(channels(RGB::from_linear(-1.0, 0.5, 2.0)), (0, 188, 255));
+    }
+
+    #[test]
+    fn opacity_utilities() {
+        let color = AlphaColor::new_opaque(RGB::new(200, 100, 51).into());
+        assert!(color.is_opaque() && !color.is_transparent());
+
+        let transparent = color.with_alpha(0);
+        assert!(transparent.is_transparent());
+        assert_eq!(channels(transparent.color().rgb()), (0, 0, 0));
+
+        let half = color.with_alpha(128);
+        assert!(!half.is_opaque() && !half.is_transparent());
+        assert_eq!(channels(half.premultiplied()), (100, 50, 26));
+    }
+
+    #[test]
+    fn cached_color_returns_identical_conversions() {
+        let color: Color = LAB::new(62.0, 18.5, -31.25).unwrap().into();
+        let cached = CachedColor::from(color.clone());
+
+        for _ in 0..3 {
+            let (lab, expected) = (cached.lab(), color.lab());
+            assert_eq!(
+                [lab.l().to_bits(), lab.a().to_bits(), lab.b().to_bits()],
+                [
+                    expected.l().to_bits(),
+                    expected.a().to_bits(),
+                    expected.b().to_bits()
+                ]
+            );
+
+            let (yuv, expected) = (cached.yuv(), color.yuv());
+            assert_eq!(
+                [yuv.y().to_bits(), yuv.u().to_bits(), yuv.v().to_bits()],
+                [
+                    expected.y().to_bits(),
+                    expected.u().to_bits(),
+                    expected.v().to_bits()
+                ]
+            );
+
+            assert_eq!(channels(cached.rgb()), channels(color.rgb()));
+            assert_eq!(cached.color().hex(), color.hex());
+        }
+    }
+
+    #[test]
+    fn gamut_clip_preserves_hue() {
+        let lab = LAB::new(50.0, 120.0, -90.0).unwrap();
+        let hue = |lab: &LAB| lab.b().atan2(lab.a());
+
+        let naive = Color::from(lab.clone()).rgb();
+        let clipped = lab.clip_to_srgb();
+        let naive_error = (hue(&Color::from(naive).lab()) - hue(&lab)).abs();
+        let clipped_error = (hue(&Color::from(clipped).lab()) - hue(&lab)).abs();
+        assert!(clipped_error < naive_error);
+    }
+
+    #[test]
+    fn lab_similar_compares_lightness_and_chroma_separately() {
+        let lab = LAB::new(50.0, 10.0, -10.0).unwrap();
+
+        let lighter = LAB::new(60.0, 10.0, -10.0).unwrap();
+        assert!(!lab.similar(&lighter, 5.0, 100.0));
+        assert!(lab.similar(&lighter, 10.0, 0.0));
+
+        let shifted = LAB::new(50.0, 13.0, -6.0).unwrap();
+        assert!(lab.similar(&shifted, 0.0, 5.0));
+        assert!(!lab.similar(&shifted, 0.0, 4.9));
+    }
+
+    #[test]
+    fn near_gray_colors_have_low_chroma() {
+        let gray: Color = RGB::new(128, 128, 128).into();
+        let tinted: Color = RGB::new(128, 128, 140).into();
+        let red: Color = RGB::new(200, 30, 30).into();
+
+        assert!(gray.chroma() < 0.01);
+        assert!(gray.is_near_gray(5.0));
+        assert!(!tinted.is_near_gray(5.0) && tinted.is_near_gray(15.0));
+        assert!(!red.is_near_gray(15.0));
+    }
+
+    #[test]
+    fn delta_e_2000_matches_reference_values() {
+        // Pairs from the test data published with the CIEDE2000 paper by Sharma et al.
+        let pairs = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((2.0776, 0.0795, -1.135), (0.9033, -0.0636, -0.5514), 0.9082),
+        ];
+        for ((l1, a1, b1), (l2, a2, b2), expected) in pairs {
+            let first = LAB::new(l1, a1, b1).unwrap();
+            let second = LAB::new(l2, a2, b2).unwrap();
+            assert!((first.delta_e_2000(&second) - expected).abs() < 1e-3);
+            assert!((second.delta_e_2000(&first) - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn average_lab_is_weighted_by_alpha() {
+        let dark = LAB::new(20.0, 10.0, -10.0).unwrap();
+        let light = LAB::new(80.0, -10.0, 30.0).unwrap();
+        let colors = [
+            AlphaColor::new_opaque(dark.clone().into()),
+            AlphaColor::new(light.clone().into(), 85),
+            AlphaColor::new(RGB::new(0, 0, 255).into(), 0),
+        ];
+
+        let average = AlphaColor::average_lab(&colors).unwrap();
+        assert!((average.l() - (dark.l() * 3.0 + light.l()) / 4.0).abs() < 1e-3);
+        assert!((average.a() - (dark.a() * 3.0 + light.a()) / 4.0).abs() < 1e-3);
+
+        assert!(AlphaColor::average_lab(&[AlphaColor::new_transparent()]).is_none());
+    }
+
+    #[test]
+    fn over_composites_colors() {
+        let red = AlphaColor::new(RGB::new(255, 0, 0).into(), 128);
+        let blue = AlphaColor::new_opaque(RGB::new(0, 0, 255).into());
+        let transparent = AlphaColor::new_transparent();
+
+        let blended = red.over(&blue);
+        assert_eq!(channels(blended.color().rgb()), (128, 0, 127));
+        assert_eq!(blended.alpha(), 255);
+
+        let blended = red.over(&transparent);
+        assert_eq!(channels(blended.color().rgb()), (255, 0, 0));
+        assert_eq!(blended.alpha(), 128);
+        assert!(transparent.over(&transparent).is_transparent());
+        assert_eq!(channels(blue.over(&red).color().rgb()), (0, 0, 255));
+    }
+}
@@ -0,0 +1,1174 @@
+use crate::{
+    color::{Color, MixSpace},
+    errors::Result,
+    extractor::DEFAULT_HIGHLIGHT_CEILING,
+};
+use anyhow::{Context, anyhow};
+use image::imageops::FilterType;
+use log::warn;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, atomic::AtomicBool},
+};
+
+// Newest version of the config file format which can be loaded. Config files may specify the
+// version they were written for using a top level `version` key.
+const CONFIG_VERSION: u32 = 1;
+
+// Pixels cropped beyond the markers by default, the bounding boxes of the markers don't
+// include their antialiased edges.
+const DEFAULT_INITIAL_CROP_MARGIN: u32 = 2;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ExtractionConfig {
+    // Save images produced by the intermediate stages of the extraction process.
+    pub save_intermediate_images: bool,
+
+    // Directory into which the intermediate images are written.
+    pub preview_directory: PathBuf,
+
+    // Process only this region of the photo as if the photo was cropped to it. Positions in the
+    // manifest are relative to the region except for the corners of the stickers in the photo.
+    pub region_of_interest: Option<RegionOfInterest>,
+
+    // Invert the colors of the photo before processing it e.g. for negative scans.
+    pub invert_colors: InvertColors,
+
+    // Look for multiple sets of markers in each photo and process each sheet separately.
+    pub multiple_sheets: bool,
+
+    // Consider stickers to be in the same column if their centers are this far away
+    // horizontally, expressed as a fraction of image width.
+    pub snap_stickers_threshold_x: f32,
+
+    // Consider stickers to be in the same row if their centers are this far away vertically,
+    // expressed as a fraction of image height.
+    pub snap_stickers_threshold_y: f32,
+
+    // Expected width to height ratio of the sheet. If the markers indicate that the sheet was
+    // photographed sideways then the image is rotated before correcting the perspective. The
+    // perspective corrected sheet has these proportions instead of the proportions of the
+    // photo, if not set the proportions of the sheet size are used.
+    pub sheet_aspect_ratio: Option<f32>,
+
+    // Calculate background differences in tiles of this size instead of for the entire image at
+    // once to limit memory usage when processing very large images.
+    pub tile_size: Option<u32>,
+
+    // How much is cropped off the edges of the perspective corrected image to get rid of the
+    // markers before looking for the stickers.
+    pub initial_crop: InitialCrop,
+
+    // Write the entire sheet after correcting the perspective and removing the background in
+    // addition to the individual stickers.
+    pub save_full_image: bool,
+
+    // Write the pixels removed as the background, before correcting the perspective, e.g. to
+    // composite the stickers back onto the photo.
+    pub save_background_image: bool,
+
+    // Process everything in a single thread and in a fixed order so that the output of
+    // repeated runs is identical e.g. when comparing it with reference images.
+    pub deterministic: bool,
+
+    // Write the entire sheet after correcting the perspective but before cropping it, with the
+    // markers colored red, to check that the right markers were found.
+    pub save_marker_image: bool,
+
+    // Pixels which touch only diagonally are a part of the same sticker if set to eight.
+    pub sticker_connectivity: Connectivity,
+
+    // Pixels whose alpha is below this value aren't considered to be a part of a sticker when
+    // looking for stickers e.g. faint feathered edges.
+    pub sticker_alpha_threshold: u8,
+
+    // Separate stickers which touch each other by splitting the regions which are pinched in
+    // the middle. This is slower and may split stickers with narrow waists.
+    pub split_touching_stickers: bool,
+
+    // Expected number of columns and rows of stickers on the sheet. If set the stickers are
+    // assigned to the cells of an evenly spaced grid and empty cells are reported instead of
+    // renumbering the following stickers.
+    pub grid: Option<GridShape>,
+
+    // Name the stickers using their position in reading order instead of their column and row.
+    pub numbered_file_names: bool,
+
+    // Crop each sticker to the smallest rotated rectangle containing it and rotate it so that
+    // the rectangle is upright instead of using the axis aligned bounding box.
+    pub trim_rotated: bool,
+
+    // How the rotation of each sticker is determined when trimming rotated stickers.
+    pub trim_rotation: TrimRotation,
+
+    // Format in which the stickers are written.
+    pub output_format: OutputFormat,
+
+    // Quality of JPEG from 1 to 100, lower values produce smaller files, 90 if it isn't set. PNG
+    // ignores it. WebP is always written losslessly so setting the quality is an error.
+    pub quality: Option<u8>,
+
+    // Write PNG stickers with at most this many distinct colors, up to 256, using an indexed
+    // palette which is much smaller than truecolor e.g. for pixel art.
+    pub indexed_png_max_colors: Option<usize>,
+
+    // Write each sticker once per target in the format of the target into the directory of the
+    // target, relative directories are relative to the output directory. If there are no
+    // targets the stickers are written in the output format into the output directory.
+    pub output_targets: Vec<OutputTarget>,
+
+    // Write the stickers of each photo together with a manifest describing them into a zip
+    // archive named after the photo instead of writing them as separate files.
+    pub archive: bool,
+
+    // Write the outline of each sticker traced along the edges of its opaque pixels as an SVG
+    // file next to the image of the sticker, e.g. as a cut line for a plotter.
+    pub save_outlines: bool,
+
+    // Also write a copy of each sticker downscaled so that its longer side is at most this many
+    // pixels as a PNG file next to the image of the sticker, e.g. for a catalog.
+    pub thumbnail_size: Option<u32>,
+
+    // Number of markers on the sheet. Two markers placed in the top left and bottom right
+    // corners allow correcting translation and scale, three markers placed in all corners but
+    // the bottom right one allow an affine correction.
+    pub marker_count: usize,
+
+    // Number of steps, each 1% of the image width and height, performed when looking for each
+    // marker starting from the corner of the image. Fewer steps make the search fail faster.
+    pub marker_scan_steps: u32,
+
+    // Overrides the number of marker scan steps for individual corners e.g. if the markers are
+    // always further from the corners of the photo on one side of the sheet.
+    pub corner_scan_steps: CornerScanSteps,
+
+    // Colors of the markers tried in order until one of them yields a valid set of markers
+    // e.g. when sheets with white and black markers are processed together.
+    pub marker_colors: Vec<MarkerColor>,
+
+    // Image of a single marker. If the markers can't be found by their color they are looked
+    // for by comparing the corners of the photo with this image.
+    pub marker_template: Option<PathBuf>,
+
+    // Known positions of the markers e.g. if the camera and the sheet are fixed in place. The
+    // markers aren't looked for in the photo if they are given.
+    pub marker_positions: Option<MarkerPositions>,
+
+    // What to do if markers are found in all corners but they aren't in the expected positions
+    // relative to each other e.g. because the sheet was photographed upside down.
+    pub marker_order: MarkerOrder,
+
+    // Large regions whose pixels have all channels at or above this value are specular
+    // highlights e.g. glare on a glossy sheet and aren't mistaken for markers.
+    pub highlight_ceiling: u8,
+
+    // Reject photos whose sharpness, measured as the variance of the Laplacian of the sheet, is
+    // below this value as blurry photos produce stickers with bad edges.
+    pub min_sharpness: Option<f32>,
+
+    // Write a JSON manifest describing the extracted stickers into the output directory.
+    pub save_manifest: bool,
+
+    // Include the areas used to sample the background and their colors in the manifest.
+    pub save_background_samples: bool,
+
+    // How the background color of each pixel is determined from the background samples.
+    pub background_reference: BackgroundReference,
+
+    // Divide out the differences in the brightness of the markers before analysing the
+    // background to compensate for uneven lighting. Requires all four markers.
+    pub correct_exposure: bool,
+
+    // How much each background sample contributes to the background color of each pixel
+    // depending on its distance from the pixel.
+    pub background_weighting: BackgroundWeighting,
+
+    // Color space in which the background samples are interpolated. Interpolating in LAB
+    // matches the space in which the differences from the background are calculated.
+    pub background_interpolation: MixSpace,
+
+    // Average each background sample with the sample on the opposite side of the sheet. This
+    // stabilises the background model if the sheet is lit symmetrically.
+    pub mirror_background_samples: bool,
+
+    // How the differences between the pixels and the background are scaled before comparing
+    // them with the detection thresholds.
+    pub background_normalisation: BackgroundNormalisation,
+
+    // Scale the thresholds used to decide which pixels are a part of the background by the
+    // noise of the photo measured in the background samples, loosening them for noisy photos
+    // taken at high ISO and tightening them for clean ones.
+    pub noise_adaptive_thresholds: bool,
+
+    // Filter used when correcting the perspective and resizing images. If not set a bilinear
+    // filter is used.
+    pub resample_filter: Option<ResampleFilter>,
+
+    // Fill used for the parts of the perspective corrected image which are outside of the
+    // photo.
+    pub virtual_pixel: VirtualPixel,
+
+    // Margin in pixels added around the sheet when correcting the perspective, the markers are
+    // mapped to the corners of the rectangle inset by the margin instead of the corners of the
+    // image e.g. to leave room when stitching several sheets together.
+    pub perspective_margin: u32,
+
+    // Remove the background by making pixels similar in hue to the color of a solid backdrop
+    // transparent instead of comparing them with the background sampled around the markers.
+    pub chroma_key: Option<ChromaKey>,
+
+    // Physical size of the sheet used to calculate the size of the stickers in millimeters.
+    pub sheet_size: Option<SheetSize>,
+
+    // Consider pixels to be a part of the background if the CIEDE2000 difference between them
+    // and the interpolated background color is at most this large instead of checking the
+    // differences of each LAB channel separately.
+    pub delta_e_threshold: Option<f32>,
+
+    // Same as delta_e_threshold but using the euclidean distance in LAB, which is cheaper and
+    // treats differences in hue the same regardless of lightness. Only one of them can be set.
+    pub lab_distance_threshold: Option<f32>,
+
+    // Keep pixels whose LAB chroma exceeds the chroma of the background by at least this much
+    // even if they are otherwise similar to the background e.g. holographic stickers.
+    pub chroma_exemption: Option<f32>,
+
+    // After removing the background also remove the pixels around the stickers which are
+    // darker than the background but have the same color e.g. shadows cast by the stickers.
+    pub remove_shadows: bool,
+
+    // After removing the background measure how much of the sheet still looks like the
+    // background and warn if it's a lot. Requires an additional pass over the entire sheet.
+    pub check_residual_background: bool,
+
+    // Weights of the metrics combined into the confidence of each sheet in the manifest.
+    pub confidence_weights: ConfidenceWeights,
+
+    // After removing the background restore the original colors of transparent holes of at most
+    // this many pixels which are enclosed by a single sticker.
+    pub max_hole_size: Option<usize>,
+
+    // Set e.g. by another thread to stop the processing early, it's checked between the stages
+    // and in the longest loops which then return Cancelled. Clones of the config share it.
+    #[serde(skip)]
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl Default for ExtractionConfig {
+    fn default() -> Self {
+        Self {
+            save_intermediate_images: false,
+            preview_directory: PathBuf::from("./"),
+            region_of_interest: None,
+            invert_colors: InvertColors::Never,
+            multiple_sheets: false,
+            snap_stickers_threshold_x: 0.2,
+            snap_stickers_threshold_y: 0.1,
+            sheet_aspect_ratio: None,
+            tile_size: None,
+            initial_crop: InitialCrop::Markers {
+                margin: DEFAULT_INITIAL_CROP_MARGIN,
+            },
+            save_full_image: false,
+            save_background_image: false,
+            deterministic: false,
+            save_marker_image: false,
+            sticker_connectivity: Connectivity::Four,
+            sticker_alpha_threshold: 1,
+            split_touching_stickers: false,
+            grid: None,
+            numbered_file_names: false,
+            trim_rotated: false,
+            trim_rotation: TrimRotation::MinAreaRect,
+            output_format: OutputFormat::Png,
+            quality: None,
+            indexed_png_max_colors: None,
+            output_targets: vec![],
+            archive: false,
+            save_outlines: false,
+            thumbnail_size: None,
+            marker_count: 4,
+            marker_scan_steps: 30,
+            corner_scan_steps: CornerScanSteps::default(),
+            marker_colors: vec![MarkerColor::White],
+            marker_template: None,
+            marker_positions: None,
+            marker_order: MarkerOrder::Strict,
+            highlight_ceiling: DEFAULT_HIGHLIGHT_CEILING,
+            min_sharpness: None,
+            save_manifest: false,
+            save_background_samples: false,
+            background_reference: BackgroundReference::Interpolated,
+            background_normalisation: BackgroundNormalisation::Global,
+            noise_adaptive_thresholds: false,
+            correct_exposure: false,
+            background_weighting: BackgroundWeighting::InverseDistance,
+            background_interpolation: MixSpace::Yuv,
+            mirror_background_samples: false,
+            resample_filter: None,
+            virtual_pixel: VirtualPixel::Transparent,
+            perspective_margin: 0,
+            chroma_key: None,
+            sheet_size: None,
+            delta_e_threshold: None,
+            lab_distance_threshold: None,
+            chroma_exemption: None,
+            remove_shadows: false,
+            check_residual_background: false,
+            confidence_weights: ConfidenceWeights::default(),
+            max_hole_size: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl ExtractionConfig {
+    // Loads the config from a JSON file if the file has a .json extension or from a TOML file
+    // otherwise. Fields which aren't present in the file keep their default values, unknown
+    // fields are ignored.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("error reading config file {}", path.display()))?;
+
+        let is_json = path
+            .extension()
+            .is_some_and(|v| v.eq_ignore_ascii_case("json"));
+
+        let version: ConfigVersion = if is_json {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+
+        if let Some(version) = version.version
+            && version > CONFIG_VERSION
+        {
+            return Err(anyhow!(
+                "config file version {} is newer than the supported version {}",
+                version,
+                CONFIG_VERSION
+            ));
+        }
+
+        let warn_about_unknown_key = |key: serde_ignored::Path| {
+            let key = key.to_string();
+            if key != "version" {
+                warn!(
+                    "Ignoring unknown config key '{}' in {}",
+                    key,
+                    path.display()
+                );
+            }
+        };
+
+        let config: ExtractionConfig = if is_json {
+            let mut deserializer = serde_json::Deserializer::from_str(&contents);
+            serde_ignored::deserialize(&mut deserializer, warn_about_unknown_key)?
+        } else {
+            let deserializer = toml::Deserializer::new(&contents);
+            serde_ignored::deserialize(deserializer, warn_about_unknown_key)?
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // Checks the values which can't be expressed by the types of the fields. Called both for
+    // the configs loaded from files and the ones built from the command line flags.
+    pub fn validate(&self) -> Result<()> {
+        if let VirtualPixel::Color(color) = &self.virtual_pixel {
+            Color::from_hex(color)?;
+        }
+
+        if let Some(roi) = &self.region_of_interest {
+            roi.validate()?;
+        }
+
+        if let InitialCrop::Fixed { fraction } = self.initial_crop
+            && !(0.0..0.5).contains(&fraction)
+        {
+            return Err(anyhow!(
+                "initial crop fraction must be at least 0 and below 0.5"
+            ));
+        }
+
+        if !(2..=4).contains(&self.marker_count) {
+            return Err(anyhow!("number of markers must be between 2 and 4"));
+        }
+
+        let corner_scan_steps = &self.corner_scan_steps;
+        let scan_steps = [
+            Some(self.marker_scan_steps),
+            corner_scan_steps.top_left,
+            corner_scan_steps.top_right,
+            corner_scan_steps.bottom_left,
+            corner_scan_steps.bottom_right,
+        ];
+        if scan_steps.iter().flatten().any(|v| !(1..50).contains(v)) {
+            return Err(anyhow!("marker scan steps must be at least 1 and below 50"));
+        }
+
+        if self.tile_size == Some(0) {
+            return Err(anyhow!("tile size must be positive"));
+        }
+
+        if self.thumbnail_size == Some(0) {
+            return Err(anyhow!("thumbnail size must be positive"));
+        }
+
+        if self.sticker_alpha_threshold == 0 {
+            return Err(anyhow!("sticker alpha threshold must be positive"));
+        }
+
+        if self.sheet_aspect_ratio.is_some_and(|v| v <= 0.0) {
+            return Err(anyhow!("sheet aspect ratio must be positive"));
+        }
+
+        if let BackgroundWeighting::Gaussian { sigma } = self.background_weighting
+            && sigma <= 0.0
+        {
+            return Err(anyhow!("gaussian width must be positive"));
+        }
+
+        if let BackgroundReference::Corners { percentile } = self.background_reference
+            && !(0.0..=1.0).contains(&percentile)
+        {
+            return Err(anyhow!("background percentile must be between 0 and 1"));
+        }
+
+        let weights = &self.confidence_weights;
+        let weights = [
+            weights.markers,
+            weights.quad,
+            weights.sharpness,
+            weights.background,
+        ];
+        if weights.iter().any(|v| *v < 0.0) || weights.iter().sum::<f32>() <= 0.0 {
+            return Err(anyhow!(
+                "confidence weights must not be negative and at least one must be positive"
+            ));
+        }
+
+        if self.delta_e_threshold.is_some() && self.lab_distance_threshold.is_some() {
+            return Err(anyhow!(
+                "only one of the delta E and LAB distance thresholds can be set"
+            ));
+        }
+
+        if self.delta_e_threshold.is_some_and(|v| v < 0.0) {
+            return Err(anyhow!("delta E threshold can't be negative"));
+        }
+
+        if self.lab_distance_threshold.is_some_and(|v| v < 0.0) {
+            return Err(anyhow!("LAB distance threshold can't be negative"));
+        }
+
+        if self.quality.is_some_and(|v| !(1..=100).contains(&v)) {
+            return Err(anyhow!("quality must be between 1 and 100"));
+        }
+
+        // The quality isn't ignored silently if it was set and the stickers are written as WebP.
+        let writes_webp = self.output_format == OutputFormat::Webp
+            || self
+                .output_targets
+                .iter()
+                .any(|v| v.format == OutputFormat::Webp);
+
+        if writes_webp && self.quality.is_some() {
+            return Err(anyhow!(
+                "quality can't be set when writing WebP which is always lossless"
+            ));
+        }
+
+        if self
+            .indexed_png_max_colors
+            .is_some_and(|v| !(1..=256).contains(&v))
+        {
+            return Err(anyhow!(
+                "indexed PNG palette must have between 1 and 256 colors"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Rectangle in the photo given in pixels.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegionOfInterest {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for RegionOfInterest {
+    type Err = anyhow::Error;
+
+    // Parses regions formatted as LEFT,TOP,WIDTH,HEIGHT e.g. 0,0,2000,1500.
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .context("invalid region of interest")?;
+
+        let [left, top, width, height] = values[..] else {
+            return Err(anyhow!(
+                "region of interest must be formatted as LEFT,TOP,WIDTH,HEIGHT"
+            ));
+        };
+
+        let roi = RegionOfInterest {
+            left,
+            top,
+            width,
+            height,
+        };
+        roi.validate()?;
+        Ok(roi)
+    }
+}
+
+impl RegionOfInterest {
+    // Whether the region is inside of the photo can only be checked once the photo is opened.
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!("region of interest can't be empty"));
+        }
+
+        if self.left.checked_add(self.width).is_none()
+            || self.top.checked_add(self.height).is_none()
+        {
+            return Err(anyhow!("region of interest is too large"));
+        }
+
+        Ok(())
+    }
+}
+
+// Centers of the markers in pixels relative to the region of interest and the size of the square
+// markers which determines where the background is sampled next to them.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MarkerPositions {
+    pub top_left: [f32; 2],
+    pub top_right: [f32; 2],
+    pub bottom_left: [f32; 2],
+    pub bottom_right: [f32; 2],
+    pub size: u32,
+}
+
+impl FromStr for MarkerPositions {
+    type Err = anyhow::Error;
+
+    // Parses positions formatted as X,Y of the top left, top right, bottom left and bottom right
+    // markers followed by their SIZE e.g. 50,40,1950,40,50,1460,1950,1460,30.
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<std::result::Result<Vec<f32>, _>>()
+            .context("invalid marker positions")?;
+
+        let [
+            top_left_x,
+            top_left_y,
+            top_right_x,
+            top_right_y,
+            bottom_left_x,
+            bottom_left_y,
+            bottom_right_x,
+            bottom_right_y,
+            size,
+        ] = values[..]
+        else {
+            return Err(anyhow!(
+                "marker positions must be formatted as X,Y for each of the four markers followed by SIZE"
+            ));
+        };
+
+        if size < 1.0 || size.fract() != 0.0 {
+            return Err(anyhow!("marker size must be a positive number of pixels"));
+        }
+
+        Ok(MarkerPositions {
+            top_left: [top_left_x, top_left_y],
+            top_right: [top_right_x, top_right_y],
+            bottom_left: [bottom_left_x, bottom_left_y],
+            bottom_right: [bottom_right_x, bottom_right_y],
+            size: size as u32,
+        })
+    }
+}
+
+// Distance between the centers of the markers in millimeters. After correcting the perspective
+// the centers of the markers are placed in the corners of the image.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SheetSize {
+    pub width_mm: f32,
+    pub height_mm: f32,
+}
+
+impl FromStr for SheetSize {
+    type Err = anyhow::Error;
+
+    // Parses sizes formatted as WIDTHxHEIGHT e.g. 210x297.
+    fn from_str(s: &str) -> Result<Self> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| anyhow!("sheet size must be formatted as WIDTHxHEIGHT"))?;
+
+        let width_mm: f32 = width.trim().parse().context("invalid sheet width")?;
+        let height_mm: f32 = height.trim().parse().context("invalid sheet height")?;
+        if width_mm <= 0.0 || height_mm <= 0.0 {
+            return Err(anyhow!("sheet size must be positive"));
+        }
+
+        Ok(SheetSize {
+            width_mm,
+            height_mm,
+        })
+    }
+}
+
+// Number of neighbours of each pixel, four only includes the pixels sharing an edge with it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "u8")]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+impl TryFrom<u8> for Connectivity {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            4 => Ok(Connectivity::Four),
+            8 => Ok(Connectivity::Eight),
+            _ => Err(anyhow!("connectivity must be 4 or 8")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GridShape {
+    pub columns: usize,
+    pub rows: usize,
+}
+
+impl FromStr for GridShape {
+    type Err = anyhow::Error;
+
+    // Parses shapes formatted as COLUMNSxROWS e.g. 4x6.
+    fn from_str(s: &str) -> Result<Self> {
+        let (columns, rows) = s
+            .split_once('x')
+            .ok_or_else(|| anyhow!("grid must be formatted as COLUMNSxROWS"))?;
+
+        let columns: usize = columns
+            .trim()
+            .parse()
+            .context("invalid number of columns")?;
+        let rows: usize = rows.trim().parse().context("invalid number of rows")?;
+        if columns == 0 || rows == 0 {
+            return Err(anyhow!("grid must have at least one column and row"));
+        }
+
+        Ok(GridShape { columns, rows })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ChromaKey {
+    // Color of the backdrop formatted as #rrggbb.
+    pub color: String,
+
+    // Pixels whose hue is at most this many degrees away from the hue of the key color are
+    // removed.
+    pub hue_tolerance: f32,
+
+    // Pixels whose saturation is below this value are kept as their hue is unreliable, [0, 1].
+    pub min_saturation: f32,
+}
+
+impl Default for ChromaKey {
+    fn default() -> Self {
+        Self {
+            color: "#00ff00".into(),
+            hue_tolerance: 30.0,
+            min_saturation: 0.25,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundReference {
+    // Interpolate the samples taken along the edges of the sheet, weighting them by distance.
+    Interpolated,
+
+    // Use the sample next to the markers whose luminance is at the given percentile, [0, 1],
+    // as the color of the entire background. This is more robust against strong vignetting
+    // which makes the interpolated color wrong in the middle of the sheet.
+    Corners { percentile: f32 },
+}
+
+// Only the ratios of the weights matter as the confidence is their weighted average.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ConfidenceWeights {
+    // How many markers were found instead of estimated and how similar their sizes are.
+    pub markers: f32,
+
+    // How close the quad formed by the markers is to a rectangle.
+    pub quad: f32,
+
+    // Sharpness of the photo.
+    pub sharpness: f32,
+
+    // Fraction of the sheet which still looked like the background after removing it.
+    pub background: f32,
+}
+
+impl Default for ConfidenceWeights {
+    fn default() -> Self {
+        Self {
+            markers: 1.0,
+            quad: 1.0,
+            sharpness: 1.0,
+            background: 1.0,
+        }
+    }
+}
+
+// Corners without a value use the default number of marker scan steps.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct CornerScanSteps {
+    pub top_left: Option<u32>,
+    pub top_right: Option<u32>,
+    pub bottom_left: Option<u32>,
+    pub bottom_right: Option<u32>,
+}
+
+impl CornerScanSteps {
+    // Sets the number of steps for a single corner given as e.g. top-left=45.
+    pub fn set(&mut self, s: &str) -> Result<()> {
+        let (corner, steps) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("corner scan steps must be given as CORNER=STEPS"))?;
+        let steps = Some(
+            steps
+                .parse()
+                .context("error parsing the number of scan steps")?,
+        );
+
+        match corner {
+            "top-left" => self.top_left = steps,
+            "top-right" => self.top_right = steps,
+            "bottom-left" => self.bottom_left = steps,
+            "bottom-right" => self.bottom_right = steps,
+            _ => return Err(anyhow!("unknown corner '{}'", corner)),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackgroundNormalisation {
+    // Divide the differences by the largest differences found in the entire image.
+    Global,
+
+    // Divide the differences by the largest differences found in the region of the image
+    // containing the pixel, the image is split into the given number of regions along each
+    // axis. A single bright sticker then lowers the sensitivity only in its own region.
+    Regional { regions: u32 },
+
+    // Divide the differences by fixed values which makes the detection independent of the
+    // contents of the image.
+    Fixed { l: f32, a: f32, b: f32 },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkerColor {
+    White,
+    Black,
+}
+
+impl MarkerColor {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MarkerColor::White => "white",
+            MarkerColor::Black => "black",
+        }
+    }
+}
+
+impl FromStr for MarkerColor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "white" => Ok(MarkerColor::White),
+            "black" => Ok(MarkerColor::Black),
+            _ => Err(anyhow!("unknown marker color '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InvertColors {
+    Never,
+    Always,
+
+    // Invert the colors if the markers can't be found in the photo but can be found after
+    // inverting it.
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkerOrder {
+    // Fail if the markers aren't in the expected positions.
+    Strict,
+
+    // Reassign the markers to the corners based on their positions and fail if they still
+    // don't form a valid quad.
+    AutoCorrect,
+
+    // Same as auto correct but the reassigned markers are used even if they don't form a valid
+    // quad.
+    BestEffort,
+}
+
+impl FromStr for MarkerOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(MarkerOrder::Strict),
+            "auto-correct" => Ok(MarkerOrder::AutoCorrect),
+            "best-effort" => Ok(MarkerOrder::BestEffort),
+            _ => Err(anyhow!("unknown marker order '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InitialCrop {
+    // Crop the given fraction of the width and height off each side no matter where the
+    // markers are.
+    Fixed { fraction: f32 },
+
+    // Crop only the bands containing the markers plus a margin given in pixels, which keeps the
+    // stickers placed close to the edges of the sheet intact.
+    Markers { margin: u32 },
+
+    // Crop everything outside of the edges of the sheet detected after removing the background
+    // plus a margin given in pixels. This works if the sheet is surrounded by a surface which
+    // doesn't look like its background.
+    Edges { margin: u32 },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackgroundWeighting {
+    // Weight the samples by the inverse of the squared distance. The background matches the
+    // samples exactly but it changes abruptly around them.
+    InverseDistance,
+
+    // Weight the samples using a gaussian whose standard deviation is given as a fraction of
+    // the longer side of the image, which produces smoother backgrounds.
+    Gaussian { sigma: f32 },
+}
+
+// Nearest is the fastest and therefore useful for previews, Lanczos produces the sharpest
+// results.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleFilter {
+    Nearest,
+    Bilinear,
+    Lanczos,
+}
+
+impl ResampleFilter {
+    pub fn filter_type(&self) -> FilterType {
+        match self {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Bilinear => FilterType::Triangle,
+            ResampleFilter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+
+    // Distance from the sampled point beyond which the pixels have no weight.
+    pub fn support(&self) -> f32 {
+        match self {
+            ResampleFilter::Nearest => 0.5,
+            ResampleFilter::Bilinear => 1.0,
+            ResampleFilter::Lanczos => 3.0,
+        }
+    }
+
+    // Weight of a pixel at the given distance from the sampled point along one axis, the
+    // weights aren't normalised.
+    pub fn weight(&self, distance: f32) -> f32 {
+        match self {
+            // Half-open so that exactly one pixel is picked when the point lies between two.
+            ResampleFilter::Nearest if distance > -0.5 && distance <= 0.5 => 1.0,
+            ResampleFilter::Nearest => 0.0,
+            ResampleFilter::Bilinear => (1.0 - distance.abs()).max(0.0),
+            ResampleFilter::Lanczos if distance == 0.0 => 1.0,
+            ResampleFilter::Lanczos if distance.abs() >= 3.0 => 0.0,
+            ResampleFilter::Lanczos => {
+                let x = distance * std::f32::consts::PI;
+                3.0 * x.sin() * (x / 3.0).sin() / (x * x)
+            }
+        }
+    }
+}
+
+impl FromStr for ResampleFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(ResampleFilter::Nearest),
+            "bilinear" => Ok(ResampleFilter::Bilinear),
+            "lanczos" => Ok(ResampleFilter::Lanczos),
+            _ => Err(anyhow!("unknown resample filter '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrimRotation {
+    // Rotate the smallest rectangle containing the sticker so that it is upright, the sticker is
+    // rotated by at most 45 degrees.
+    MinAreaRect,
+
+    // Rotate the sticker so that the principal axis of its pixels is horizontal. This is cheaper
+    // and keeps elongated stickers e.g. ones containing a line of text level.
+    PrincipalAxis,
+}
+
+impl FromStr for TrimRotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "min-area-rect" => Ok(TrimRotation::MinAreaRect),
+            "principal-axis" => Ok(TrimRotation::PrincipalAxis),
+            _ => Err(anyhow!("unknown trim rotation '{}'", s)),
+        }
+    }
+}
+
+// JPEG doesn't support transparency so the transparent parts of the stickers are filled with
+// white.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "png" => Ok(OutputFormat::Png),
+            "jpeg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::Webp),
+            _ => Err(anyhow!("unknown output format '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct OutputTarget {
+    pub format: OutputFormat,
+    pub directory: PathBuf,
+}
+
+// Transparent regions are removed together with the background. Other fills are useful if the
+// image will later be flattened onto a solid background but they remain a part of the sheet.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VirtualPixel {
+    Transparent,
+
+    // Repeat the pixels at the edge of the photo.
+    Edge,
+
+    // Fill with a color formatted as #rrggbb.
+    Color(String),
+}
+
+impl FromStr for VirtualPixel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "transparent" => Ok(VirtualPixel::Transparent),
+            "edge" => Ok(VirtualPixel::Edge),
+            _ => {
+                Color::from_hex(s)?;
+                Ok(VirtualPixel::Color(s.into()))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ConfigVersion {
+    version: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn load(name: &str, contents: &str) -> Result<ExtractionConfig> {
+        let directory = TempDir::new()?;
+        let path = directory.path().join(name);
+        fs::write(&path, contents)?;
+        ExtractionConfig::from_path(path)
+    }
+
+    #[test]
+    fn from_path_keeps_defaults_for_missing_fields() {
+        let config = load(
+            "config.toml",
+            "version = 1\nsnap_stickers_threshold_x = 0.3\nunknown = 2\n",
+        )
+        .unwrap();
+        assert_eq!(config.snap_stickers_threshold_x, 0.3);
+        assert_eq!(
+            config.snap_stickers_threshold_y,
+            ExtractionConfig::default().snap_stickers_threshold_y
+        );
+
+        let config = load("config.json", "{\"snap_stickers_threshold_y\": 0.2}").unwrap();
+        assert_eq!(config.snap_stickers_threshold_y, 0.2);
+    }
+
+    #[test]
+    fn from_path_rejects_newer_versions() {
+        let contents = format!("version = {}\n", CONFIG_VERSION + 1);
+        assert!(load("config.toml", &contents).is_err());
+    }
+
+    #[test]
+    fn region_of_interest_is_validated() {
+        let roi: RegionOfInterest = "10, 20, 300, 400".parse().unwrap();
+        assert_eq!(
+            (roi.left, roi.top, roi.width, roi.height),
+            (10, 20, 300, 400)
+        );
+        assert!("10,20,300".parse::<RegionOfInterest>().is_err());
+        assert!("10,20,0,400".parse::<RegionOfInterest>().is_err());
+
+        let roi = "[region_of_interest]\nleft = 0\ntop = 0\nwidth = 0\nheight = 10\n";
+        assert!(load("config.toml", roi).is_err());
+        let roi = "[region_of_interest]\nleft = 4294967295\ntop = 0\nwidth = 2\nheight = 10\n";
+        assert!(load("config.toml", roi).is_err());
+    }
+
+    #[test]
+    fn marker_positions_are_parsed() {
+        let positions: MarkerPositions = "50,40,1950,40.5,50,1460,1950,1460,30".parse().unwrap();
+        assert_eq!(positions.top_left, [50.0, 40.0]);
+        assert_eq!(positions.top_right, [1950.0, 40.5]);
+        assert_eq!(positions.bottom_right, [1950.0, 1460.0]);
+        assert_eq!(positions.size, 30);
+
+        assert!("1,2,3".parse::<MarkerPositions>().is_err());
+        assert!(
+            "50,40,1950,40,50,1460,1950,1460,0"
+                .parse::<MarkerPositions>()
+                .is_err()
+        );
+        assert!(
+            "50,40,1950,40,50,1460,1950,1460,2.5"
+                .parse::<MarkerPositions>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn resample_filter_is_parsed() {
+        let filter: ResampleFilter = "lanczos".parse().unwrap();
+        assert_eq!(filter, ResampleFilter::Lanczos);
+        assert_eq!(filter.filter_type(), FilterType::Lanczos3);
+        assert!("bicubic".parse::<ResampleFilter>().is_err());
+
+        let config = load("config.toml", "resample_filter = \"nearest\"\n").unwrap();
+        assert_eq!(config.resample_filter, Some(ResampleFilter::Nearest));
+        assert_eq!(ExtractionConfig::default().resample_filter, None);
+    }
+
+    #[test]
+    fn sheet_size_is_parsed() {
+        let size: SheetSize = "210x297".parse().unwrap();
+        assert_eq!((size.width_mm, size.height_mm), (210.0, 297.0));
+        assert!("210".parse::<SheetSize>().is_err());
+        assert!("210xA4".parse::<SheetSize>().is_err());
+        assert!("0x297".parse::<SheetSize>().is_err());
+    }
+
+    #[test]
+    fn quality_is_validated() {
+        assert!(load("config.toml", "quality = 0\n").is_err());
+        assert_eq!(
+            load("config.toml", "quality = 50\n").unwrap().quality,
+            Some(50)
+        );
+        assert!(load("config.toml", "output_format = \"webp\"\nquality = 50\n").is_err());
+        assert!(load("config.toml", "output_format = \"webp\"\nquality = 90\n").is_err());
+
+        let config = load("config.toml", "output_format = \"webp\"\n").unwrap();
+        assert_eq!(config.output_format, OutputFormat::Webp);
+        assert_eq!(config.output_format.extension(), "webp");
+        assert!("gif".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn ranges_of_loaded_values_are_validated() {
+        assert!(load("config.toml", "marker_count = 5\n").is_err());
+        assert!(load("config.toml", "marker_scan_steps = 50\n").is_err());
+        assert!(load("config.toml", "tile_size = 0\n").is_err());
+        assert!(load("config.json", "{\"thumbnail_size\": 0}").is_err());
+        assert!(load("config.toml", "[corner_scan_steps]\ntop_left = 0\n").is_err());
+
+        let config = load("config.toml", "marker_count = 3\ntile_size = 512\n").unwrap();
+        assert_eq!(config.marker_count, 3);
+        assert!(ExtractionConfig::default().validate().is_ok());
+    }
+}
@@ -1,2 +1,46 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 pub type Error = anyhow::Error;
+
+// Returned when no stickers remain on a sheet after removing the background. Can be
+// distinguished from other errors using downcast_ref.
+#[derive(Debug)]
+pub struct EmptySheet;
+
+impl fmt::Display for EmptySheet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the sheet is empty, no stickers remain after removing the background"
+        )
+    }
+}
+
+impl std::error::Error for EmptySheet {}
+
+// Returned when the processing was stopped early because the cancellation token was set. Can be
+// distinguished from other errors using downcast_ref.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the processing was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// Returns Cancelled if the token was set e.g. by another thread. It is checked between the
+// stages of the processing and in the longest loops so it can be stopped without waiting for it
+// to finish.
+pub fn check_cancelled(cancel: &AtomicBool) -> Result<()> {
+    if cancel.load(Ordering::Relaxed) {
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
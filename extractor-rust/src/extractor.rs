@@ -1,12 +1,22 @@
 use crate::{
-    color::{AlphaColor, Color, LAB, RGB, YUV},
-    errors::Result,
+    color::{AlphaColor, CachedColor, Color, LAB, MixSpace, RGB, YUV},
+    config::{
+        BackgroundNormalisation, BackgroundReference, BackgroundWeighting, ChromaKey,
+        ConfidenceWeights, Connectivity, CornerScanSteps, ExtractionConfig, MarkerColor,
+        MarkerOrder, ResampleFilter, SheetSize,
+    },
+    errors::{Result, check_cancelled},
+    homography::solve,
 };
 use anyhow::anyhow;
-use image::Rgb;
+use image::{Pixel, Rgb, Rgba, RgbaImage, imageops, imageops::FilterType};
+use log::info;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::{
     cmp,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
+    f32::consts::{FRAC_PI_2, FRAC_PI_4},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 // Specifies a fraction of image/height every which the image will be probed for markers, the
@@ -18,33 +28,487 @@ const MARKER_SCAN_STEPS: u32 = 30;
 
 const BACKGROUND_ANALYSIS_STEPS: usize = 10;
 
+// Runs of background samples which are uniform and brighter than the samples next to them on the
+// same edge by at least this much luminance are considered to be glare.
+const GLARE_MAX_LUMINANCE_VARIANCE: f32 = 0.001;
+const GLARE_MIN_BRIGHTENING: f32 = 0.15;
+
 // Marker must be at least 0.001% of the total image in pixel count.
 const MARKER_THRESHOLD: f32 = 0.0001;
 
-// Consider stickers to be in the same column if their
-// centers are this far away.
-const SNAP_STICKERS_THRESHOLD: f32 = 0.2;
-
+// A marker-colored region too small to be a marker is only considered to be the visible part of a
+// marker outside of the photo if it is at least this large fraction of the smallest marker, fills
+// this much of its bounding box like a square marker would and is cut off by one of the edges of
+// the photo next to the corner along this much of its side.
+const CLIPPED_MARKER_MIN_SIZE: f32 = 0.1;
+const CLIPPED_MARKER_MIN_FILL: f32 = 0.5;
+const CLIPPED_MARKER_MIN_CUT: f32 = 0.5;
+
+// Normalised cross-correlation between the marker template and the image required to accept a
+// match, correlation of identical images is 1.
+const TEMPLATE_MIN_CORRELATION: f32 = 0.5;
+
+// Regions consisting mostly of pixels whose channels are all at least as large as the highlight
+// ceiling and which are at least this large fraction of the image are likely to be specular
+// highlights and not markers as markers are much smaller than the glare reflected by a glossy
+// sheet. Such regions are only used if no other marker candidates were found.
+pub const DEFAULT_HIGHLIGHT_CEILING: u8 = 250;
+const HIGHLIGHT_CLIPPED_FRACTION: f32 = 0.5;
+const HIGHLIGHT_MIN_SIZE: f32 = 0.005;
+
+// When looking for multiple sets of markers only candidates which are at most this many times
+// larger than each other are grouped together. At most this many largest candidates are
+// considered.
+const MARKER_SIZE_RATIO: f32 = 2.0;
+const MAX_MARKER_CANDIDATES: usize = 24;
+
+// Colors are grouped by dropping this many least significant bits of each channel when looking
+// for the dominant color.
+const DOMINANT_COLOR_SHIFT: u8 = 4;
+
+// Shadows are at most this much darker than the background in LAB lightness and their
+// chromaticity differs from the chromaticity of the background by at most this much.
+const SHADOW_MAX_DARKENING: f32 = 30.0;
+const SHADOW_MAX_CHROMATICITY_DIFFERENCE: f32 = 0.03;
+
+// Pixels which weren't removed are considered to be leftover background if the CIEDE2000
+// difference between them and the background is at most this large.
+const RESIDUAL_BACKGROUND_DELTA_E: f32 = 5.0;
+
+// Pixels of a sticker whose distance from its edge is at least this fraction of the largest
+// distance are the seeds from which the parts of touching stickers are grown.
+const SPLIT_SEED_FRACTION: f32 = 0.7;
+
+// Rows and columns along the edges of the perspective corrected image in which at least this
+// fraction of pixels is opaque are outside of the sheet, whose background was removed.
+const SHEET_EDGE_MIN_OPAQUE_FRACTION: f32 = 0.5;
+
+// Sharpness for which the sharpness part of the confidence is 0.5, it approaches 1 for sharper
+// photos.
+const CONFIDENCE_HALF_SHARPNESS: f32 = 50.0;
+
+// Width of the sticker bounding boxes drawn on the overlay.
+const OVERLAY_BORDER_WIDTH: u32 = 3;
+
+// When processing in tiles the background is analysed using a copy of the image whose longer
+// side is resized to this size.
+pub const TILED_PROCESSING_DOWNSCALED_SIZE: u32 = 2000;
+
+#[derive(Clone)]
 pub struct Markers {
-    top_left: Area,
-    top_right: Area,
-    bottom_left: Area,
-    bottom_right: Area,
+    top_left: Marker,
+    top_right: Marker,
+    bottom_left: Marker,
+    bottom_right: Marker,
 }
 
 impl Markers {
     pub fn find<I: Image>(img: &I) -> Result<Markers> {
-        if MARKER_SCAN_STEP * MARKER_SCAN_STEPS as f32 >= 0.5 {
+        Markers::find_with_scan_steps(img, MARKER_SCAN_STEPS, DEFAULT_HIGHLIGHT_CEILING)
+    }
+
+    // Same as find but the number of steps performed when looking for each marker near the
+    // corners can be specified to make the search more or less thorough. Large regions whose
+    // pixels are clipped at the highlight ceiling are ignored.
+    pub fn find_with_scan_steps<I: Image>(
+        img: &I,
+        scan_steps: u32,
+        highlight_ceiling: u8,
+    ) -> Result<Markers> {
+        Markers::find_with_scan_steps_and_color(
+            img,
+            &ScanSteps::uniform(scan_steps),
+            highlight_ceiling,
+            MarkerColor::White,
+            MarkerOrder::Strict,
+        )
+    }
+
+    fn find_with_scan_steps_and_color<I: Image>(
+        img: &I,
+        scan_steps: &ScanSteps,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+        order: MarkerOrder,
+    ) -> Result<Markers> {
+        scan_steps.check()?;
+
+        match Markers::find_near_corners(img, scan_steps, highlight_ceiling, marker_color, order) {
+            Ok(markers) => Ok(markers),
+            Err(corners_err) => {
+                Markers::find_closest_to_corners(img, highlight_ceiling, marker_color, order)
+                    .map_err(|err| {
+                        anyhow!("corner scan failed: {corners_err}, full image scan failed: {err}")
+                    })
+            }
+        }
+    }
+
+    // Finds markers when only some of them are present on the sheet. With two markers they are
+    // expected in the top left and bottom right corners and the sheet is assumed not to be
+    // rotated so that only translation and scale can be corrected. With three markers the
+    // bottom right one is expected to be missing and the sheet is assumed to be a
+    // parallelogram which allows an affine correction. The missing markers are estimated so
+    // that the perspective can be corrected in the same way as with four markers.
+    pub fn find_with_count<I: Image>(
+        img: &I,
+        marker_count: usize,
+        scan_steps: u32,
+        highlight_ceiling: u8,
+    ) -> Result<Markers> {
+        Markers::find_with_colors(
+            img,
+            &[MarkerColor::White],
+            marker_count,
+            scan_steps,
+            highlight_ceiling,
+        )
+    }
+
+    // Same as find_with_count but tries each of the marker colors in order and returns the
+    // markers found using the first color which yields a valid set of markers e.g. when
+    // photos of sheets with white and black markers are processed together.
+    pub fn find_with_colors<I: Image>(
+        img: &I,
+        marker_colors: &[MarkerColor],
+        marker_count: usize,
+        scan_steps: u32,
+        highlight_ceiling: u8,
+    ) -> Result<Markers> {
+        Markers::find_with_colors_and_order(
+            img,
+            marker_colors,
+            marker_count,
+            scan_steps,
+            &CornerScanSteps::default(),
+            highlight_ceiling,
+            MarkerOrder::Strict,
+        )
+    }
+
+    // Same as find_with_colors but the number of scan steps can be overridden for each corner
+    // and the order policy decides what happens if the markers found in the corners aren't in
+    // the expected positions relative to each other.
+    pub fn find_with_colors_and_order<I: Image>(
+        img: &I,
+        marker_colors: &[MarkerColor],
+        marker_count: usize,
+        scan_steps: u32,
+        corner_scan_steps: &CornerScanSteps,
+        highlight_ceiling: u8,
+        order: MarkerOrder,
+    ) -> Result<Markers> {
+        if marker_colors.is_empty() {
+            return Err(anyhow!("at least one marker color is required"));
+        }
+
+        let scan_steps = ScanSteps::new(scan_steps, corner_scan_steps);
+
+        let mut errors = vec![];
+        for marker_color in marker_colors {
+            match Markers::find_with_count_and_color(
+                img,
+                marker_count,
+                &scan_steps,
+                highlight_ceiling,
+                *marker_color,
+                order,
+            ) {
+                Ok(markers) => return Ok(markers),
+                Err(err) => errors.push(format!("{} markers: {err}", marker_color.name())),
+            }
+        }
+
+        Err(anyhow!("{}", errors.join("; ")))
+    }
+
+    fn find_with_count_and_color<I: Image>(
+        img: &I,
+        marker_count: usize,
+        scan_steps: &ScanSteps,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+        order: MarkerOrder,
+    ) -> Result<Markers> {
+        scan_steps.check()?;
+
+        let find_marker = |corner: &Corner| {
+            Markers::find_marker(
+                img,
+                corner,
+                scan_steps.get(corner),
+                highlight_ceiling,
+                marker_color,
+            )
+        };
+
+        match marker_count {
+            4 => Markers::find_with_scan_steps_and_color(
+                img,
+                scan_steps,
+                highlight_ceiling,
+                marker_color,
+                order,
+            ),
+            3 => {
+                let top_left = find_marker(&Corner::TopLeft)?;
+                let top_right = find_marker(&Corner::TopRight)?;
+                let bottom_left = find_marker(&Corner::BottomLeft)?;
+                let bottom_right = Marker::new_estimated(
+                    PreciseXY {
+                        x: top_right.center.x + bottom_left.center.x - top_left.center.x,
+                        y: top_right.center.y + bottom_left.center.y - top_left.center.y,
+                    },
+                    &top_left,
+                );
+                Markers::new_with_order(top_left, top_right, bottom_left, bottom_right, order)
+            }
+            2 => {
+                let top_left = find_marker(&Corner::TopLeft)?;
+                let bottom_right = find_marker(&Corner::BottomRight)?;
+                let top_right = Marker::new_estimated(
+                    PreciseXY {
+                        x: bottom_right.center.x,
+                        y: top_left.center.y,
+                    },
+                    &top_left,
+                );
+                let bottom_left = Marker::new_estimated(
+                    PreciseXY {
+                        x: top_left.center.x,
+                        y: bottom_right.center.y,
+                    },
+                    &top_left,
+                );
+                Markers::new_with_order(top_left, top_right, bottom_left, bottom_right, order)
+            }
+            _ => Err(anyhow!("marker count must be 2, 3 or 4")),
+        }
+    }
+
+    fn check_scan_steps(scan_steps: u32) -> Result<()> {
+        if scan_steps == 0 {
+            return Err(anyhow!("at least one marker scan step is required"));
+        }
+
+        if MARKER_SCAN_STEP * scan_steps as f32 >= 0.5 {
             return Err(anyhow!(
                 "marker search will go past the middle of width/height, you didn't mean to do this"
             ));
         }
 
-        let top_left = Markers::find_marker(img, &Corner::TopLeft)?;
-        let top_right = Markers::find_marker(img, &Corner::TopRight)?;
-        let bottom_left = Markers::find_marker(img, &Corner::BottomLeft)?;
-        let bottom_right = Markers::find_marker(img, &Corner::BottomRight)?;
+        Ok(())
+    }
+
+    fn find_near_corners<I: Image>(
+        img: &I,
+        scan_steps: &ScanSteps,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+        order: MarkerOrder,
+    ) -> Result<Markers> {
+        let find_marker = |corner: &Corner| {
+            Markers::find_marker(
+                img,
+                corner,
+                scan_steps.get(corner),
+                highlight_ceiling,
+                marker_color,
+            )
+        };
+
+        let top_left = find_marker(&Corner::TopLeft);
+        let top_right = find_marker(&Corner::TopRight);
+        let bottom_left = find_marker(&Corner::BottomLeft);
+        let bottom_right = find_marker(&Corner::BottomRight);
+
+        match (top_left, top_right, bottom_left, bottom_right) {
+            (Ok(top_left), Ok(top_right), Ok(bottom_left), Ok(bottom_right)) => {
+                Markers::new_with_order(top_left, top_right, bottom_left, bottom_right, order)
+            }
+            // If only one of the markers is missing then the sheet is assumed to be a
+            // parallelogram to check if the marker would be outside of the photo.
+            (Err(err), Ok(top_right), Ok(bottom_left), Ok(bottom_right)) => {
+                Err(Markers::out_of_frame_error(
+                    img,
+                    &Corner::TopLeft,
+                    &top_right,
+                    &bottom_left,
+                    &bottom_right,
+                )
+                .unwrap_or(err))
+            }
+            (Ok(top_left), Err(err), Ok(bottom_left), Ok(bottom_right)) => {
+                Err(Markers::out_of_frame_error(
+                    img,
+                    &Corner::TopRight,
+                    &top_left,
+                    &bottom_right,
+                    &bottom_left,
+                )
+                .unwrap_or(err))
+            }
+            (Ok(top_left), Ok(top_right), Err(err), Ok(bottom_right)) => {
+                Err(Markers::out_of_frame_error(
+                    img,
+                    &Corner::BottomLeft,
+                    &top_left,
+                    &bottom_right,
+                    &top_right,
+                )
+                .unwrap_or(err))
+            }
+            (Ok(top_left), Ok(top_right), Ok(bottom_left), Err(err)) => {
+                Err(Markers::out_of_frame_error(
+                    img,
+                    &Corner::BottomRight,
+                    &top_right,
+                    &bottom_left,
+                    &top_left,
+                )
+                .unwrap_or(err))
+            }
+            (top_left, top_right, bottom_left, bottom_right) => {
+                Markers::new_with_order(top_left?, top_right?, bottom_left?, bottom_right?, order)
+            }
+        }
+    }
+
+    // Returns an error if the marker in the given corner would be outside of the image assuming
+    // that the markers form a parallelogram. The adjacent markers are the ones sharing an edge
+    // with the missing one and the opposite marker is the one diagonally across from it.
+    fn out_of_frame_error<I: Image>(
+        img: &I,
+        corner: &Corner,
+        adjacent_a: &Marker,
+        adjacent_b: &Marker,
+        opposite: &Marker,
+    ) -> Option<anyhow::Error> {
+        let x = adjacent_a.center.x + adjacent_b.center.x - opposite.center.x;
+        let y = adjacent_a.center.y + adjacent_b.center.y - opposite.center.y;
+        if x >= 0.0 && y >= 0.0 && x < img.width() as f32 && y < img.height() as f32 {
+            return None;
+        }
+
+        Some(anyhow!(
+            "{} marker likely out of frame, it would be at {:.0},{:.0} outside of the {}x{} photo",
+            corner.name(),
+            x,
+            y,
+            img.width(),
+            img.height()
+        ))
+    }
+
+    // Scans the entire image and picks the marker candidates which are the closest to each
+    // corner of the image. This finds the markers even if the sheet occupies only a small part
+    // of the photo as they are the outermost marker-colored regions.
+    fn find_closest_to_corners<I: Image>(
+        img: &I,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+        order: MarkerOrder,
+    ) -> Result<Markers> {
+        let candidates = Markers::find_marker_candidates(img, highlight_ceiling, marker_color);
+        if candidates.len() < 4 {
+            return Err(anyhow!("found only {} marker candidates", candidates.len()));
+        }
+
+        let right = (img.width() - 1) as f32;
+        let bottom = (img.height() - 1) as f32;
+
+        let closest_to = |corner: PreciseXY| {
+            candidates
+                .iter()
+                .enumerate()
+                .min_by(|a, b| {
+                    let a = a.1.center().distance(&corner);
+                    let b = b.1.center().distance(&corner);
+                    a.total_cmp(&b)
+                })
+                .map(|v| v.0)
+                .unwrap()
+        };
+
+        let top_left = closest_to(PreciseXY::new(0.0, 0.0));
+        let top_right = closest_to(PreciseXY::new(right, 0.0));
+        let bottom_left = closest_to(PreciseXY::new(0.0, bottom));
+        let bottom_right = closest_to(PreciseXY::new(right, bottom));
+
+        let indices = HashSet::from([top_left, top_right, bottom_left, bottom_right]);
+        if indices.len() != 4 {
+            return Err(anyhow!(
+                "the same marker is the closest to multiple corners"
+            ));
+        }
+
+        Markers::new_with_order(
+            candidates[top_left].clone(),
+            candidates[top_right].clone(),
+            candidates[bottom_left].clone(),
+            candidates[bottom_right].clone(),
+            order,
+        )
+    }
+
+    // Same as new but if the markers aren't in the expected positions relative to each other
+    // the order policy may reassign them to the corners based on their positions instead.
+    pub fn new_with_order(
+        top_left: Marker,
+        top_right: Marker,
+        bottom_left: Marker,
+        bottom_right: Marker,
+        order: MarkerOrder,
+    ) -> Result<Markers> {
+        let markers = [top_left, top_right, bottom_left, bottom_right];
+        let err = match Markers::new(
+            markers[0].clone(),
+            markers[1].clone(),
+            markers[2].clone(),
+            markers[3].clone(),
+        ) {
+            Ok(markers) => return Ok(markers),
+            Err(err) => err,
+        };
+
+        if order == MarkerOrder::Strict {
+            return Err(err);
+        }
+
+        // The two upper markers are the top ones, each pair is then ordered from left to right.
+        let mut sorted: Vec<&Marker> = markers.iter().collect();
+        sorted.sort_by(|a, b| a.center().y.total_cmp(&b.center().y));
+        sorted[0..2].sort_by(|a, b| a.center().x.total_cmp(&b.center().x));
+        sorted[2..4].sort_by(|a, b| a.center().x.total_cmp(&b.center().x));
+
+        let corrected = Markers::new(
+            sorted[0].clone(),
+            sorted[1].clone(),
+            sorted[2].clone(),
+            sorted[3].clone(),
+        );
+        match (corrected, order) {
+            (Ok(markers), _) => Ok(markers),
+            // The quad may still be degenerate but using it is preferred over not extracting
+            // anything from the sheet.
+            (Err(_), MarkerOrder::BestEffort) => Ok(Markers {
+                top_left: sorted[0].clone(),
+                top_right: sorted[1].clone(),
+                bottom_left: sorted[2].clone(),
+                bottom_right: sorted[3].clone(),
+            }),
+            (Err(corrected_err), _) => Err(anyhow!(
+                "{err}, reordering the markers failed: {corrected_err}"
+            )),
+        }
+    }
 
+    pub fn new(
+        top_left: Marker,
+        top_right: Marker,
+        bottom_left: Marker,
+        bottom_right: Marker,
+    ) -> Result<Markers> {
         if top_left.center().x > top_right.center().x {
             return Err(anyhow!("top left must be to the left of top right"));
         }
@@ -85,17 +549,279 @@ impl Markers {
         })
     }
 
-    fn find_marker<I: Image>(img: &I, corner: &Corner) -> Result<Area> {
+    // Finds all sets of markers in the image e.g. when multiple sheets were photographed side by
+    // side. Marker candidates are grouped into quads which satisfy the same constraints as the
+    // ones found by find, consist of markers of a similar size and don't contain any other
+    // similarly sized candidates. A set of quads which don't overlap is then picked greedily.
+    pub fn find_all<I: Image>(img: &I, highlight_ceiling: u8) -> Vec<Markers> {
+        Markers::find_all_with_colors(img, &[MarkerColor::White], highlight_ceiling)
+    }
+
+    // Same as find_all but tries each of the marker colors in order and returns the sets of
+    // markers found using the first color which yields any.
+    pub fn find_all_with_colors<I: Image>(
+        img: &I,
+        marker_colors: &[MarkerColor],
+        highlight_ceiling: u8,
+    ) -> Vec<Markers> {
+        for marker_color in marker_colors {
+            let sheets = Markers::find_all_with_color(img, highlight_ceiling, *marker_color);
+            if !sheets.is_empty() {
+                return sheets;
+            }
+        }
+
+        vec![]
+    }
+
+    fn find_all_with_color<I: Image>(
+        img: &I,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+    ) -> Vec<Markers> {
+        let mut candidates = Markers::find_marker_candidates(img, highlight_ceiling, marker_color);
+        candidates.sort_by_key(|v| cmp::Reverse(v.area().area()));
+        candidates.truncate(MAX_MARKER_CANDIDATES);
+
+        let mut quads = vec![];
+        for a in 0..candidates.len() {
+            for b in (a + 1)..candidates.len() {
+                for c in (b + 1)..candidates.len() {
+                    for d in (c + 1)..candidates.len() {
+                        let indices = [a, b, c, d];
+                        if let Some(quad) = Markers::new_quad(&candidates, &indices) {
+                            let bounds = quad.quad_bounds();
+                            quads.push((quad, bounds));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Quads which overlap the fewest other quads are picked first, out of those the smaller
+        // ones. This prevents the gaps between sheets from being selected as those overlap the
+        // sheets on both sides of them.
+        let overlapping: Vec<usize> = quads
+            .iter()
+            .map(|(_, a)| quads.iter().filter(|(_, b)| overlaps(a, b)).count())
+            .collect();
+        let mut order: Vec<usize> = (0..quads.len()).collect();
+        order.sort_by(|a, b| {
+            overlapping[*a]
+                .cmp(&overlapping[*b])
+                .then(bounds_area(&quads[*a].1).total_cmp(&bounds_area(&quads[*b].1)))
+        });
+
+        let mut selected: Vec<usize> = vec![];
+        for i in order {
+            if selected
+                .iter()
+                .all(|v| !overlaps(&quads[*v].1, &quads[i].1))
+            {
+                selected.push(i);
+            }
+        }
+
+        let mut result: Vec<Markers> = vec![];
+        for (i, (quad, _)) in quads.into_iter().enumerate() {
+            if selected.contains(&i) {
+                result.push(quad);
+            }
+        }
+
+        result.sort_by(|a, b| {
+            let a = a.top_left.center();
+            let b = b.top_left.center();
+            a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y))
+        });
+
+        result
+    }
+
+    fn find_marker_candidates<I: Image>(
+        img: &I,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+    ) -> Vec<Marker> {
+        let step_x = cmp::max(1, (MARKER_SCAN_STEP * img.width() as f32) as usize);
+        let step_y = cmp::max(1, (MARKER_SCAN_STEP * img.height() as f32) as usize);
+
+        let mut visited: HashSet<XY> = HashSet::new();
+        let mut candidates = vec![];
+        let mut highlights = vec![];
+
+        for x in (0..img.width()).step_by(step_x) {
+            for y in (0..img.height()).step_by(step_y) {
+                let xy = XY { x, y };
+                if visited.contains(&xy) {
+                    continue;
+                }
+
+                let pixels = flood_fill(img, xy, |_xy: &XY, color: &AlphaColor| {
+                    is_marker_color(color, marker_color)
+                });
+                if is_at_least_this_much_of_image(pixels.len(), img, MARKER_THRESHOLD)
+                    && let Some(marker) = Marker::new_from_pixels(img, &pixels)
+                {
+                    if is_specular_highlight(img, &pixels, highlight_ceiling) {
+                        highlights.push(marker);
+                    } else {
+                        candidates.push(marker);
+                    }
+                }
+
+                visited.extend(pixels);
+            }
+        }
+
+        if candidates.len() < 4 {
+            candidates.extend(highlights);
+        }
+
+        candidates
+    }
+
+    fn new_quad(candidates: &[Marker], indices: &[usize; 4]) -> Option<Markers> {
+        let mut quad: Vec<&Marker> = indices.iter().map(|i| &candidates[*i]).collect();
+        quad.sort_by(|a, b| a.center().y.total_cmp(&b.center().y));
+        quad[0..2].sort_by(|a, b| a.center().x.total_cmp(&b.center().x));
+        quad[2..4].sort_by(|a, b| a.center().x.total_cmp(&b.center().x));
+
+        let smallest = quad.iter().map(|v| v.area().area()).min().unwrap() as f32;
+        let largest = quad.iter().map(|v| v.area().area()).max().unwrap() as f32;
+        if largest > smallest * MARKER_SIZE_RATIO {
+            return None;
+        }
+
+        let markers = Markers::new(
+            quad[0].clone(),
+            quad[1].clone(),
+            quad[2].clone(),
+            quad[3].clone(),
+        )
+        .ok()?;
+
+        let (left, top, right, bottom) = markers.quad_bounds();
+        let contains_other_markers = candidates.iter().enumerate().any(|(i, v)| {
+            let size = v.area().area() as f32;
+            let xy = v.center();
+            !indices.contains(&i)
+                && size <= largest * MARKER_SIZE_RATIO
+                && size >= smallest / MARKER_SIZE_RATIO
+                && xy.x >= left
+                && xy.x <= right
+                && xy.y >= top
+                && xy.y <= bottom
+        });
+        if contains_other_markers {
+            return None;
+        }
+
+        Some(markers)
+    }
+
+    // Returns left, top, right and bottom edges of the rectangle enclosing marker centers.
+    fn quad_bounds(&self) -> (f32, f32, f32, f32) {
+        let centers = [
+            self.top_left.center(),
+            self.top_right.center(),
+            self.bottom_left.center(),
+            self.bottom_right.center(),
+        ];
+        let left = centers.iter().map(|v| v.x).fold(f32::MAX, f32::min);
+        let top = centers.iter().map(|v| v.y).fold(f32::MAX, f32::min);
+        let right = centers.iter().map(|v| v.x).fold(f32::MIN, f32::max);
+        let bottom = centers.iter().map(|v| v.y).fold(f32::MIN, f32::max);
+        (left, top, right, bottom)
+    }
+
+    // Checks if the point lies inside of the quad formed by the centers of the markers, unlike
+    // quad_bounds this doesn't assume that the quad is a rectangle.
+    pub fn contains(&self, xy: &PreciseXY) -> bool {
+        let corners = [
+            self.top_left.center(),
+            self.top_right.center(),
+            self.bottom_right.center(),
+            self.bottom_left.center(),
+        ];
+
+        let mut has_positive = false;
+        let mut has_negative = false;
+        for i in 0..corners.len() {
+            let a = corners[i];
+            let b = corners[(i + 1) % corners.len()];
+            let cross = (b.x - a.x) * (xy.y - a.y) - (b.y - a.y) * (xy.x - a.x);
+            if cross > 0.0 {
+                has_positive = true;
+            }
+            if cross < 0.0 {
+                has_negative = true;
+            }
+        }
+
+        !(has_positive && has_negative)
+    }
+
+    // Fraction of the markers which were found instead of estimated multiplied by the ratio of
+    // the sizes of the smallest and the largest found marker, [0, 1].
+    pub fn confidence(&self) -> f32 {
+        let found: Vec<u32> = self
+            .markers()
+            .iter()
+            .filter(|v| !v.is_estimated())
+            .map(|v| v.area().area())
+            .collect();
+
+        let smallest = found.iter().min().copied().unwrap_or(0) as f32;
+        let largest = found.iter().max().copied().unwrap_or(0) as f32;
+        if largest == 0.0 {
+            return 0.0;
+        }
+
+        found.len() as f32 / 4.0 * smallest / largest
+    }
+
+    // Sine of the interior angle of the quad formed by the centers of the markers which is the
+    // furthest from a right angle, 1 for a rectangle and close to 0 for a degenerate quad.
+    pub fn quad_health(&self) -> f32 {
+        let corners = [
+            self.top_left.center(),
+            self.top_right.center(),
+            self.bottom_right.center(),
+            self.bottom_left.center(),
+        ];
+
+        (0..corners.len())
+            .map(|i| {
+                let previous = corners[(i + corners.len() - 1) % corners.len()];
+                let next = corners[(i + 1) % corners.len()];
+                let (ax, ay) = (previous.x - corners[i].x, previous.y - corners[i].y);
+                let (bx, by) = (next.x - corners[i].x, next.y - corners[i].y);
+                let lengths = (ax * ax + ay * ay).sqrt() * (bx * bx + by * by).sqrt();
+                if lengths == 0.0 {
+                    return 0.0;
+                }
+                (ax * by - ay * bx).abs() / lengths
+            })
+            .fold(1.0, f32::min)
+    }
+
+    fn find_marker<I: Image>(
+        img: &I,
+        corner: &Corner,
+        scan_steps: u32,
+        highlight_ceiling: u8,
+        marker_color: MarkerColor,
+    ) -> Result<Marker> {
         let step_x: u32 = cmp::max(1, (MARKER_SCAN_STEP * img.width() as f32) as u32);
         let step_y: u32 = cmp::max(1, (MARKER_SCAN_STEP * img.height() as f32) as u32);
 
-        let match_color = |_xy: &XY, color: &AlphaColor| {
-            let yuv: YUV = color.color().yuv();
-            yuv.y() > 0.7 && yuv.u().abs() < 0.15 && yuv.v().abs() < 0.15
-        };
+        let match_color = |_xy: &XY, color: &AlphaColor| is_marker_color(color, marker_color);
+        let mut highlight = None;
+        let mut clipped = false;
 
-        for step_x_i in 0..MARKER_SCAN_STEPS {
-            for step_y_i in 0..MARKER_SCAN_STEPS {
+        for step_x_i in 0..scan_steps {
+            for step_y_i in 0..scan_steps {
                 let x = match corner {
                     Corner::TopLeft => step_x_i * step_x,
                     Corner::TopRight => img.width() - 1 - (step_x_i * step_x),
@@ -110,25 +836,246 @@ impl Markers {
                 };
 
                 let pixels = flood_fill(img, XY { x, y }, match_color);
-                if !pixels.is_empty()
-                    && is_at_least_this_much_of_image(pixels.len(), img, MARKER_THRESHOLD)
+                if is_at_least_this_much_of_image(pixels.len(), img, MARKER_THRESHOLD)
+                    && let Some(marker) = Marker::new_from_pixels(img, &pixels)
                 {
-                    let area = Area::new_from_pixels(pixels).unwrap();
-                    return Ok(area);
+                    if !is_specular_highlight(img, &pixels, highlight_ceiling) {
+                        return Ok(marker);
+                    }
+                    highlight.get_or_insert(marker);
+                } else {
+                    clipped |= is_clipped_marker(img, &pixels, corner);
+                }
+            }
+        }
+
+        if let Some(marker) = highlight {
+            return Ok(marker);
+        }
+
+        // A marker-like region too small to be a marker which is cut off by the edge of the photo
+        // next to the corner is most likely the visible part of a marker which is mostly outside
+        // of it.
+        if clipped {
+            return Err(anyhow!(
+                "{} marker likely out of frame, only a small marker-colored region at the edge of the photo was found",
+                corner.name()
+            ));
+        }
+
+        Err(anyhow!(
+            "{} marker not found after scanning {}x{} positions",
+            corner.name(),
+            scan_steps,
+            scan_steps
+        ))
+    }
+
+    // Finds the markers by comparing the corners of the image with an image of a single marker
+    // using the normalised cross-correlation of luminance. This is slower than looking for the
+    // markers by their color but works e.g. for noisy scans in which the color of the markers
+    // isn't reliable. The corners span the same part of the image as when scanning for the
+    // markers using the given number of steps.
+    pub fn find_with_template<I: Image, T: Image>(
+        img: &I,
+        template: &T,
+        scan_steps: u32,
+    ) -> Result<Markers> {
+        Markers::check_scan_steps(scan_steps)?;
+
+        if template.width() > img.width() || template.height() > img.height() {
+            return Err(anyhow!("marker template is larger than the image"));
+        }
+
+        let find_marker =
+            |corner: &Corner| Markers::find_marker_with_template(img, template, corner, scan_steps);
+
+        let top_left = find_marker(&Corner::TopLeft)?;
+        let top_right = find_marker(&Corner::TopRight)?;
+        let bottom_left = find_marker(&Corner::BottomLeft)?;
+        let bottom_right = find_marker(&Corner::BottomRight)?;
+
+        Markers::new(top_left, top_right, bottom_left, bottom_right)
+    }
+
+    fn find_marker_with_template<I: Image, T: Image>(
+        img: &I,
+        template: &T,
+        corner: &Corner,
+        scan_steps: u32,
+    ) -> Result<Marker> {
+        let luminance = |color: AlphaColor| color.color().yuv().y();
+
+        let (template_width, template_height) = (template.width(), template.height());
+        let template_pixels: Vec<f32> = (0..template_height)
+            .flat_map(|y| (0..template_width).map(move |x| (x, y)))
+            .map(|(x, y)| luminance(template.get_pixel(x, y)))
+            .collect();
+        let count = template_pixels.len() as f32;
+        let template_mean = template_pixels.iter().sum::<f32>() / count;
+        let template_deviations: Vec<f32> =
+            template_pixels.iter().map(|v| v - template_mean).collect();
+        let template_norm = template_deviations
+            .iter()
+            .map(|v| v.powi(2))
+            .sum::<f32>()
+            .sqrt();
+        // The mean of equal values isn't always exactly equal to them so the deviations of a
+        // uniform template aren't necessarily 0.
+        if template_pixels.iter().all(|v| *v == template_pixels[0]) {
+            return Err(anyhow!("marker template has uniform brightness"));
+        }
+
+        // Positions of the top left corner of the template which keep it in the scanned part of
+        // the image.
+        let scan_width = (MARKER_SCAN_STEP * scan_steps as f32 * img.width() as f32) as u32;
+        let scan_height = (MARKER_SCAN_STEP * scan_steps as f32 * img.height() as f32) as u32;
+        let max_left = img.width() - template_width;
+        let max_top = img.height() - template_height;
+        let (lefts, tops) = match corner {
+            Corner::TopLeft => (0..=scan_width.min(max_left), 0..=scan_height.min(max_top)),
+            Corner::TopRight => (
+                max_left.saturating_sub(scan_width)..=max_left,
+                0..=scan_height.min(max_top),
+            ),
+            Corner::BottomLeft => (
+                0..=scan_width.min(max_left),
+                max_top.saturating_sub(scan_height)..=max_top,
+            ),
+            Corner::BottomRight => (
+                max_left.saturating_sub(scan_width)..=max_left,
+                max_top.saturating_sub(scan_height)..=max_top,
+            ),
+        };
+
+        let correlation = |left: u32, top: u32| {
+            let mut window = Vec::with_capacity(template_pixels.len());
+            for y in top..top + template_height {
+                for x in left..left + template_width {
+                    window.push(luminance(img.get_pixel(x, y)));
+                }
+            }
+
+            let mean = window.iter().sum::<f32>() / count;
+            let mut covariance = 0.0;
+            let mut norm = 0.0;
+            for (value, template_deviation) in window.iter().zip(&template_deviations) {
+                covariance += (value - mean) * template_deviation;
+                norm += (value - mean).powi(2);
+            }
+
+            if norm == 0.0 {
+                return 0.0;
+            }
+            covariance / (norm.sqrt() * template_norm)
+        };
+
+        // Coarse search first, then every position around the best coarse match.
+        let step = cmp::max(1, cmp::min(template_width, template_height) / 4) as usize;
+        let mut best = (f32::MIN, 0, 0);
+        for top in tops.clone().step_by(step) {
+            for left in lefts.clone().step_by(step) {
+                let value = correlation(left, top);
+                if value > best.0 {
+                    best = (value, left, top);
+                }
+            }
+        }
+
+        let (_, coarse_left, coarse_top) = best;
+        let step = step as u32;
+        let refine_lefts =
+            coarse_left.saturating_sub(step)..=cmp::min(coarse_left + step, *lefts.end());
+        let refine_tops =
+            coarse_top.saturating_sub(step)..=cmp::min(coarse_top + step, *tops.end());
+        for top in refine_tops {
+            for left in refine_lefts.clone() {
+                if !lefts.contains(&left) || !tops.contains(&top) {
+                    continue;
+                }
+
+                let value = correlation(left, top);
+                if value > best.0 {
+                    best = (value, left, top);
                 }
             }
         }
 
-        Err(anyhow!("not found"))
+        let (value, left, top) = best;
+        if value < TEMPLATE_MIN_CORRELATION {
+            return Err(anyhow!(
+                "{} marker not found, best match correlates with the template only {:.2}",
+                corner.name(),
+                value
+            ));
+        }
+
+        let area = Area::new(left, top, template_width, template_height);
+        let center = PreciseXY {
+            x: left as f32 + (template_width - 1) as f32 / 2.0,
+            y: top as f32 + (template_height - 1) as f32 / 2.0,
+        };
+        Ok(Marker::new(area, center, false))
+    }
+
+    // Average of the centers of all markers.
+    fn quad_center(&self) -> PreciseXY {
+        let markers = self.markers();
+        let count = markers.len() as f32;
+        PreciseXY {
+            x: markers.iter().map(|v| v.center().x).sum::<f32>() / count,
+            y: markers.iter().map(|v| v.center().y).sum::<f32>() / count,
+        }
     }
 
     pub fn middle_of_top_edge(&self) -> XY {
-        let x = (self.top_left.center().x + self.top_right.center().x) / 2;
-        let y = (self.top_left.center().y + self.top_right.center().y) / 2;
-        XY { x, y }
+        let x = (self.top_left.center().x + self.top_right.center().x) / 2.0;
+        let y = (self.top_left.center().y + self.top_right.center().y) / 2.0;
+        PreciseXY { x, y }.to_xy()
+    }
+
+    // Width to height ratio of the quad formed by the markers.
+    pub fn aspect_ratio(&self) -> f32 {
+        let top = self.top_left.center().distance(self.top_right.center());
+        let bottom = self
+            .bottom_left
+            .center()
+            .distance(self.bottom_right.center());
+        let left = self.top_left.center().distance(self.bottom_left.center());
+        let right = self.top_right.center().distance(self.bottom_right.center());
+        (top + bottom) / (left + right)
+    }
+
+    // Checks if the quad is landscape when a portrait sheet is expected or the other way around.
+    pub fn is_sideways(&self, expected_aspect_ratio: f32) -> bool {
+        let aspect_ratio = self.aspect_ratio();
+        (aspect_ratio > 1.0 && expected_aspect_ratio < 1.0)
+            || (aspect_ratio < 1.0 && expected_aspect_ratio > 1.0)
+    }
+
+    // Returns the markers as they would be positioned in the image after resizing it by the
+    // given factor.
+    pub fn scale(&self, factor: f32) -> Markers {
+        Markers {
+            top_left: self.top_left.scale(factor),
+            top_right: self.top_right.scale(factor),
+            bottom_left: self.bottom_left.scale(factor),
+            bottom_right: self.bottom_right.scale(factor),
+        }
+    }
+
+    // Returns the markers as they would be positioned in the image after rotating it by 90
+    // degrees clockwise.
+    pub fn rotate_clockwise(&self, img_height: u32) -> Markers {
+        Markers {
+            top_left: self.bottom_left.rotate_clockwise(img_height),
+            top_right: self.top_left.rotate_clockwise(img_height),
+            bottom_left: self.bottom_right.rotate_clockwise(img_height),
+            bottom_right: self.top_right.rotate_clockwise(img_height),
+        }
     }
 
-    pub fn markers(&self) -> Vec<&Area> {
+    pub fn markers(&self) -> Vec<&Marker> {
         vec![
             &self.top_left,
             &self.top_right,
@@ -137,140 +1084,847 @@ impl Markers {
         ]
     }
 
-    pub fn top_left(&self) -> &Area {
+    pub fn top_left(&self) -> &Marker {
         &self.top_left
     }
 
-    pub fn top_right(&self) -> &Area {
+    pub fn top_right(&self) -> &Marker {
         &self.top_right
     }
 
-    pub fn bottom_left(&self) -> &Area {
+    pub fn bottom_left(&self) -> &Marker {
         &self.bottom_left
     }
 
-    pub fn bottom_right(&self) -> &Area {
+    pub fn bottom_right(&self) -> &Marker {
         &self.bottom_right
     }
 }
 
+// Checks if two rectangles returned by Markers::quad_bounds overlap.
+fn overlaps(a: &(f32, f32, f32, f32), b: &(f32, f32, f32, f32)) -> bool {
+    let (left, top, right, bottom) = a;
+    let (other_left, other_top, other_right, other_bottom) = b;
+    left <= other_right && other_left <= right && top <= other_bottom && other_top <= bottom
+}
+
+fn bounds_area(bounds: &(f32, f32, f32, f32)) -> f32 {
+    let (left, top, right, bottom) = bounds;
+    (right - left) * (bottom - top)
+}
+
+fn is_specular_highlight<I: Image>(img: &I, pixels: &HashSet<XY>, ceiling: u8) -> bool {
+    if !is_at_least_this_much_of_image(pixels.len(), img, HIGHLIGHT_MIN_SIZE) {
+        return false;
+    }
+
+    let clipped = pixels
+        .iter()
+        .filter(|xy| {
+            let rgb = img.get_pixel(xy.x, xy.y).color().rgb();
+            rgb.r() >= ceiling && rgb.g() >= ceiling && rgb.b() >= ceiling
+        })
+        .count();
+    clipped as f32 >= pixels.len() as f32 * HIGHLIGHT_CLIPPED_FRACTION
+}
+
+fn is_clipped_marker<I: Image>(img: &I, pixels: &HashSet<XY>, corner: &Corner) -> bool {
+    if !is_at_least_this_much_of_image(
+        pixels.len(),
+        img,
+        MARKER_THRESHOLD * CLIPPED_MARKER_MIN_SIZE,
+    ) {
+        return false;
+    }
+
+    let Some(area) = Area::new_from_pixels(pixels) else {
+        return false;
+    };
+    if (pixels.len() as f32) < area.area() as f32 * CLIPPED_MARKER_MIN_FILL {
+        return false;
+    }
+
+    let (edge_x, edge_y) = match corner {
+        Corner::TopLeft => (0, 0),
+        Corner::TopRight => (img.width() - 1, 0),
+        Corner::BottomLeft => (0, img.height() - 1),
+        Corner::BottomRight => (img.width() - 1, img.height() - 1),
+    };
+    let cut_x = pixels.iter().filter(|xy| xy.x == edge_x).count();
+    let cut_y = pixels.iter().filter(|xy| xy.y == edge_y).count();
+    cut_x as f32 >= area.height as f32 * CLIPPED_MARKER_MIN_CUT
+        || cut_y as f32 >= area.width as f32 * CLIPPED_MARKER_MIN_CUT
+}
+
+fn is_marker_color(color: &AlphaColor, marker_color: MarkerColor) -> bool {
+    let yuv: YUV = color.color().yuv();
+    let is_neutral = yuv.u().abs() < 0.15 && yuv.v().abs() < 0.15;
+    match marker_color {
+        MarkerColor::White => yuv.y() > 0.7 && is_neutral,
+        MarkerColor::Black => yuv.y() < 0.2 && is_neutral,
+    }
+}
+
 pub struct Background {
-    areas: HashMap<Area, Color>,
-    interpolated_colors: Vec<Vec<Color>>,
+    // Ordered so that the results which depend on the order of the samples e.g. sums of floats
+    // are the same every time.
+    areas: BTreeMap<Area, Color>,
+    interpolated_colors: Vec<Vec<CachedColor>>,
 }
 
 impl Background {
-    pub fn analyse<I: Image>(img: &I, markers: &Markers) -> Result<Background> {
-        let mut areas = HashMap::new();
-
-        let marker_width = markers.top_left.width;
-        let marker_height = markers.top_left.height;
+    // Samples the background along the edges of the quad formed by the markers and
+    // interpolates it for every pixel of the image. If mirroring the samples is enabled each
+    // sample is averaged with the sample closest to its reflection across the center of the
+    // quad which stabilises the model when the lighting is symmetric. The background reference
+    // can instead make the entire background a single color picked from the samples next to the
+    // markers.
+    pub fn analyse<I: Image>(
+        img: &I,
+        markers: &Markers,
+        config: &ExtractionConfig,
+    ) -> Result<Background> {
+        let mut samples = vec![];
+        let mut corner_samples = vec![];
+
+        let marker_width = markers.top_left.area.width;
+        let marker_height = markers.top_left.area.height;
 
         let iter_top = EdgeIterator::new(
-            markers.top_left.center(),
-            markers.top_right.center(),
+            markers.top_left.center().clone(),
+            markers.top_right.center().clone(),
             BACKGROUND_ANALYSIS_STEPS,
         )?;
 
         let iter_bottom = EdgeIterator::new(
-            markers.bottom_left.center(),
-            markers.bottom_right.center(),
+            markers.bottom_left.center().clone(),
+            markers.bottom_right.center().clone(),
             BACKGROUND_ANALYSIS_STEPS,
         )?;
 
         let iter_left = EdgeIterator::new(
-            markers.top_left.center(),
-            markers.bottom_left.center(),
+            markers.top_left.center().clone(),
+            markers.bottom_left.center().clone(),
             BACKGROUND_ANALYSIS_STEPS,
         )?;
 
         let iter_right = EdgeIterator::new(
-            markers.top_right.center(),
-            markers.bottom_right.center(),
+            markers.top_right.center().clone(),
+            markers.bottom_right.center().clone(),
             BACKGROUND_ANALYSIS_STEPS,
         )?;
 
-        for (i, xy) in iter_top
-            .chain(iter_bottom)
-            .chain(iter_left)
-            .chain(iter_right)
-        {
-            if i == 0 || i == BACKGROUND_ANALYSIS_STEPS - 1 {
-                continue;
-            }
-
-            let area = Area {
-                top: xy.y - marker_height / 2,
-                left: xy.x - marker_width / 2,
-                width: marker_width,
-                height: marker_height,
-            };
+        for edge in [iter_top, iter_bottom, iter_left, iter_right] {
+            let mut edge_samples = vec![];
+            for (i, xy) in edge {
+                if i == 0 || i == BACKGROUND_ANALYSIS_STEPS - 1 {
+                    continue;
+                }
 
-            let color: Color = area.average_color(img)?;
-            areas.insert(area, color);
-        }
+                let area = Area {
+                    top: xy.y - marker_height / 2,
+                    left: xy.x - marker_width / 2,
+                    width: marker_width,
+                    height: marker_height,
+                };
 
-        let row_size = img.width() as usize;
-        let column_size = img.height() as usize;
+                let color: Color = area.average_color(img)?;
+                let luminance = area.luminance_statistics(img);
+                let corner = i == 1 || i == BACKGROUND_ANALYSIS_STEPS - 2;
+                edge_samples.push((area, color, luminance, corner));
+            }
 
-        let mut interpolated_colors = Vec::with_capacity(row_size);
+            // Glare on a glossy sheet makes samples uniformly bright which would brighten the
+            // background model around them so such samples are skipped unless all of them are.
+            let statistics: Vec<(f32, f32)> = edge_samples.iter().map(|v| v.2).collect();
+            let glare = Self::find_glare(&statistics);
+            let keep_all = glare.iter().all(|v| *v);
 
-        for x in 0..row_size {
-            let mut column = Vec::with_capacity(column_size);
+            for ((area, color, _, corner), glare) in edge_samples.into_iter().zip(glare) {
+                if glare && !keep_all {
+                    continue;
+                }
 
-            for y in 0..column_size {
-                let xy = XY::new(x as u32, y as u32);
+                if corner {
+                    corner_samples.push(color.clone());
+                }
+                samples.push((area, color));
+            }
+        }
 
-                let mut y = 0.0;
-                let mut u = 0.0;
-                let mut v = 0.0;
-                let mut distances = 0.0;
+        let mut areas = BTreeMap::new();
+        if config.mirror_background_samples {
+            let quad_center = markers.quad_center();
+            for (area, color) in samples.iter() {
+                let center = area.center();
+                let mirrored = PreciseXY {
+                    x: 2.0 * quad_center.x - center.x,
+                    y: 2.0 * quad_center.y - center.y,
+                };
 
-                for (area, color) in areas.iter() {
-                    let yuv = color.yuv();
-                    // powi to bias towards closer points
-                    let distance = 1.0 / (xy.distance(&area.center()).powi(2));
-                    y += distance * yuv.y();
-                    u += distance * yuv.u();
-                    v += distance * yuv.v();
-                    distances += distance;
-                }
+                let (_, opposite_color) = samples
+                    .iter()
+                    .min_by(|a, b| {
+                        let a = a.0.center().distance(&mirrored);
+                        let b = b.0.center().distance(&mirrored);
+                        a.total_cmp(&b)
+                    })
+                    .unwrap();
 
-                let yuv = YUV::new(y / distances, u / distances, v / distances)?;
-                column.push(yuv.into());
+                areas.insert(area.clone(), color.mix(opposite_color, 0.5, MixSpace::Yuv));
             }
+        } else {
+            areas.extend(samples);
+        }
 
-            interpolated_colors.push(column);
+        if let BackgroundReference::Corners { percentile } = config.background_reference {
+            let color = CachedColor::from(Self::percentile_color(corner_samples, percentile)?);
+            return Ok(Background {
+                areas,
+                interpolated_colors: vec![vec![color; img.height() as usize]; img.width() as usize],
+            });
         }
 
+        let points: Vec<(XY, &Color)> = areas
+            .iter()
+            .map(|(area, color)| (area.center().to_xy(), color))
+            .collect();
+        let interpolated_colors = Self::interpolate(img, &points, config)?;
+
         Ok(Background {
             areas,
             interpolated_colors,
         })
     }
 
-    pub fn check_color(&self, xy: &XY) -> &Color {
-        &self.interpolated_colors[xy.x as usize][xy.y as usize]
-    }
+    // Builds the background model from samples centered on the given points instead of
+    // sampling the edges of the quad formed by the markers, e.g. when it is known where the
+    // clean background is. Each sample is the average color of a square with sides of the given
+    // size which is moved inside of the image if necessary, the background color at each of the
+    // points is the color of its sample. The samples are interpolated like in analyse, the
+    // corners background reference picks the color out of all samples.
+    pub fn analyse_with_samples<I: Image>(
+        img: &I,
+        points: &[XY],
+        area_size: u32,
+        config: &ExtractionConfig,
+    ) -> Result<Background> {
+        if points.is_empty() {
+            return Err(anyhow!("no background sample points"));
+        }
 
-    pub fn areas(&self) -> &HashMap<Area, Color> {
-        &self.areas
-    }
-}
+        if area_size == 0 || area_size > img.width() || area_size > img.height() {
+            return Err(anyhow!(
+                "background sample size must be positive and fit in the image"
+            ));
+        }
 
-enum Corner {
-    TopLeft,
-    TopRight,
+        let mut areas = BTreeMap::new();
+        let mut samples = vec![];
+        for xy in points {
+            if xy.x >= img.width() || xy.y >= img.height() {
+                return Err(anyhow!(
+                    "background sample point ({}, {}) is outside of the image",
+                    xy.x,
+                    xy.y
+                ));
+            }
+
+            let area = Area {
+                top: cmp::min(xy.y.saturating_sub(area_size / 2), img.height() - area_size),
+                left: cmp::min(xy.x.saturating_sub(area_size / 2), img.width() - area_size),
+                width: area_size,
+                height: area_size,
+            };
+
+            let color = area.average_color(img)?;
+            areas.insert(area, color.clone());
+            samples.push((xy.clone(), color));
+        }
+
+        if let BackgroundReference::Corners { percentile } = config.background_reference {
+            let colors = samples.into_iter().map(|(_, color)| color).collect();
+            let color = CachedColor::from(Self::percentile_color(colors, percentile)?);
+            return Ok(Background {
+                areas,
+                interpolated_colors: vec![vec![color; img.height() as usize]; img.width() as usize],
+            });
+        }
+
+        let points: Vec<(XY, &Color)> = samples
+            .iter()
+            .map(|(xy, color)| (xy.clone(), color))
+            .collect();
+        let interpolated_colors = Self::interpolate(img, &points, config)?;
+
+        Ok(Background {
+            areas,
+            interpolated_colors,
+        })
+    }
+
+    // Interpolates the colors of the samples centered on the given points for every pixel of
+    // the image weighting them by their distance from the pixel.
+    fn interpolate<I: Image>(
+        img: &I,
+        points: &[(XY, &Color)],
+        config: &ExtractionConfig,
+    ) -> Result<Vec<Vec<CachedColor>>> {
+        let space = config.background_interpolation;
+        let row_size = img.width() as usize;
+        let column_size = img.height() as usize;
+
+        let mut interpolated_colors = Vec::with_capacity(row_size);
+
+        let sample_channels: Vec<(XY, &Color, [f32; 3])> = points
+            .iter()
+            .map(|(xy, color)| (xy.clone(), *color, color.channels(space)))
+            .collect();
+
+        let weight = |distance: f32| match config.background_weighting {
+            // powi to bias towards closer points
+            BackgroundWeighting::InverseDistance => 1.0 / distance.powi(2),
+            BackgroundWeighting::Gaussian { sigma } => {
+                let sigma = sigma * cmp::max(img.width(), img.height()) as f32;
+                (-distance.powi(2) / (2.0 * sigma.powi(2))).exp()
+            }
+        };
+
+        for x in 0..row_size {
+            check_cancelled(&config.cancel)?;
+            let mut column = Vec::with_capacity(column_size);
+
+            for y in 0..column_size {
+                let xy = XY::new(x as u32, y as u32);
+
+                // The weights are infinite at the samples themselves.
+                if let Some((_, color, _)) = sample_channels.iter().find(|v| v.0 == xy) {
+                    column.push((*color).clone().into());
+                    continue;
+                }
+
+                let mut channels = [0.0; 3];
+                let mut distances = 0.0;
+
+                for (center, _, sample) in sample_channels.iter() {
+                    let distance = weight(xy.distance(center));
+                    for (channel, value) in channels.iter_mut().zip(sample) {
+                        *channel += distance * value;
+                    }
+                    distances += distance;
+                }
+
+                // Gaussian weights of all samples underflow far away from them.
+                if distances <= 0.0 {
+                    let (_, color, _) = sample_channels
+                        .iter()
+                        .min_by(|a, b| xy.distance(&a.0).total_cmp(&xy.distance(&b.0)))
+                        .ok_or(anyhow!("no background samples"))?;
+                    column.push((*color).clone().into());
+                    continue;
+                }
+
+                column.push(Color::from_channels(channels.map(|v| v / distances), space)?.into());
+            }
+
+            interpolated_colors.push(column);
+        }
+
+        Ok(interpolated_colors)
+    }
+
+    // Flags the samples along an edge which are glare given the mean and the variance of their
+    // luminance. Glare usually covers more than one sample so neighbouring uniform samples with
+    // a similar luminance are grouped and a group is glare if it's brighter than the samples on
+    // both sides of it.
+    fn find_glare(statistics: &[(f32, f32)]) -> Vec<bool> {
+        let is_uniform = |i: usize| statistics[i].1 <= GLARE_MAX_LUMINANCE_VARIANCE;
+
+        let mut glare = vec![false; statistics.len()];
+        let mut start = 0;
+        while start < statistics.len() {
+            let mut end = start + 1;
+            if !is_uniform(start) {
+                start = end;
+                continue;
+            }
+
+            while end < statistics.len()
+                && is_uniform(end)
+                && (statistics[end].0 - statistics[end - 1].0).abs() < GLARE_MIN_BRIGHTENING
+            {
+                end += 1;
+            }
+
+            let darkest = statistics[start..end]
+                .iter()
+                .map(|v| v.0)
+                .fold(f32::MAX, f32::min);
+            let neighbours: Vec<f32> = [start.checked_sub(1), Some(end)]
+                .into_iter()
+                .flatten()
+                .filter_map(|i| statistics.get(i))
+                .map(|v| v.0)
+                .collect();
+
+            let is_glare = !neighbours.is_empty()
+                && neighbours
+                    .iter()
+                    .all(|v| darkest - v >= GLARE_MIN_BRIGHTENING);
+            glare[start..end].fill(is_glare);
+            start = end;
+        }
+        glare
+    }
+
+    // Returns the sample whose luminance is at the given percentile of the luminances of all
+    // samples, percentile is in [0, 1].
+    fn percentile_color(mut samples: Vec<Color>, percentile: f32) -> Result<Color> {
+        if !(0.0..=1.0).contains(&percentile) {
+            return Err(anyhow!("background percentile must be between 0 and 1"));
+        }
+
+        if samples.is_empty() {
+            return Err(anyhow!("no background samples next to the markers"));
+        }
+
+        samples.sort_by(|a, b| a.yuv().y().total_cmp(&b.yuv().y()));
+        let i = (percentile * (samples.len() - 1) as f32).round() as usize;
+        Ok(samples.swap_remove(i))
+    }
+
+    pub fn check_color(&self, xy: &XY) -> &CachedColor {
+        &self.interpolated_colors[xy.x as usize][xy.y as usize]
+    }
+
+    // Returns the background color for a pixel of an image which is larger than the analysed
+    // image, the scale is the factor by which the analysed image was resized.
+    pub fn check_color_scaled(&self, xy: &XY, scale: f32) -> &CachedColor {
+        let width = self.interpolated_colors.len();
+        let height = self.interpolated_colors[0].len();
+        let x = cmp::min((xy.x as f32 * scale) as usize, width - 1);
+        let y = cmp::min((xy.y as f32 * scale) as usize, height - 1);
+        &self.interpolated_colors[x][y]
+    }
+
+    pub fn areas(&self) -> &BTreeMap<Area, Color> {
+        &self.areas
+    }
+
+    // Paints the samples over a copy of the analysed image using their colors.
+    pub fn render_samples(&self, img: &RgbaImage) -> RgbaImage {
+        let mut img = img.clone();
+        for (area, color) in &self.areas {
+            area.color(&mut img, color);
+        }
+        img
+    }
+
+    // Estimates the noise of the image as the standard deviation of the lightness of the pixels
+    // in the background samples. It's calculated from the differences between neighbouring
+    // pixels using the median absolute deviation so that gradients and the occasional edge in
+    // a sample don't count as noise. The scale is the factor by which the analysed image was
+    // resized compared to the given image.
+    pub fn noise<I: Image>(&self, img: &I, scale: f32) -> f32 {
+        let median = |mut values: Vec<f32>| {
+            if values.is_empty() {
+                return 0.0;
+            }
+            values.sort_by(|a, b| a.total_cmp(b));
+            values[values.len() / 2]
+        };
+
+        let noises = self
+            .areas
+            .keys()
+            .map(|area| {
+                let area = area.scale(1.0 / scale);
+                let right = cmp::min(area.right(), img.width() - 1);
+                let bottom = cmp::min(area.bottom(), img.height() - 1);
+                let lightness = |x: u32, y: u32| img.get_pixel(x, y).color().lab().l();
+
+                let mut differences = vec![];
+                for y in area.top..=bottom {
+                    for x in area.left..right {
+                        differences.push((lightness(x + 1, y) - lightness(x, y)).abs());
+                    }
+                }
+
+                // The median absolute deviation of normally distributed values is 0.6745 of
+                // their standard deviation, differences of two pixels have √2 times larger
+                // deviation than the pixels.
+                median(differences) / (0.6745 * std::f32::consts::SQRT_2)
+            })
+            .collect();
+
+        median(noises)
+    }
+
+    // Average of the colors of all background samples calculated in LAB.
+    pub fn mean_color(&self) -> Option<Color> {
+        if self.areas.is_empty() {
+            return None;
+        }
+
+        let count = self.areas.len() as f32;
+        let (mut l, mut a, mut b) = (0.0, 0.0, 0.0);
+        for color in self.areas.values() {
+            let lab = color.lab();
+            l += lab.l();
+            a += lab.a();
+            b += lab.b();
+        }
+
+        LAB::new(l / count, a / count, b / count)
+            .ok()
+            .map(Color::from)
+    }
+
+    // Returns the fraction of pixels inside of the quad formed by the markers which weren't
+    // removed even though they are still similar to the background. High values mean that the
+    // background wasn't fully removed. The scale is the factor by which the analysed image was
+    // resized compared to the given image.
+    pub fn residual<I: Image>(&self, img: &I, markers: &Markers, scale: f32) -> f32 {
+        let (left, top, right, bottom) = markers.quad_bounds();
+        let left = left.max(0.0) as u32;
+        let top = top.max(0.0) as u32;
+        let right = cmp::min(right.max(0.0) as u32, img.width() - 1);
+        let bottom = cmp::min(bottom.max(0.0) as u32, img.height() - 1);
+
+        let mut inside = 0;
+        let mut residual = 0;
+        for x in left..=right {
+            for y in top..=bottom {
+                let xy = XY { x, y };
+                if !markers.contains(&(&xy).into()) {
+                    continue;
+                }
+
+                inside += 1;
+
+                let color = img.get_pixel(x, y);
+                if color.is_transparent() {
+                    continue;
+                }
+
+                let background = self.check_color_scaled(&xy, scale).lab();
+                if color.color().lab().delta_e_2000(&background) <= RESIDUAL_BACKGROUND_DELTA_E {
+                    residual += 1;
+                }
+            }
+        }
+
+        if inside == 0 {
+            return 0.0;
+        }
+
+        residual as f32 / inside as f32
+    }
+}
+
+// Photo of a single sheet with the markers located and the background analysed. The same image
+// can be processed multiple times using different removal settings without repeating those
+// steps. Settings which affect the analysis i.e. the chroma key, the tile size and the
+// background reference are taken from the config used to prepare the image.
+pub struct PreparedImage {
+    img: RgbaImage,
+    markers: Markers,
+    background: BackgroundModel,
+
+    // Height of the photo before rotating it if the sheet was sideways.
+    rotated_photo_height: Option<u32>,
+}
+
+pub enum BackgroundModel {
+    ChromaKey(ChromaKey),
+    Full(Background),
+
+    // Background analysed using a copy of the image downscaled by the given factor.
+    Tiled {
+        background: Background,
+        scale: f32,
+        tile_size: u32,
+    },
+}
+
+impl PreparedImage {
+    // The intermediate images are passed to the given function together with their names e.g.
+    // so that they can be previewed.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "prepare_image", skip_all)
+    )]
+    pub fn new<P>(
+        mut img: RgbaImage,
+        markers: &Markers,
+        config: &ExtractionConfig,
+        mut preview: P,
+    ) -> Result<Self>
+    where
+        P: FnMut(&RgbaImage, &str) -> Result<()>,
+    {
+        let mut markers = markers.clone();
+        let mut rotated_photo_height = None;
+        if let Some(sheet_aspect_ratio) = config.sheet_aspect_ratio
+            && markers.is_sideways(sheet_aspect_ratio)
+        {
+            info!("Rotating sideways sheet...");
+            rotated_photo_height = Some(img.height());
+            markers = markers.rotate_clockwise(img.height());
+            img = imageops::rotate90(&img);
+        }
+
+        if config.correct_exposure {
+            info!("Correcting exposure...");
+            correct_exposure_with_colors(&mut img, &markers, &config.marker_colors)?;
+            preview(&img, "exposure_corrected")?;
+        }
+
+        let red: Color = RGB::new(255, 0, 0).into();
+        for marker in markers.markers() {
+            if !marker.is_estimated() {
+                marker.area().color(&mut img, &red);
+            }
+        }
+        preview(&img, "markers")?;
+
+        let background = if let Some(chroma_key) = &config.chroma_key {
+            BackgroundModel::ChromaKey(chroma_key.clone())
+        } else if let Some(tile_size) = config.tile_size {
+            info!("Analysing background...");
+            let (downscaled, scale) = downscale(
+                &img,
+                TILED_PROCESSING_DOWNSCALED_SIZE,
+                config.resample_filter,
+            );
+            let background = Background::analyse(&downscaled, &markers.scale(scale), config)?;
+            preview(
+                &background.render_samples(&downscaled),
+                "background_samples",
+            )?;
+            BackgroundModel::Tiled {
+                background,
+                scale,
+                tile_size,
+            }
+        } else {
+            info!("Analysing background...");
+            let background = Background::analyse(&img, &markers, config)?;
+            preview(&background.render_samples(&img), "background_samples")?;
+            BackgroundModel::Full(background)
+        };
+
+        Ok(Self {
+            img,
+            markers,
+            background,
+            rotated_photo_height,
+        })
+    }
+
+    // Photo rotated if the sheet was sideways with the markers painted over.
+    pub fn img(&self) -> &RgbaImage {
+        &self.img
+    }
+
+    pub fn markers(&self) -> &Markers {
+        &self.markers
+    }
+
+    pub fn background(&self) -> &BackgroundModel {
+        &self.background
+    }
+
+    pub fn rotated_photo_height(&self) -> Option<u32> {
+        self.rotated_photo_height
+    }
+}
+
+// Resizes the image so that it's longer side is at most of the given size. Returns the resized
+// image and the factor by which it was scaled.
+pub fn downscale(
+    img: &RgbaImage,
+    max_size: u32,
+    filter: Option<ResampleFilter>,
+) -> (RgbaImage, f32) {
+    let longer_side = cmp::max(img.width(), img.height());
+    if longer_side <= max_size {
+        return (img.clone(), 1.0);
+    }
+
+    let scale = max_size as f32 / longer_side as f32;
+    let width = cmp::max(1, (img.width() as f32 * scale) as u32);
+    let height = cmp::max(1, (img.height() as f32 * scale) as u32);
+    let filter = filter.map_or(FilterType::Triangle, |v| v.filter_type());
+    (imageops::resize(img, width, height, filter), scale)
+}
+
+// Returns the indices of the background colors whose color cast differs from the median color
+// cast of all of them by more than the threshold, e.g. photos taken after the lighting changed
+// in the middle of a session. The cast is measured as the distance in the a and b channels of
+// LAB so that differences in exposure alone are ignored.
+pub fn find_color_cast_outliers(colors: &[Color], threshold: f32) -> Vec<usize> {
+    if colors.len() < 3 {
+        return vec![];
+    }
+
+    let median = |mut values: Vec<f32>| {
+        values.sort_by(|a, b| a.total_cmp(b));
+        let middle = values.len() / 2;
+        if values.len().is_multiple_of(2) {
+            (values[middle - 1] + values[middle]) / 2.0
+        } else {
+            values[middle]
+        }
+    };
+
+    let labs: Vec<LAB> = colors.iter().map(|v| v.lab()).collect();
+    let median_a = median(labs.iter().map(|v| v.a()).collect());
+    let median_b = median(labs.iter().map(|v| v.b()).collect());
+
+    labs.iter()
+        .enumerate()
+        .filter(|(_, lab)| {
+            ((lab.a() - median_a).powi(2) + (lab.b() - median_b).powi(2)).sqrt() > threshold
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+enum Corner {
+    TopLeft,
+    TopRight,
     BottomLeft,
     BottomRight,
 }
 
+impl Corner {
+    fn name(&self) -> &'static str {
+        match self {
+            Corner::TopLeft => "top left",
+            Corner::TopRight => "top right",
+            Corner::BottomLeft => "bottom left",
+            Corner::BottomRight => "bottom right",
+        }
+    }
+}
+
+// Number of steps performed when looking for the marker near each corner of the image.
+struct ScanSteps {
+    top_left: u32,
+    top_right: u32,
+    bottom_left: u32,
+    bottom_right: u32,
+}
+
+impl ScanSteps {
+    // Uses the default number of steps for the corners which don't override it.
+    fn new(default: u32, corners: &CornerScanSteps) -> Self {
+        Self {
+            top_left: corners.top_left.unwrap_or(default),
+            top_right: corners.top_right.unwrap_or(default),
+            bottom_left: corners.bottom_left.unwrap_or(default),
+            bottom_right: corners.bottom_right.unwrap_or(default),
+        }
+    }
+
+    fn uniform(steps: u32) -> Self {
+        ScanSteps::new(steps, &CornerScanSteps::default())
+    }
+
+    fn get(&self, corner: &Corner) -> u32 {
+        match corner {
+            Corner::TopLeft => self.top_left,
+            Corner::TopRight => self.top_right,
+            Corner::BottomLeft => self.bottom_left,
+            Corner::BottomRight => self.bottom_right,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        for steps in [
+            self.top_left,
+            self.top_right,
+            self.bottom_left,
+            self.bottom_right,
+        ] {
+            Markers::check_scan_steps(steps)?;
+        }
+        Ok(())
+    }
+}
+
 pub fn flood_fill<I, FM>(img: &I, xy: XY, match_color: FM) -> HashSet<XY>
 where
     I: Image,
     FM: Fn(&XY, &AlphaColor) -> bool,
 {
-    let mut pixels = HashSet::new();
+    flood_fill_into(img, xy, Connectivity::Four, match_color)
+}
+
+// Same as flood_fill but pixels can also be connected diagonally.
+pub fn flood_fill_with_connectivity<I, FM>(
+    img: &I,
+    xy: XY,
+    connectivity: Connectivity,
+    match_color: FM,
+) -> HashSet<XY>
+where
+    I: Image,
+    FM: Fn(&XY, &AlphaColor) -> bool,
+{
+    flood_fill_into(img, xy, connectivity, match_color)
+}
+
+// Same as flood_fill but the pixels are returned in raster order (top to bottom, left to right)
+// so that the output is always the same for the same input.
+pub fn flood_fill_ordered<I, FM>(img: &I, xy: XY, match_color: FM) -> Vec<XY>
+where
+    I: Image,
+    FM: Fn(&XY, &AlphaColor) -> bool,
+{
+    let mut pixels: Vec<XY> = flood_fill(img, xy, match_color).into_iter().collect();
+    pixels.sort_by_key(|v| (v.y, v.x));
+    pixels
+}
+
+// Same as flood_fill but the pixels are stored in a mask which uses a lot less memory than a set
+// when the filled region covers a large part of the image. Returns Cancelled if the token is set
+// while filling.
+pub fn flood_fill_mask<I, FM>(
+    img: &I,
+    xy: XY,
+    cancel: &AtomicBool,
+    match_color: FM,
+) -> Result<PixelMask>
+where
+    I: Image,
+    FM: Fn(&XY, &AlphaColor) -> bool,
+{
+    // Once the token is set no more pixels match so the fill stops right away.
+    let pixels = flood_fill_into(
+        img,
+        xy,
+        Connectivity::Four,
+        |xy: &XY, color: &AlphaColor| !cancel.load(Ordering::Relaxed) && match_color(xy, color),
+    );
+    check_cancelled(cancel)?;
+    Ok(pixels)
+}
+
+// Implementation shared by all variants of flood_fill which only differ in the connectivity and
+// in how the pixels are stored. Every pixel is queued at most once.
+#[allow(clippy::while_let_loop)]
+fn flood_fill_into<I, FM, S>(img: &I, xy: XY, connectivity: Connectivity, match_color: FM) -> S
+where
+    I: Image,
+    FM: Fn(&XY, &AlphaColor) -> bool,
+    S: PixelSet,
+{
+    let mut pixels = S::new(img.width(), img.height());
+    let mut queued = S::new(img.width(), img.height());
+
+    queued.insert(&xy);
     let mut queue = vec![xy];
 
     loop {
@@ -278,451 +1932,4023 @@ where
             break;
         };
 
-        if pixels.contains(&xy) {
-            continue;
-        }
-
         let color = img.get_pixel(xy.x, xy.y);
 
         if !match_color(&xy, &color) {
             continue;
         }
 
-        pixels.insert(xy.clone());
-
-        if xy.x > 0 {
-            queue.push(XY {
-                x: xy.x - 1,
-                y: xy.y,
-            });
-        }
-
-        if xy.y > 0 {
-            queue.push(XY {
-                x: xy.x,
-                y: xy.y - 1,
-            });
-        }
-
-        if xy.x < img.width() - 1 {
-            queue.push(XY {
-                x: xy.x + 1,
-                y: xy.y,
-            });
+        for neighbour in neighbours(&xy, img.width(), img.height(), connectivity) {
+            if !queued.contains(&neighbour) {
+                queued.insert(&neighbour);
+                queue.push(neighbour);
+            }
         }
 
-        if xy.y < img.height() - 1 {
-            queue.push(XY {
-                x: xy.x,
-                y: xy.y + 1,
-            });
-        }
+        pixels.insert(&xy);
     }
 
     pixels
 }
 
-impl From<Rgb<u8>> for RGB {
-    fn from(value: Rgb<u8>) -> Self {
-        RGB::new(value[0], value[1], value[2])
+// Storage for the pixels found by flood_fill_into.
+trait PixelSet {
+    fn new(width: u32, height: u32) -> Self;
+    fn contains(&self, xy: &XY) -> bool;
+    fn insert(&mut self, xy: &XY);
+}
+
+impl PixelSet for HashSet<XY> {
+    fn new(_width: u32, _height: u32) -> Self {
+        HashSet::new()
+    }
+
+    fn contains(&self, xy: &XY) -> bool {
+        HashSet::contains(self, xy)
+    }
+
+    fn insert(&mut self, xy: &XY) {
+        HashSet::insert(self, xy.clone());
     }
 }
 
-#[derive(PartialEq, Eq, Clone, Debug, Hash)]
-pub struct XY {
-    x: u32,
-    y: u32,
+impl PixelSet for PixelMask {
+    fn new(width: u32, height: u32) -> Self {
+        PixelMask::new(width, height)
+    }
+
+    fn contains(&self, xy: &XY) -> bool {
+        PixelMask::contains(self, xy)
+    }
+
+    fn insert(&mut self, xy: &XY) {
+        PixelMask::insert(self, xy)
+    }
 }
 
-impl XY {
-    pub fn new(x: u32, y: u32) -> Self {
-        XY { x, y }
+// Finds all regions of connected pixels which match. The result is the same as if flood_fill
+// was performed starting from every pixel which doesn't belong to a region yet but the image is
+// split into horizontal strips which are processed in parallel. Regions which cross the
+// boundaries between the strips are merged afterwards.
+pub fn find_regions<I, FM>(img: &I, match_color: FM) -> Vec<Vec<XY>>
+where
+    I: Image + Sync,
+    FM: Fn(&XY, &AlphaColor) -> bool + Sync,
+{
+    if img.width() == 0 || img.height() == 0 {
+        return vec![];
     }
 
-    fn distance(&self, other: &XY) -> f32 {
-        let pow1 = (self.x as f32 - other.x as f32).powi(2);
-        let pow2 = (self.y as f32 - other.y as f32).powi(2);
-        (pow1 + pow2).sqrt()
+    let strip_count = cmp::min(rayon::current_num_threads() as u32, img.height());
+    let strip_height = img.height().div_ceil(strip_count);
+
+    let strips: Vec<Strip> = (0..img.height())
+        .step_by(strip_height as usize)
+        .collect::<Vec<u32>>()
+        .into_par_iter()
+        .map(|top| {
+            let height = cmp::min(strip_height, img.height() - top);
+            Strip::new(img, top, height, &match_color)
+        })
+        .collect();
+
+    // regions are identified by their index in the strip offset by the number of regions in
+    // the preceding strips
+    let mut offsets = Vec::with_capacity(strips.len());
+    let mut region_count = 0;
+    for strip in &strips {
+        offsets.push(region_count);
+        region_count += strip.regions.len();
     }
 
-    pub fn x(&self) -> u32 {
-        self.x
+    let mut parents: Vec<usize> = (0..region_count).collect();
+    for i in 1..strips.len() {
+        let above = &strips[i - 1];
+        let below = &strips[i];
+
+        for x in 0..img.width() {
+            let above_label = above.label(x, above.top + above.height - 1);
+            let below_label = below.label(x, below.top);
+
+            if let (Some(a), Some(b)) = (above_label, below_label) {
+                let a = find_root(&mut parents, offsets[i - 1] + a);
+                let b = find_root(&mut parents, offsets[i] + b);
+                if a != b {
+                    parents[b] = a;
+                }
+            }
+        }
     }
 
-    pub fn y(&self) -> u32 {
-        self.y
+    let mut merged: HashMap<usize, Vec<XY>> = HashMap::new();
+    for (i, strip) in strips.into_iter().enumerate() {
+        for (j, pixels) in strip.regions.into_iter().enumerate() {
+            let root = find_root(&mut parents, offsets[i] + j);
+            merged.entry(root).or_default().extend(pixels);
+        }
+    }
+
+    let mut regions: Vec<(usize, Vec<XY>)> = merged.into_iter().collect();
+    regions.sort_by_key(|v| v.0);
+    regions.into_iter().map(|v| v.1).collect()
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+
+    let mut i = i;
+    while parents[i] != root {
+        let next = parents[i];
+        parents[i] = root;
+        i = next;
+    }
+
+    root
+}
+
+// Regions of connected pixels found in a horizontal strip of an image.
+struct Strip {
+    top: u32,
+    height: u32,
+    width: u32,
+    labels: Vec<Option<usize>>,
+    regions: Vec<Vec<XY>>,
+}
+
+impl Strip {
+    fn new<I, FM>(img: &I, top: u32, height: u32, match_color: &FM) -> Self
+    where
+        I: Image,
+        FM: Fn(&XY, &AlphaColor) -> bool,
+    {
+        let mut strip = Strip {
+            top,
+            height,
+            width: img.width(),
+            labels: vec![None; img.width() as usize * height as usize],
+            regions: vec![],
+        };
+
+        let matches = |xy: &XY| {
+            img.try_get_pixel(xy.x, xy.y)
+                .is_some_and(|color| match_color(xy, &color))
+        };
+
+        for y in top..(top + height) {
+            for x in 0..img.width() {
+                let xy = XY { x, y };
+                if strip.label(x, y).is_some() || !matches(&xy) {
+                    continue;
+                }
+
+                let label = strip.regions.len();
+                let mut pixels = vec![];
+
+                strip.set_label(&xy, label);
+                let mut queue = vec![xy];
+
+                while let Some(xy) = queue.pop() {
+                    for neighbour in neighbours(&xy, img.width(), img.height(), Connectivity::Four)
+                    {
+                        if neighbour.y < top || neighbour.y >= top + height {
+                            continue;
+                        }
+
+                        if strip.label(neighbour.x, neighbour.y).is_none() && matches(&neighbour) {
+                            strip.set_label(&neighbour, label);
+                            queue.push(neighbour);
+                        }
+                    }
+
+                    pixels.push(xy);
+                }
+
+                strip.regions.push(pixels);
+            }
+        }
+
+        strip
+    }
+
+    fn label(&self, x: u32, y: u32) -> Option<usize> {
+        self.labels[self.index(x, y)]
+    }
+
+    fn set_label(&mut self, xy: &XY, label: usize) {
+        let index = self.index(xy.x, xy.y);
+        self.labels[index] = Some(label);
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y - self.top) as usize * self.width as usize + x as usize
+    }
+}
+
+// Set of pixels of an image stored as one flag per pixel.
+pub struct PixelMask {
+    width: u32,
+    height: u32,
+    pixels: Vec<bool>,
+}
+
+impl PixelMask {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![false; width as usize * height as usize],
+        }
+    }
+
+    pub fn insert(&mut self, xy: &XY) {
+        let index = self.index(xy);
+        self.pixels[index] = true;
+    }
+
+    pub fn contains(&self, xy: &XY) -> bool {
+        self.pixels[self.index(xy)]
+    }
+
+    pub fn len(&self) -> usize {
+        self.pixels.iter().filter(|v| **v).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pixels.iter().all(|v| !*v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = XY> + '_ {
+        self.pixels
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| **v)
+            .map(|(i, _)| XY {
+                x: (i % self.width as usize) as u32,
+                y: (i / self.width as usize) as u32,
+            })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, xy: &XY) -> usize {
+        xy.y as usize * self.width as usize + xy.x as usize
+    }
+}
+
+impl From<Rgb<u8>> for RGB {
+    fn from(value: Rgb<u8>) -> Self {
+        RGB::new(value[0], value[1], value[2])
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Hash)]
+pub struct XY {
+    x: u32,
+    y: u32,
+}
+
+impl XY {
+    pub fn new(x: u32, y: u32) -> Self {
+        XY { x, y }
+    }
+
+    fn distance(&self, other: &XY) -> f32 {
+        let pow1 = (self.x as f32 - other.x as f32).powi(2);
+        let pow2 = (self.y as f32 - other.y as f32).powi(2);
+        (pow1 + pow2).sqrt()
+    }
+
+    pub fn x(&self) -> u32 {
+        self.x
+    }
+
+    pub fn y(&self) -> u32 {
+        self.y
+    }
+}
+
+// Pixel coordinates with sub-pixel precision.
+#[derive(PartialEq, Clone, Debug)]
+pub struct PreciseXY {
+    x: f32,
+    y: f32,
+}
+
+impl PreciseXY {
+    pub fn new(x: f32, y: f32) -> Self {
+        PreciseXY { x, y }
+    }
+
+    pub fn x(&self) -> f32 {
+        self.x
+    }
+
+    pub fn y(&self) -> f32 {
+        self.y
+    }
+
+    fn distance(&self, other: &PreciseXY) -> f32 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+    }
+
+    fn scale(&self, factor: f32) -> PreciseXY {
+        PreciseXY {
+            x: self.x * factor,
+            y: self.y * factor,
+        }
+    }
+
+    fn rotate_clockwise(&self, img_height: u32) -> PreciseXY {
+        PreciseXY {
+            x: (img_height - 1) as f32 - self.y,
+            y: self.x,
+        }
+    }
+
+    pub fn to_xy(&self) -> XY {
+        XY {
+            x: self.x.round() as u32,
+            y: self.y.round() as u32,
+        }
+    }
+}
+
+impl From<&XY> for PreciseXY {
+    fn from(value: &XY) -> Self {
+        PreciseXY {
+            x: value.x as f32,
+            y: value.y as f32,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Marker {
+    area: Area,
+    center: PreciseXY,
+    estimated: bool,
+    clipped: bool,
+}
+
+impl Marker {
+    // Recreates a marker e.g. one which was previously found and saved.
+    pub fn new(area: Area, center: PreciseXY, estimated: bool) -> Marker {
+        Marker {
+            area,
+            center,
+            estimated,
+            clipped: false,
+        }
+    }
+
+    fn new_from_pixels<I: Image>(img: &I, pixels: &HashSet<XY>) -> Option<Marker> {
+        // The coordinates are summed as integers as the sum of floats would depend on the
+        // order in which the pixels are iterated over.
+        let count = pixels.len() as f64;
+        let centroid = PreciseXY {
+            x: (pixels.iter().map(|v| v.x as u64).sum::<u64>() as f64 / count) as f32,
+            y: (pixels.iter().map(|v| v.y as u64).sum::<u64>() as f64 / count) as f32,
+        };
+
+        let area = Area::new_from_pixels(pixels)?;
+
+        // The centroid of a region which is only one pixel wide or tall doesn't tell us
+        // anything that the bounding box doesn't.
+        let mut center = if area.width > 1 && area.height > 1 {
+            centroid
+        } else {
+            area.center()
+        };
+
+        let clipped_left = area.left == 0;
+        let clipped_right = area.right() >= img.width() - 1;
+        let clipped_top = area.top == 0;
+        let clipped_bottom = area.bottom() >= img.height() - 1;
+        let clipped_horizontally = clipped_left || clipped_right;
+        let clipped_vertically = clipped_top || clipped_bottom;
+
+        // A marker cut off by the edge of the image has its center shifted inwards. Markers are
+        // assumed to be square so if only one dimension was cut off the other one tells us where
+        // the missing edge should be. If both were cut off there is nothing to go by.
+        if clipped_horizontally
+            && !clipped_vertically
+            && !(clipped_left && clipped_right)
+            && area.height > area.width
+        {
+            let half = (area.height - 1) as f32 / 2.0;
+            center.x = if clipped_left {
+                area.right() as f32 - half
+            } else {
+                area.left as f32 + half
+            };
+        }
+
+        if clipped_vertically
+            && !clipped_horizontally
+            && !(clipped_top && clipped_bottom)
+            && area.width > area.height
+        {
+            let half = (area.width - 1) as f32 / 2.0;
+            center.y = if clipped_top {
+                area.bottom() as f32 - half
+            } else {
+                area.top as f32 + half
+            };
+        }
+
+        Some(Marker {
+            area,
+            center,
+            estimated: false,
+            clipped: clipped_horizontally || clipped_vertically,
+        })
+    }
+
+    // Creates a marker which wasn't found in the image but whose position was derived from
+    // other markers. It is assumed to be the same size as the given marker.
+    fn new_estimated(center: PreciseXY, like: &Marker) -> Marker {
+        let area = Area {
+            top: (center.y - (like.area.height / 2) as f32).max(0.0) as u32,
+            left: (center.x - (like.area.width / 2) as f32).max(0.0) as u32,
+            width: like.area.width,
+            height: like.area.height,
+        };
+
+        Marker {
+            area,
+            center,
+            estimated: true,
+            clipped: false,
+        }
+    }
+
+    // Estimated markers weren't found in the image.
+    pub fn is_estimated(&self) -> bool {
+        self.estimated
+    }
+
+    // Clipped markers touch the edge of the image so their centers are less reliable.
+    pub fn is_clipped(&self) -> bool {
+        self.clipped
+    }
+
+    pub fn area(&self) -> &Area {
+        &self.area
+    }
+
+    fn scale(&self, factor: f32) -> Marker {
+        Marker {
+            area: self.area.scale(factor),
+            center: self.center.scale(factor),
+            estimated: self.estimated,
+            clipped: self.clipped,
+        }
+    }
+
+    fn rotate_clockwise(&self, img_height: u32) -> Marker {
+        Marker {
+            area: self.area.rotate_clockwise(img_height),
+            center: self.center.rotate_clockwise(img_height),
+            estimated: self.estimated,
+            clipped: self.clipped,
+        }
+    }
+
+    // Centroid of the marker pixels which unlike the center of the bounding box isn't biased
+    // for markers which aren't squares.
+    pub fn center(&self) -> &PreciseXY {
+        &self.center
+    }
+}
+
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone)]
+pub struct Area {
+    top: u32,
+    left: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Area {
+    pub fn new(left: u32, top: u32, width: u32, height: u32) -> Area {
+        Area {
+            top,
+            left,
+            width,
+            height,
+        }
+    }
+
+    fn new_from_pixels(pixels: &HashSet<XY>) -> Option<Area> {
+        if pixels.is_empty() {
+            return None;
+        }
+
+        let top = pixels.iter().map(|v| v.y).min().unwrap();
+        let bottom = pixels.iter().map(|v| v.y).max().unwrap();
+        let left = pixels.iter().map(|v| v.x).min().unwrap();
+        let right = pixels.iter().map(|v| v.x).max().unwrap();
+
+        Some(Area {
+            top,
+            left,
+            width: right - left + 1,
+            height: bottom - top + 1,
+        })
+    }
+
+    // The exact center of an area with an even width or height lies between two pixels, it
+    // isn't rounded so that it's not biased towards either of them.
+    pub fn center(&self) -> PreciseXY {
+        PreciseXY {
+            x: self.left as f32 + (self.width as f32 - 1.0) / 2.0,
+            y: self.top as f32 + (self.height as f32 - 1.0) / 2.0,
+        }
+    }
+
+    // Converts the area to the coordinates of an image resized by the given factor e.g. from a
+    // downscaled copy used for detection back to the full resolution. The position is rounded
+    // down and the size to the nearest pixel, but it's never smaller than a single pixel.
+    pub fn scale(&self, factor: f32) -> Area {
+        Area {
+            top: (self.top as f32 * factor) as u32,
+            left: (self.left as f32 * factor) as u32,
+            width: cmp::max(1, (self.width as f32 * factor).round() as u32),
+            height: cmp::max(1, (self.height as f32 * factor).round() as u32),
+        }
+    }
+
+    // Moves the area by the given offsets e.g. from the coordinates of a region of interest to
+    // the coordinates of the entire photo. Returns None if the area would end up outside of the
+    // range of the coordinates.
+    pub fn translate(&self, dx: i64, dy: i64) -> Option<Area> {
+        let left = u32::try_from(self.left as i64 + dx).ok()?;
+        let top = u32::try_from(self.top as i64 + dy).ok()?;
+        left.checked_add(self.width)?;
+        top.checked_add(self.height)?;
+
+        Some(Area {
+            top,
+            left,
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    fn rotate_clockwise(&self, img_height: u32) -> Area {
+        Area {
+            top: self.left,
+            left: img_height - 1 - self.bottom(),
+            width: self.height,
+            height: self.width,
+        }
+    }
+
+    pub fn contains(&self, xy: &XY) -> bool {
+        xy.x >= self.left && xy.x <= self.right() && xy.y >= self.top && xy.y <= self.bottom()
+    }
+
+    // Iterates over all pixels of the area column by column.
+    pub fn pixels(&self) -> impl Iterator<Item = XY> + use<> {
+        let (top, bottom) = (self.top, self.top + self.height);
+        (self.left..self.left + self.width)
+            .flat_map(move |x| (top..bottom).map(move |y| XY { x, y }))
+    }
+
+    // Paints every pixel of the area including its rightmost column and bottom row.
+    pub fn color<I: Image>(&self, img: &mut I, color: &Color) {
+        let color = AlphaColor::new_opaque(color.clone());
+
+        for xy in self.pixels() {
+            img.put_pixel(xy.x, xy.y, &color);
+        }
+    }
+
+    // Returns the width and height of the area in millimeters given the size of the
+    // perspective corrected image in pixels and the physical size of the sheet.
+    pub fn physical_size(
+        &self,
+        image_width: u32,
+        image_height: u32,
+        sheet: &SheetSize,
+    ) -> (f32, f32) {
+        let width = self.width as f32 * sheet.width_mm / image_width as f32;
+        let height = self.height as f32 * sheet.height_mm / image_height as f32;
+        (width, height)
+    }
+
+    // Blends the given color over the pixels in this area taking its alpha into account e.g. to
+    // highlight the area in a preview without hiding what is underneath.
+    pub fn color_alpha<I: Image>(&self, img: &mut I, color: &AlphaColor) {
+        for xy in self.pixels() {
+            let pixel = img.get_pixel(xy.x, xy.y);
+            img.put_pixel(xy.x, xy.y, &color.over(&pixel));
+        }
+    }
+
+    // Averages the colors of the pixels in this area in the LAB color space weighting them by
+    // their alpha, see AlphaColor::average_lab. Returns None if all pixels are transparent.
+    pub fn average_lab_color<I: Image>(&self, img: &I) -> Option<Color> {
+        let pixels: Vec<AlphaColor> = self.pixels().map(|v| img.get_pixel(v.x, v.y)).collect();
+        AlphaColor::average_lab(&pixels).map(|v| v.into())
+    }
+
+    // Averages the colors of the pixels in this area which aren't fully transparent so that
+    // e.g. the transparent margins of a sticker don't make its color darker. Returns None if
+    // all pixels are transparent.
+    pub fn average_opaque_color<I: Image>(&self, img: &I) -> Option<Color> {
+        let mut r: u64 = 0;
+        let mut g: u64 = 0;
+        let mut b: u64 = 0;
+        let mut count: u64 = 0;
+
+        for xy in self.pixels() {
+            let pixel = img.get_pixel(xy.x, xy.y);
+            if pixel.is_transparent() {
+                continue;
+            }
+
+            let rgb = pixel.color().rgb();
+            r += rgb.r() as u64;
+            g += rgb.g() as u64;
+            b += rgb.b() as u64;
+            count += 1;
+        }
+
+        if count == 0 {
+            return None;
+        }
+
+        let rgb = RGB::new(
+            (r as f32 / count as f32).round() as u8,
+            (g as f32 / count as f32).round() as u8,
+            (b as f32 / count as f32).round() as u8,
+        );
+        Some(rgb.into())
+    }
+
+    // Returns the mean and the variance of the luminance of the pixels in the area, the
+    // luminance is in [0, 1].
+    fn luminance_statistics<I: Image>(&self, img: &I) -> (f32, f32) {
+        let luminances: Vec<f32> = self
+            .pixels()
+            .map(|v| img.get_pixel(v.x, v.y).color().yuv().y())
+            .collect();
+
+        let count = luminances.len() as f32;
+        let mean = luminances.iter().sum::<f32>() / count;
+        let variance = luminances.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / count;
+        (mean, variance)
+    }
+
+    fn average_color<I: Image>(&self, img: &I) -> Result<Color> {
+        //let mut y: Option<f32> = None;
+        //let mut u: Option<f32> = None;
+        //let mut v: Option<f32> = None;
+
+        let mut r: Option<f32> = None;
+        let mut g: Option<f32> = None;
+        let mut b: Option<f32> = None;
+
+        for xy in self.pixels() {
+            let pixel = img.get_pixel(xy.x, xy.y);
+            let rgb: RGB = pixel.color().rgb();
+            //let color: Color = rgb.into();
+            //let yuv: YUV = color.yuv();
+
+            //y = match y {
+            //    Some(y) => Some((y + yuv.y()) / 2.0),
+            //    None => Some(yuv.y()),
+            //};
+
+            //u = match u {
+            //    Some(u) => Some((u + yuv.u()) / 2.0),
+            //    None => Some(yuv.u()),
+            //};
+
+            //v = match v {
+            //    Some(v) => Some((v + yuv.v()) / 2.0),
+            //    None => Some(yuv.v()),
+            //};
+
+            r = match r {
+                Some(r) => Some((r + rgb.r() as f32) / 2.0),
+                None => Some(rgb.r() as f32),
+            };
+
+            g = match g {
+                Some(g) => Some((g + rgb.g() as f32) / 2.0),
+                None => Some(rgb.g() as f32),
+            };
+
+            b = match b {
+                Some(b) => Some((b + rgb.b() as f32) / 2.0),
+                None => Some(rgb.b() as f32),
+            };
+        }
+
+        //Ok(YUV::new(y.unwrap(), u.unwrap(), v.unwrap())?.into())
+        Ok(RGB::new(r.unwrap() as u8, g.unwrap() as u8, b.unwrap() as u8).into())
+    }
+
+    pub fn top(&self) -> u32 {
+        self.top
+    }
+
+    pub fn left(&self) -> u32 {
+        self.left
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn right(&self) -> u32 {
+        self.left + self.width - 1
+    }
+
+    fn bottom(&self) -> u32 {
+        self.top + self.height - 1
+    }
+
+    pub fn area(&self) -> u32 {
+        self.width * self.height
+    }
+}
+
+// Fits a line v = a + b * t to the given (t, v) points using least squares and returns the
+// differences between the values and the line. Two points always lie on a line so fewer than
+// three points don't produce any residuals.
+fn line_fit_residuals(points: &[(f32, f32)]) -> Vec<f32> {
+    if points.len() < 3 {
+        return vec![];
+    }
+
+    let count = points.len() as f32;
+    let mean_t = points.iter().map(|v| v.0).sum::<f32>() / count;
+    let mean_v = points.iter().map(|v| v.1).sum::<f32>() / count;
+
+    let covariance: f32 = points.iter().map(|v| (v.0 - mean_t) * (v.1 - mean_v)).sum();
+    let variance: f32 = points.iter().map(|v| (v.0 - mean_t).powi(2)).sum();
+    let slope = if variance > 0.0 {
+        covariance / variance
+    } else {
+        0.0
+    };
+
+    points
+        .iter()
+        .map(|v| v.1 - (mean_v + slope * (v.0 - mean_t)))
+        .collect()
+}
+
+struct EdgeIterator {
+    a: PreciseXY,
+    b: PreciseXY,
+    steps: usize,
+    next_step: usize,
+}
+
+impl EdgeIterator {
+    pub fn new(a: PreciseXY, b: PreciseXY, steps: usize) -> Result<Self> {
+        if steps < 2 {
+            return Err(anyhow!(
+                "requesting fewer than two steps seems a bit nonsensical"
+            ));
+        }
+
+        Ok(Self {
+            a,
+            b,
+            steps,
+            next_step: 0,
+        })
+    }
+}
+
+impl Iterator for EdgeIterator {
+    type Item = (usize, XY);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current_step = self.next_step;
+        if current_step >= self.steps {
+            return None;
+        }
+
+        self.next_step += 1;
+
+        let fraction = current_step as f32 / ((self.steps - 1) as f32);
+        let length_x = self.b.x - self.a.x;
+        let length_y = self.b.y - self.a.y;
+        let x = self.a.x + fraction * length_x;
+        let y = self.a.y + fraction * length_y;
+
+        Some((current_step, PreciseXY { x, y }.to_xy()))
+    }
+}
+
+// Visits every pixel of the line between two points including both of them using Bresenham's
+// algorithm, unlike EdgeIterator which visits a fixed number of evenly spaced points.
+pub struct LineIterator {
+    x: i64,
+    y: i64,
+    end_x: i64,
+    end_y: i64,
+    dx: i64,
+    dy: i64,
+    step_x: i64,
+    step_y: i64,
+    error: i64,
+    done: bool,
+}
+
+impl LineIterator {
+    pub fn new(a: &XY, b: &XY) -> Self {
+        let dx = (b.x as i64 - a.x as i64).abs();
+        let dy = -(b.y as i64 - a.y as i64).abs();
+        Self {
+            x: a.x as i64,
+            y: a.y as i64,
+            end_x: b.x as i64,
+            end_y: b.y as i64,
+            dx,
+            dy,
+            step_x: if a.x < b.x { 1 } else { -1 },
+            step_y: if a.y < b.y { 1 } else { -1 },
+            error: dx + dy,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for LineIterator {
+    type Item = XY;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let xy = XY {
+            x: self.x as u32,
+            y: self.y as u32,
+        };
+
+        if self.x == self.end_x && self.y == self.end_y {
+            self.done = true;
+            return Some(xy);
+        }
+
+        let error = 2 * self.error;
+        if error >= self.dy {
+            self.error += self.dy;
+            self.x += self.step_x;
+        }
+        if error <= self.dx {
+            self.error += self.dx;
+            self.y += self.step_y;
+        }
+
+        Some(xy)
+    }
+}
+
+pub struct IdentifiedSticker {
+    pub area: Area,
+    pub column: usize,
+    pub row: usize,
+
+    // Position of the sticker when reading the sheet from top to bottom and from left to right
+    // starting from 1. Unlike the column and row the indices don't have gaps.
+    pub index: usize,
+}
+
+pub struct IdentifiedStickers {
+    stickers: Vec<IdentifiedSticker>,
+    width: u32,
+    height: u32,
+}
+
+// Stickers placed onto a grid of an expected shape.
+pub struct GridFit {
+    pub stickers: Vec<IdentifiedSticker>,
+
+    // Cells of the grid which don't contain any sticker as (column, row).
+    pub missing_cells: Vec<(usize, usize)>,
+
+    // Root mean square distance of the centers of the stickers from the straight lines fitted
+    // through the centers of the stickers in each column and row, as a fraction of the size of
+    // a cell. The perspective correction keeps the rows and columns of a flat sheet straight
+    // so high values mean that the sheet was bowed. None if no column or row contains at least
+    // three stickers.
+    pub residual_distortion: Option<f32>,
+}
+
+impl IdentifiedStickers {
+    pub fn new<I: Image>(img: &I, config: &ExtractionConfig) -> Self {
+        let mut areas: Vec<Area> = vec![];
+
+        for ix in 0..img.width() {
+            for iy in 0..img.height() {
+                let xy = XY::new(ix, iy);
+
+                let area_with_this_pixel_exists = areas.iter().any(|v| v.contains(&xy));
+                if area_with_this_pixel_exists {
+                    continue;
+                }
+
+                let is_opaque = |xy: &XY| {
+                    img.try_get_pixel(xy.x(), xy.y()).is_some_and(|color| {
+                        !color.is_transparent() && color.alpha() >= config.sticker_alpha_threshold
+                    })
+                };
+
+                if !is_opaque(&xy) {
+                    continue;
+                }
+
+                let pixels = flood_fill_with_connectivity(
+                    img,
+                    xy,
+                    config.sticker_connectivity,
+                    |xy: &XY, _color: &AlphaColor| is_opaque(xy),
+                );
+
+                let parts = if config.split_touching_stickers {
+                    split_touching(img, &pixels, config.sticker_connectivity)
+                } else {
+                    vec![pixels]
+                };
+
+                for part in parts {
+                    if let Some(area) = Area::new_from_pixels(&part) {
+                        areas.push(area);
+                    }
+                }
+            }
+        }
+
+        areas.sort_by_key(|a| a.left());
+
+        let snap_distance = img.width() as f32 * config.snap_stickers_threshold_x;
+
+        let mut stickers_assigned_to_columns = vec![];
+        for area in &areas {
+            if stickers_assigned_to_columns.is_empty() {
+                stickers_assigned_to_columns.push((area.clone(), 0));
+            } else {
+                let existing_column = stickers_assigned_to_columns
+                    .iter()
+                    .find(|v| (v.0.center().x - area.center().x).abs() < snap_distance)
+                    .map(|v| v.1);
+                match existing_column {
+                    Some(column) => {
+                        stickers_assigned_to_columns.push((area.clone(), column));
+                    }
+                    None => {
+                        let highest_column = stickers_assigned_to_columns
+                            .iter()
+                            .map(|v| v.1)
+                            .max()
+                            .unwrap();
+                        stickers_assigned_to_columns.push((area.clone(), highest_column + 1));
+                    }
+                }
+            }
+        }
+
+        areas.sort_by_key(|a| a.top());
+
+        let snap_distance = img.height() as f32 * config.snap_stickers_threshold_y;
+
+        let mut stickers_assigned_to_rows: Vec<(Area, usize)> = vec![];
+        for area in &areas {
+            if stickers_assigned_to_rows.is_empty() {
+                stickers_assigned_to_rows.push((area.clone(), 0));
+            } else {
+                let existing_row = stickers_assigned_to_rows
+                    .iter()
+                    .find(|v| (v.0.center().y - area.center().y).abs() < snap_distance)
+                    .map(|v| v.1);
+                match existing_row {
+                    Some(row) => {
+                        stickers_assigned_to_rows.push((area.clone(), row));
+                    }
+                    None => {
+                        let highest_row =
+                            stickers_assigned_to_rows.iter().map(|v| v.1).max().unwrap();
+                        stickers_assigned_to_rows.push((area.clone(), highest_row + 1));
+                    }
+                }
+            }
+        }
+
+        let mut stickers: Vec<IdentifiedSticker> = stickers_assigned_to_columns
+            .into_iter()
+            .map(|(area, column)| {
+                let row = stickers_assigned_to_rows
+                    .iter()
+                    .find(|v| v.0 == area)
+                    .map(|v| v.1)
+                    .unwrap();
+                IdentifiedSticker {
+                    area,
+                    column,
+                    row,
+                    index: 0,
+                }
+            })
+            .collect();
+
+        stickers.sort_by_key(|v| (v.row, v.column, v.area.left(), v.area.top()));
+        for (i, sticker) in stickers.iter_mut().enumerate() {
+            sticker.index = i + 1;
+        }
+
+        stickers.sort_by_key(|v| (v.column, v.row, v.area.top()));
+
+        Self {
+            stickers,
+            width: img.width(),
+            height: img.height(),
+        }
+    }
+
+    // Assigns each sticker to the cell of an evenly spaced grid covering the entire image which
+    // contains the center of the sticker. The indices are derived from the cells so that empty
+    // cells leave gaps instead of shifting the numbering of the following stickers.
+    pub fn fit_to_grid(&self, columns: usize, rows: usize) -> Result<GridFit> {
+        if columns == 0 || rows == 0 {
+            return Err(anyhow!("grid must have at least one column and row"));
+        }
+
+        if self.stickers.len() > columns * rows {
+            return Err(anyhow!(
+                "found {} stickers which don't fit into a {}x{} grid",
+                self.stickers.len(),
+                columns,
+                rows
+            ));
+        }
+
+        let cell_width = self.width as f32 / columns as f32;
+        let cell_height = self.height as f32 / rows as f32;
+
+        let mut occupied_cells = vec![false; columns * rows];
+        let mut stickers = vec![];
+        for sticker in &self.stickers {
+            let center = sticker.area.center();
+            let column = cmp::min((center.x / cell_width) as usize, columns - 1);
+            let row = cmp::min((center.y / cell_height) as usize, rows - 1);
+
+            let cell = row * columns + column;
+            if occupied_cells[cell] {
+                return Err(anyhow!(
+                    "more than one sticker found in column {} row {} of the grid",
+                    column,
+                    row
+                ));
+            }
+            occupied_cells[cell] = true;
+
+            stickers.push(IdentifiedSticker {
+                area: sticker.area.clone(),
+                column,
+                row,
+                index: cell + 1,
+            });
+        }
+
+        stickers.sort_by_key(|v| (v.column, v.row));
+
+        let residual_distortion =
+            Self::residual_distortion(&stickers, columns, rows, cell_width, cell_height);
+
+        let missing_cells = occupied_cells
+            .iter()
+            .enumerate()
+            .filter(|(_, occupied)| !**occupied)
+            .map(|(cell, _)| (cell % columns, cell / columns))
+            .collect();
+
+        Ok(GridFit {
+            stickers,
+            missing_cells,
+            residual_distortion,
+        })
+    }
+
+    fn residual_distortion(
+        stickers: &[IdentifiedSticker],
+        columns: usize,
+        rows: usize,
+        cell_width: f32,
+        cell_height: f32,
+    ) -> Option<f32> {
+        let center = |sticker: &IdentifiedSticker| {
+            let area = &sticker.area;
+            PreciseXY::new(
+                area.left as f32 + area.width as f32 / 2.0,
+                area.top as f32 + area.height as f32 / 2.0,
+            )
+        };
+
+        let mut residuals = vec![];
+        for column in 0..columns {
+            let points: Vec<(f32, f32)> = stickers
+                .iter()
+                .filter(|v| v.column == column)
+                .map(center)
+                .map(|v| (v.y, v.x))
+                .collect();
+            residuals.extend(line_fit_residuals(&points).iter().map(|v| v / cell_width));
+        }
+
+        for row in 0..rows {
+            let points: Vec<(f32, f32)> = stickers
+                .iter()
+                .filter(|v| v.row == row)
+                .map(center)
+                .map(|v| (v.x, v.y))
+                .collect();
+            residuals.extend(line_fit_residuals(&points).iter().map(|v| v / cell_height));
+        }
+
+        if residuals.is_empty() {
+            return None;
+        }
+
+        Some((residuals.iter().map(|v| v.powi(2)).sum::<f32>() / residuals.len() as f32).sqrt())
+    }
+
+    pub fn stickers(&self) -> &[IdentifiedSticker] {
+        &self.stickers
+    }
+
+    // Returns a copy of the image with the bounding box of each sticker drawn onto it, the color
+    // of the box depends on the column. The grid position is marked with column + 1 squares along
+    // the top edge and row + 1 squares along the left edge of the box.
+    pub fn render_overlay(&self, img: &RgbaImage) -> RgbaImage {
+        let mut overlay = img.clone();
+        let palette = [
+            RGB::new(255, 0, 0),
+            RGB::new(0, 200, 0),
+            RGB::new(0, 0, 255),
+            RGB::new(255, 0, 255),
+            RGB::new(0, 200, 200),
+            RGB::new(255, 150, 0),
+        ];
+
+        for sticker in &self.stickers {
+            let color: Color = palette[sticker.column % palette.len()].clone().into();
+            let area = &sticker.area;
+            let border = cmp::min(OVERLAY_BORDER_WIDTH, cmp::min(area.width, area.height));
+
+            let edges = [
+                Area {
+                    top: area.top,
+                    left: area.left,
+                    width: area.width,
+                    height: border,
+                },
+                Area {
+                    top: area.bottom() + 1 - border,
+                    left: area.left,
+                    width: area.width,
+                    height: border,
+                },
+                Area {
+                    top: area.top,
+                    left: area.left,
+                    width: border,
+                    height: area.height,
+                },
+                Area {
+                    top: area.top,
+                    left: area.right() + 1 - border,
+                    width: border,
+                    height: area.height,
+                },
+            ];
+
+            for edge in &edges {
+                edge.color(&mut overlay, &color);
+            }
+
+            let label_size = OVERLAY_BORDER_WIDTH * 2;
+            for i in 0..=sticker.column as u32 {
+                let left = area.left + border + i * label_size * 2;
+                if left + label_size > area.right() {
+                    break;
+                }
+
+                let label = Area {
+                    top: area.top + border,
+                    left,
+                    width: label_size,
+                    height: cmp::min(label_size, area.height - border),
+                };
+                label.color(&mut overlay, &color);
+            }
+
+            for i in 0..=sticker.row as u32 {
+                let top = area.top + border + i * label_size * 2;
+                if top + label_size > area.bottom() {
+                    break;
+                }
+
+                let label = Area {
+                    top,
+                    left: area.left + border,
+                    width: cmp::min(label_size, area.width - border),
+                    height: label_size,
+                };
+                label.color(&mut overlay, &color);
+            }
+        }
+
+        overlay
+    }
+}
+
+pub struct NormalisedBackgroundDifference {
+    pub diff_l: f32, // [-1, 1]
+    pub diff_a: f32, // [-1, 1]
+    pub diff_b: f32, // [-1, 1]
+}
+
+pub struct BackgroundDifference {
+    area: Area,
+    distances: Vec<Vec<NormalisedBackgroundDifference>>,
+}
+
+impl BackgroundDifference {
+    // Returns Cancelled if the token is set while the differences are calculated.
+    pub fn new<I: Image>(
+        img: &I,
+        background: &Background,
+        normalisation: &BackgroundNormalisation,
+        cancel: &AtomicBool,
+    ) -> Result<Self> {
+        let row_size = img.width() as usize;
+        let column_size = img.height() as usize;
+
+        let mut scale = DifferenceScale::new(img, normalisation)?;
+        let mut distances = Vec::with_capacity(row_size);
+
+        for xi in 0..img.width() {
+            check_cancelled(cancel)?;
+            let mut column = Vec::with_capacity(column_size);
+
+            for yi in 0..img.height() {
+                let xy = XY::new(xi, yi);
+
+                let background_color: LAB = background.check_color(&xy).lab();
+                let difference = BackgroundDifference::difference(img, &xy, &background_color);
+                scale.update(&xy, &difference);
+                column.push(difference);
+            }
+
+            distances.push(column);
+        }
+
+        for xi in 0..img.width() {
+            for yi in 0..img.height() {
+                let xy = XY::new(xi, yi);
+                BackgroundDifference::normalise(
+                    &mut distances[xi as usize][yi as usize],
+                    scale.get(&xy),
+                );
+            }
+        }
+
+        Ok(Self {
+            area: Area {
+                top: 0,
+                left: 0,
+                width: img.width(),
+                height: img.height(),
+            },
+            distances,
+        })
+    }
+
+    // Calculates the differences for square tiles of the image one at a time so that they
+    // never have to be kept in memory for the entire image. The values used for normalisation
+    // are found in a separate pass over the entire image beforehand therefore the results are
+    // identical to the ones produced by new and the tiles don't need to overlap. An error
+    // returned for a tile stops the processing of the remaining ones.
+    pub fn for_each_tile<I, B, F>(
+        img: &I,
+        background_color: B,
+        tile_size: u32,
+        normalisation: &BackgroundNormalisation,
+        mut f: F,
+    ) -> Result<()>
+    where
+        I: Image,
+        B: Fn(&XY) -> LAB,
+        F: FnMut(&BackgroundDifference) -> Result<()>,
+    {
+        if tile_size == 0 {
+            return Err(anyhow!("tile size must be positive"));
+        }
+
+        let mut tiles = vec![];
+        for top in (0..img.height()).step_by(tile_size as usize) {
+            for left in (0..img.width()).step_by(tile_size as usize) {
+                tiles.push(Area {
+                    top,
+                    left,
+                    width: cmp::min(tile_size, img.width() - left),
+                    height: cmp::min(tile_size, img.height() - top),
+                });
+            }
+        }
+
+        let mut scale = DifferenceScale::new(img, normalisation)?;
+
+        if scale.needs_update() {
+            for tile in &tiles {
+                for xy in tile.pixels() {
+                    let difference =
+                        BackgroundDifference::difference(img, &xy, &background_color(&xy));
+                    scale.update(&xy, &difference);
+                }
+            }
+        }
+
+        for tile in tiles {
+            let mut distances = Vec::with_capacity(tile.width as usize);
+
+            for xi in tile.left..(tile.right() + 1) {
+                let mut column = Vec::with_capacity(tile.height as usize);
+
+                for yi in tile.top..(tile.bottom() + 1) {
+                    let xy = XY::new(xi, yi);
+                    let mut difference =
+                        BackgroundDifference::difference(img, &xy, &background_color(&xy));
+                    BackgroundDifference::normalise(&mut difference, scale.get(&xy));
+                    column.push(difference);
+                }
+
+                distances.push(column);
+            }
+
+            f(&BackgroundDifference {
+                area: tile,
+                distances,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn difference<I: Image>(
+        img: &I,
+        xy: &XY,
+        background_color: &LAB,
+    ) -> NormalisedBackgroundDifference {
+        let alpha_color = img.get_pixel(xy.x(), xy.y());
+        let color = alpha_color.color().lab();
+
+        NormalisedBackgroundDifference {
+            diff_l: color.l() - background_color.l(),
+            diff_a: color.a() - background_color.a(),
+            diff_b: color.b() - background_color.b(),
+        }
+    }
+
+    fn normalise(
+        difference: &mut NormalisedBackgroundDifference,
+        max: &NormalisedBackgroundDifference,
+    ) {
+        difference.diff_l /= max.diff_l;
+        difference.diff_a /= max.diff_a;
+        difference.diff_b /= max.diff_b;
+    }
+
+    // Area of the image covered by the differences.
+    pub fn area(&self) -> &Area {
+        &self.area
+    }
+
+    pub fn get(&self, xy: &XY) -> &NormalisedBackgroundDifference {
+        &self.distances[(xy.x() - self.area.left) as usize][(xy.y() - self.area.top) as usize]
+    }
+
+    // Renders the magnitudes of the differences of the L, a and b channels as the red, green
+    // and blue channels of an image covering the area. The hue shows which channel dominates
+    // the difference and the brightness shows how large it is, pixels similar to the
+    // background are dark.
+    pub fn render_heatmap(&self) -> RgbaImage {
+        let to_u8 = |v: f32| (v.abs().min(1.0) * 255.0) as u8;
+
+        let mut img = RgbaImage::new(self.area.width, self.area.height);
+        for (x, column) in self.distances.iter().enumerate() {
+            for (y, difference) in column.iter().enumerate() {
+                img.put_pixel(
+                    x as u32,
+                    y as u32,
+                    Rgba([
+                        to_u8(difference.diff_l),
+                        to_u8(difference.diff_a),
+                        to_u8(difference.diff_b),
+                        255,
+                    ]),
+                );
+            }
+        }
+        img
+    }
+}
+
+// Values by which the differences from the background are divided, either fixed or the largest
+// differences found in each region of the image.
+struct DifferenceScale {
+    width: u32,
+    height: u32,
+    regions: u32,
+    fixed: bool,
+    maxima: Vec<NormalisedBackgroundDifference>,
+}
+
+impl DifferenceScale {
+    fn new<I: Image>(img: &I, normalisation: &BackgroundNormalisation) -> Result<Self> {
+        let zero = || NormalisedBackgroundDifference {
+            diff_l: 0.0,
+            diff_a: 0.0,
+            diff_b: 0.0,
+        };
+
+        let (regions, fixed, maxima) = match normalisation {
+            BackgroundNormalisation::Global => (1, false, vec![zero()]),
+            BackgroundNormalisation::Regional { regions } => {
+                if *regions == 0 {
+                    return Err(anyhow!("number of normalisation regions must be positive"));
+                }
+                let count = (regions * regions) as usize;
+                (*regions, false, (0..count).map(|_| zero()).collect())
+            }
+            BackgroundNormalisation::Fixed { l, a, b } => {
+                if *l <= 0.0 || *a <= 0.0 || *b <= 0.0 {
+                    return Err(anyhow!("fixed normalisation values must be positive"));
+                }
+                let scale = NormalisedBackgroundDifference {
+                    diff_l: *l,
+                    diff_a: *a,
+                    diff_b: *b,
+                };
+                (1, true, vec![scale])
+            }
+        };
+
+        Ok(Self {
+            width: img.width(),
+            height: img.height(),
+            regions,
+            fixed,
+            maxima,
+        })
+    }
+
+    // Fixed values don't depend on the differences.
+    fn needs_update(&self) -> bool {
+        !self.fixed
+    }
+
+    fn update(&mut self, xy: &XY, difference: &NormalisedBackgroundDifference) {
+        if self.fixed {
+            return;
+        }
+
+        let index = self.index(xy);
+        let max = &mut self.maxima[index];
+
+        if difference.diff_l > max.diff_l {
+            max.diff_l = difference.diff_l;
+        }
+
+        if difference.diff_a > max.diff_a {
+            max.diff_a = difference.diff_a;
+        }
+
+        if difference.diff_b > max.diff_b {
+            max.diff_b = difference.diff_b;
+        }
+    }
+
+    fn get(&self, xy: &XY) -> &NormalisedBackgroundDifference {
+        &self.maxima[self.index(xy)]
+    }
+
+    fn index(&self, xy: &XY) -> usize {
+        let column = xy.x() as u64 * self.regions as u64 / self.width as u64;
+        let row = xy.y() as u64 * self.regions as u64 / self.height as u64;
+        (row * self.regions as u64 + column) as usize
+    }
+}
+
+// Makes the pixels whose hue is close to the hue of the key color transparent. This is much
+// faster than analysing the background and works well for solid saturated backdrops.
+pub fn remove_chroma_key<I: Image>(img: &mut I, chroma_key: &ChromaKey) -> Result<()> {
+    let key = Color::from_hex(&chroma_key.color)?.hsv();
+    let transparent = AlphaColor::new_transparent();
+
+    for x in 0..img.width() {
+        for y in 0..img.height() {
+            let hsv = img.get_pixel(x, y).color().hsv();
+            if hsv.s() >= chroma_key.min_saturation
+                && hsv.hue_distance(&key) <= chroma_key.hue_tolerance
+            {
+                img.put_pixel(x, y, &transparent);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Same as correct_exposure but tries each of the marker colors in order and uses the first one
+// which all markers contain as they may have been found using any of them.
+pub fn correct_exposure_with_colors<I: Image>(
+    img: &mut I,
+    markers: &Markers,
+    marker_colors: &[MarkerColor],
+) -> Result<()> {
+    if marker_colors.is_empty() {
+        return Err(anyhow!("at least one marker color is required"));
+    }
+
+    let mut errors = vec![];
+    for marker_color in marker_colors {
+        match correct_exposure(img, markers, *marker_color) {
+            Ok(()) => return Ok(()),
+            Err(err) => errors.push(format!("{} markers: {err}", marker_color.name())),
+        }
+    }
+
+    Err(anyhow!("{}", errors.join("; ")))
+}
+
+// Evens out the exposure of the image using the brightness of the markers which should be the
+// same. A bilinear surface, a + bx + cy + dxy, is fitted to the luminances of the markers and
+// the image is divided by it in linear light so that the markers end up as bright as the
+// brightest one. Uneven lighting and the falloff between the corners of the sheet are reduced.
+// The image isn't modified if an error is returned.
+pub fn correct_exposure<I: Image>(
+    img: &mut I,
+    markers: &Markers,
+    marker_color: MarkerColor,
+) -> Result<()> {
+    let linear: Vec<f32> = (0..=255u8)
+        .map(|v| RGB::new(v, v, v).to_linear().0)
+        .collect();
+    let luminance = |rgb: &RGB| {
+        0.2126 * linear[rgb.r() as usize]
+            + 0.7152 * linear[rgb.g() as usize]
+            + 0.0722 * linear[rgb.b() as usize]
+    };
+
+    let mut equations = [[0.0; 5]; 4];
+    let mut brightest: f32 = 0.0;
+    for (i, marker) in markers.markers().iter().enumerate() {
+        if marker.is_estimated() {
+            return Err(anyhow!(
+                "correcting the exposure requires all four markers to be present"
+            ));
+        }
+
+        let area = marker.area();
+        let mut sum = 0.0;
+        let mut count = 0;
+        for x in area.left..=area.right() {
+            for y in area.top..=area.bottom() {
+                let color = img.get_pixel(x, y);
+                if is_marker_color(&color, marker_color) {
+                    sum += luminance(&color.color().rgb());
+                    count += 1;
+                }
+            }
+        }
+
+        if count == 0 {
+            return Err(anyhow!("marker doesn't contain any marker colored pixels"));
+        }
+
+        let marker_luminance = sum / count as f32;
+        brightest = brightest.max(marker_luminance);
+
+        let x = marker.center().x as f64;
+        let y = marker.center().y as f64;
+        equations[i] = [1.0, x, y, x * y, marker_luminance as f64];
+    }
+
+    let [a, b, c, d] = solve(equations)?;
+    let surface = |x: f32, y: f32| {
+        let (x, y) = (x as f64, y as f64);
+        (a + b * x + c * y + d * x * y) as f32
+    };
+
+    for x in 0..img.width() {
+        for y in 0..img.height() {
+            // The surface can approach zero or go negative far away from the markers.
+            let factor = brightest / surface(x as f32, y as f32).max(brightest * 0.1);
+
+            let color = img.get_pixel(x, y);
+            let rgb = color.color().rgb();
+            let corrected = RGB::from_linear(
+                linear[rgb.r() as usize] * factor,
+                linear[rgb.g() as usize] * factor,
+                linear[rgb.b() as usize] * factor,
+            );
+            img.put_pixel(x, y, &AlphaColor::new(corrected.into(), color.alpha()));
+        }
+    }
+
+    Ok(())
+}
+
+// Finds the edges of the sheet in a perspective corrected image whose background was already
+// removed. Scanning inward from each edge of the image the rows or columns which are mostly
+// opaque belong to the band outside of the sheet or to the markers, the sheet starts at the
+// first row or column which is mostly transparent. Returns the area covered by the sheet.
+pub fn find_sheet_edges<I: Image>(img: &I) -> Result<Area> {
+    let (width, height) = (img.width(), img.height());
+    let is_outside = |pixels: &mut dyn Iterator<Item = (u32, u32)>, count: u32| {
+        let opaque = pixels
+            .filter(|(x, y)| !img.get_pixel(*x, *y).is_transparent())
+            .count();
+        opaque as f32 >= count as f32 * SHEET_EDGE_MIN_OPAQUE_FRACTION
+    };
+    let is_outside_row = |y: u32| is_outside(&mut (0..width).map(|x| (x, y)), width);
+    let is_outside_column = |x: u32| is_outside(&mut (0..height).map(|y| (x, y)), height);
+
+    let not_found = || anyhow!("edges of the sheet not found");
+    let top = (0..height)
+        .find(|y| !is_outside_row(*y))
+        .ok_or_else(not_found)?;
+    let bottom = (0..height)
+        .rev()
+        .find(|y| !is_outside_row(*y))
+        .ok_or_else(not_found)?;
+    let left = (0..width)
+        .find(|x| !is_outside_column(*x))
+        .ok_or_else(not_found)?;
+    let right = (0..width)
+        .rev()
+        .find(|x| !is_outside_column(*x))
+        .ok_or_else(not_found)?;
+
+    Ok(Area {
+        top,
+        left,
+        width: right - left + 1,
+        height: bottom - top + 1,
+    })
+}
+
+// Picks the size of the square areas whose colors are averaged so that an image of the given
+// size is split into as close to the target number of areas as possible, the areas along the
+// right and bottom edges may be cut off. Using it e.g. for mosaics gives them a similar
+// resolution regardless of the size of the photo.
+pub fn averaged_area_size(width: u32, height: u32, target_areas: u32) -> Result<u32> {
+    if width == 0 || height == 0 {
+        return Err(anyhow!("image can't be empty"));
+    }
+
+    if target_areas == 0 {
+        return Err(anyhow!("target number of areas must be positive"));
+    }
+
+    let area_count = |size: u32| width.div_ceil(size) as u64 * height.div_ceil(size) as u64;
+
+    // The number of areas never grows with the size so the smallest size which doesn't yield
+    // more areas than the target is found first and then compared with the next smaller one.
+    let (mut low, mut high) = (1, cmp::max(width, height));
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if area_count(middle) <= target_areas as u64 {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+
+    let size = if low > 1
+        && area_count(low - 1) - (target_areas as u64) < target_areas as u64 - area_count(low)
+    {
+        low - 1
+    } else {
+        low
+    };
+    Ok(size)
+}
+
+// Measures how sharp the part of the image inside of the quad formed by the markers is using
+// the variance of the Laplacian of pixel brightness. Blurry images have low values.
+pub fn sharpness<I: Image>(img: &I, markers: &Markers) -> f32 {
+    let brightness = |x: u32, y: u32| {
+        let rgb = img.get_pixel(x, y).color().rgb();
+        0.299 * rgb.r() as f32 + 0.587 * rgb.g() as f32 + 0.114 * rgb.b() as f32
+    };
+
+    let (left, top, right, bottom) = markers.quad_bounds();
+    let left = cmp::max(left.max(0.0) as u32, 1);
+    let top = cmp::max(top.max(0.0) as u32, 1);
+    let right = cmp::min(right.max(0.0) as u32, img.width().saturating_sub(2));
+    let bottom = cmp::min(bottom.max(0.0) as u32, img.height().saturating_sub(2));
+
+    let mut sum = 0.0;
+    let mut sum_of_squares = 0.0;
+    let mut count = 0;
+    for x in left..=right {
+        for y in top..=bottom {
+            if !markers.contains(&PreciseXY::new(x as f32, y as f32)) {
+                continue;
+            }
+
+            let laplacian = 4.0 * brightness(x, y)
+                - brightness(x - 1, y)
+                - brightness(x + 1, y)
+                - brightness(x, y - 1)
+                - brightness(x, y + 1);
+            sum += laplacian as f64;
+            sum_of_squares += (laplacian as f64).powi(2);
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        return 0.0;
+    }
+
+    let mean = sum / count as f64;
+    (sum_of_squares / count as f64 - mean.powi(2)) as f32
+}
+
+// Metrics describing how reliable the extraction of a sheet is, each in [0, 1] where 1 is the
+// best.
+#[derive(Clone, Debug)]
+pub struct ConfidenceMetrics {
+    pub markers: f32,
+    pub quad: f32,
+    pub sharpness: f32,
+    pub background: f32,
+}
+
+impl ConfidenceMetrics {
+    // The sharpness is the value returned by the sharpness function for the photo before it was
+    // modified. The residual background is the fraction returned by Background::residual, it's
+    // not known if the background was removed using a chroma key.
+    pub fn new(markers: &Markers, sharpness: f32, residual_background: Option<f32>) -> Self {
+        Self {
+            markers: markers.confidence(),
+            quad: markers.quad_health(),
+            sharpness: sharpness / (sharpness + CONFIDENCE_HALF_SHARPNESS),
+            background: 1.0 - residual_background.unwrap_or(0.0).clamp(0.0, 1.0),
+        }
+    }
+
+    // Weighted average of the metrics, [0, 1].
+    pub fn confidence(&self, weights: &ConfidenceWeights) -> f32 {
+        let weighted = [
+            (self.markers, weights.markers),
+            (self.quad, weights.quad),
+            (self.sharpness, weights.sharpness),
+            (self.background, weights.background),
+        ];
+
+        let total: f32 = weighted.iter().map(|(_, weight)| weight).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+
+        weighted.iter().map(|(v, weight)| v * weight).sum::<f32>() / total
+    }
+}
+
+// Removes shadows cast by the stickers onto the sheet. Starting next to the already transparent
+// background the pixels which are darker than the background but have a similar chromaticity
+// are made transparent.
+pub fn remove_shadows<I, BC>(img: &mut I, background_color: BC)
+where
+    I: Image,
+    BC: Fn(&XY) -> CachedColor,
+{
+    let is_shadow = |xy: &XY, color: &AlphaColor| {
+        if color.is_transparent() {
+            return false;
+        }
+
+        let background = background_color(xy);
+        let darkening = background.lab().l() - color.color().lab().l();
+        if !(0.0..=SHADOW_MAX_DARKENING).contains(&darkening) {
+            return false;
+        }
+
+        let (r, g) = chromaticity(&color.color().rgb());
+        let (background_r, background_g) = chromaticity(&background.rgb());
+        (r - background_r).abs() <= SHADOW_MAX_CHROMATICITY_DIFFERENCE
+            && (g - background_g).abs() <= SHADOW_MAX_CHROMATICITY_DIFFERENCE
+    };
+
+    let mut queue = vec![];
+    for x in 0..img.width() {
+        for y in 0..img.height() {
+            let xy = XY { x, y };
+            if !img.get_pixel(x, y).is_transparent() {
+                continue;
+            }
+
+            for neighbour in neighbours(&xy, img.width(), img.height(), Connectivity::Four) {
+                if !img.get_pixel(neighbour.x, neighbour.y).is_transparent() {
+                    queue.push(neighbour);
+                }
+            }
+        }
+    }
+
+    let mut shadow = PixelMask::new(img.width(), img.height());
+    while let Some(xy) = queue.pop() {
+        if shadow.contains(&xy) || !is_shadow(&xy, &img.get_pixel(xy.x, xy.y)) {
+            continue;
+        }
+
+        shadow.insert(&xy);
+        queue.extend(neighbours(
+            &xy,
+            img.width(),
+            img.height(),
+            Connectivity::Four,
+        ));
+    }
+
+    let transparent = AlphaColor::new_transparent();
+    for xy in shadow.iter() {
+        img.put_pixel(xy.x, xy.y, &transparent);
+    }
+}
+
+// Fills transparent holes of at most max_hole_size pixels which are enclosed by a single sticker
+// with the colors of the original image e.g. where a part of the sticker had the same color as
+// the background. Holes touching the edge of the image or more than one sticker are left alone
+// so that the gaps between stickers stay transparent. Stickers are made of pixels connected
+// according to the given connectivity and holes of pixels connected the other way so that a
+// hole can't leak through a diagonal gap in its sticker. Returns the number of filled pixels.
+pub fn fill_holes<I: Image, O: Image>(
+    img: &mut I,
+    original: &O,
+    max_hole_size: usize,
+    connectivity: Connectivity,
+) -> usize {
+    let (width, height) = (img.width(), img.height());
+    let hole_connectivity = match connectivity {
+        Connectivity::Four => Connectivity::Eight,
+        Connectivity::Eight => Connectivity::Four,
+    };
+
+    // Label of the sticker each opaque pixel belongs to.
+    let index = |xy: &XY| (xy.y * width + xy.x) as usize;
+    let mut stickers: Vec<Option<usize>> = vec![None; (width * height) as usize];
+    let mut label = 0;
+    for x in 0..width {
+        for y in 0..height {
+            let xy = XY { x, y };
+            if stickers[index(&xy)].is_some() || img.get_pixel(x, y).is_transparent() {
+                continue;
+            }
+
+            let pixels = flood_fill_with_connectivity(img, xy, connectivity, |_, color| {
+                !color.is_transparent()
+            });
+            for xy in &pixels {
+                stickers[index(xy)] = Some(label);
+            }
+            label += 1;
+        }
+    }
+
+    let mut visited = PixelMask::new(width, height);
+    let mut filled = 0;
+    for x in 0..width {
+        for y in 0..height {
+            let xy = XY { x, y };
+            if visited.contains(&xy) || !img.get_pixel(x, y).is_transparent() {
+                continue;
+            }
+
+            let hole = flood_fill_with_connectivity(img, xy, hole_connectivity, |_, color| {
+                color.is_transparent()
+            });
+
+            let mut surrounding = HashSet::new();
+            let mut touches_edge = false;
+            for xy in &hole {
+                visited.insert(xy);
+
+                if xy.x == 0 || xy.y == 0 || xy.x == width - 1 || xy.y == height - 1 {
+                    touches_edge = true;
+                }
+
+                for neighbour in neighbours(xy, width, height, hole_connectivity) {
+                    if let Some(sticker) = stickers[index(&neighbour)] {
+                        surrounding.insert(sticker);
+                    }
+                }
+            }
+
+            if touches_edge || surrounding.len() != 1 || hole.len() > max_hole_size {
+                continue;
+            }
+
+            for xy in &hole {
+                img.put_pixel(xy.x, xy.y, &original.get_pixel(xy.x, xy.y));
+            }
+            filled += hole.len();
+        }
+    }
+
+    filled
+}
+
+// Fractions of the red and green channels in the sum of all channels which don't change when
+// the color is only darkened.
+fn chromaticity(rgb: &RGB) -> (f32, f32) {
+    let sum = rgb.r() as f32 + rgb.g() as f32 + rgb.b() as f32;
+    if sum == 0.0 {
+        return (1.0 / 3.0, 1.0 / 3.0);
+    }
+    (rgb.r() as f32 / sum, rgb.g() as f32 / sum)
+}
+
+// Splits a region made of several touching stickers using a watershed of the distances of its
+// pixels from the edge of the region. The thickest parts of the region become the seeds which
+// are then grown into the thinner parts, so stickers connected by a narrow pinch are separated
+// while a region without a pinch is returned as is.
+fn split_touching<I: Image>(
+    img: &I,
+    pixels: &HashSet<XY>,
+    connectivity: Connectivity,
+) -> Vec<HashSet<XY>> {
+    let (width, height) = (img.width(), img.height());
+    let region_neighbours = |xy: &XY| {
+        let mut result = neighbours(xy, width, height, connectivity);
+        result.retain(|v| pixels.contains(v));
+        result
+    };
+
+    // Distances from the edge of the region, the pixels along the edge are one pixel away.
+    let mut distances: HashMap<XY, u32> = HashMap::new();
+    let mut queue = VecDeque::new();
+    for xy in pixels {
+        let inner_neighbours = neighbours(xy, width, height, Connectivity::Four)
+            .iter()
+            .filter(|v| pixels.contains(v))
+            .count();
+        if inner_neighbours < 4 {
+            distances.insert(xy.clone(), 1);
+            queue.push_back(xy.clone());
+        }
+    }
+
+    while let Some(xy) = queue.pop_front() {
+        let distance = distances[&xy];
+        for neighbour in neighbours(&xy, width, height, Connectivity::Four) {
+            if pixels.contains(&neighbour) && !distances.contains_key(&neighbour) {
+                distances.insert(neighbour.clone(), distance + 1);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+
+    let Some(max_distance) = distances.values().max() else {
+        return vec![pixels.clone()];
+    };
+    let seed_distance = (*max_distance as f32 * SPLIT_SEED_FRACTION).ceil() as u32;
+
+    // Seeds are labelled in a fixed order so that the parts are the same on every run.
+    let mut seeds: Vec<&XY> = distances
+        .iter()
+        .filter(|(_, distance)| **distance >= seed_distance)
+        .map(|(xy, _)| xy)
+        .collect();
+    seeds.sort_by_key(|v| (v.y, v.x));
+
+    let mut labels: HashMap<XY, usize> = HashMap::new();
+    let mut label_count = 0;
+    for xy in seeds {
+        if labels.contains_key(xy) {
+            continue;
+        }
+
+        let seed = flood_fill_with_connectivity(img, xy.clone(), connectivity, |xy, _| {
+            distances.get(xy).is_some_and(|v| *v >= seed_distance)
+        });
+        for xy in seed {
+            labels.insert(xy, label_count);
+        }
+        label_count += 1;
+    }
+
+    if label_count < 2 {
+        return vec![pixels.clone()];
+    }
+
+    // Pixels are flooded starting from the ones furthest from the edge so that the parts meet
+    // at the pinches.
+    let mut heap = BinaryHeap::new();
+    for (xy, label) in &labels {
+        for neighbour in region_neighbours(xy) {
+            heap.push((distances[&neighbour], neighbour.x, neighbour.y, *label));
+        }
+    }
+
+    while let Some((_, x, y, label)) = heap.pop() {
+        let xy = XY::new(x, y);
+        if labels.contains_key(&xy) {
+            continue;
+        }
+
+        labels.insert(xy.clone(), label);
+        for neighbour in region_neighbours(&xy) {
+            if !labels.contains_key(&neighbour) {
+                heap.push((distances[&neighbour], neighbour.x, neighbour.y, label));
+            }
+        }
+    }
+
+    let mut parts = vec![HashSet::new(); label_count];
+    for (xy, label) in labels {
+        parts[label].insert(xy);
+    }
+    parts
+}
+
+// Returns the pixels which are connected to the given pixel and lie inside of the image.
+fn neighbours(xy: &XY, width: u32, height: u32, connectivity: Connectivity) -> Vec<XY> {
+    let mut neighbours = vec![];
+
+    if xy.x > 0 {
+        neighbours.push(XY {
+            x: xy.x - 1,
+            y: xy.y,
+        });
+    }
+
+    if xy.y > 0 {
+        neighbours.push(XY {
+            x: xy.x,
+            y: xy.y - 1,
+        });
+    }
+
+    if xy.x < width - 1 {
+        neighbours.push(XY {
+            x: xy.x + 1,
+            y: xy.y,
+        });
+    }
+
+    if xy.y < height - 1 {
+        neighbours.push(XY {
+            x: xy.x,
+            y: xy.y + 1,
+        });
+    }
+
+    if connectivity == Connectivity::Eight {
+        neighbours.extend(diagonal_neighbours(xy, width, height));
+    }
+
+    neighbours
+}
+
+fn diagonal_neighbours(xy: &XY, width: u32, height: u32) -> Vec<XY> {
+    let mut neighbours = vec![];
+
+    for (dx, dy) in [(-1, -1), (1, -1), (-1, 1), (1, 1)] {
+        let x = xy.x as i64 + dx;
+        let y = xy.y as i64 + dy;
+        if x >= 0 && y >= 0 && x < width as i64 && y < height as i64 {
+            neighbours.push(XY {
+                x: x as u32,
+                y: y as u32,
+            });
+        }
+    }
+
+    neighbours
+}
+
+// Returns the most common color of the pixels which aren't transparent. Similar colors are
+// grouped together and the average color of the largest group is returned.
+pub fn dominant_color<I: Image>(img: &I) -> Option<Color> {
+    let mut groups: HashMap<(u8, u8, u8), ColorGroup> = HashMap::new();
+
+    for x in 0..img.width() {
+        for y in 0..img.height() {
+            let color = img.get_pixel(x, y);
+            if color.is_transparent() {
+                continue;
+            }
+
+            let rgb = color.color().rgb();
+            let key = (
+                rgb.r() >> DOMINANT_COLOR_SHIFT,
+                rgb.g() >> DOMINANT_COLOR_SHIFT,
+                rgb.b() >> DOMINANT_COLOR_SHIFT,
+            );
+
+            let group = groups.entry(key).or_default();
+            group.count += 1;
+            group.r += rgb.r() as u32;
+            group.g += rgb.g() as u32;
+            group.b += rgb.b() as u32;
+        }
+    }
+
+    let group = groups
+        .into_values()
+        .max_by_key(|v| (v.count, v.r, v.g, v.b))?;
+
+    Some(
+        RGB::new(
+            (group.r / group.count) as u8,
+            (group.g / group.count) as u8,
+            (group.b / group.count) as u8,
+        )
+        .into(),
+    )
+}
+
+#[derive(Default)]
+struct ColorGroup {
+    count: u32,
+    r: u32,
+    g: u32,
+    b: u32,
+}
+
+// Rectangle rotated around its center by the angle given in radians, positive angles rotate
+// clockwise in image coordinates.
+#[derive(Clone, Debug)]
+pub struct RotatedRect {
+    pub center: PreciseXY,
+    pub width: f32,
+    pub height: f32,
+    pub angle: f32,
+}
+
+// Returns the smallest rotated rectangle which contains all opaque pixels of the image. Out of
+// the equivalent rectangles the one rotated by at most 45 degrees is returned so that the
+// content stays upright.
+pub fn min_area_rect<I: Image>(img: &I) -> Option<RotatedRect> {
+    // Only the leftmost and the rightmost pixels of each row can be a part of the convex hull.
+    let mut points = vec![];
+    for y in 0..img.height() {
+        let mut opaque = (0..img.width()).filter(|x| !img.get_pixel(*x, y).is_transparent());
+        if let Some(left) = opaque.next() {
+            let right = opaque.last().unwrap_or(left);
+            for x in [left, right + 1] {
+                points.push(PreciseXY::new(x as f32, y as f32));
+                points.push(PreciseXY::new(x as f32, (y + 1) as f32));
+            }
+        }
+    }
+
+    let hull = convex_hull_of_points(points);
+    if hull.len() < 2 {
+        return hull.first().map(|v| RotatedRect {
+            center: v.clone(),
+            width: 0.0,
+            height: 0.0,
+            angle: 0.0,
+        });
+    }
+
+    let mut best: Option<(f32, RotatedRect)> = None;
+    for i in 0..hull.len() {
+        let a = &hull[i];
+        let b = &hull[(i + 1) % hull.len()];
+        let angle = (b.y - a.y).atan2(b.x - a.x);
+        let (sin, cos) = angle.sin_cos();
+
+        let mut min_u = f32::MAX;
+        let mut max_u = f32::MIN;
+        let mut min_v = f32::MAX;
+        let mut max_v = f32::MIN;
+        for point in &hull {
+            let u = point.x * cos + point.y * sin;
+            let v = -point.x * sin + point.y * cos;
+            min_u = min_u.min(u);
+            max_u = max_u.max(u);
+            min_v = min_v.min(v);
+            max_v = max_v.max(v);
+        }
+
+        let area = (max_u - min_u) * (max_v - min_v);
+        if best.as_ref().is_some_and(|v| v.0 <= area) {
+            continue;
+        }
+
+        let center_u = (min_u + max_u) / 2.0;
+        let center_v = (min_v + max_v) / 2.0;
+        best = Some((
+            area,
+            RotatedRect {
+                center: PreciseXY::new(
+                    center_u * cos - center_v * sin,
+                    center_u * sin + center_v * cos,
+                ),
+                width: max_u - min_u,
+                height: max_v - min_v,
+                angle,
+            },
+        ));
+    }
+
+    best.map(|(_, mut rect)| {
+        while rect.angle > FRAC_PI_4 {
+            rect.angle -= FRAC_PI_2;
+            (rect.width, rect.height) = (rect.height, rect.width);
+        }
+        while rect.angle <= -FRAC_PI_4 {
+            rect.angle += FRAC_PI_2;
+            (rect.width, rect.height) = (rect.height, rect.width);
+        }
+        rect
+    })
+}
+
+// Returns the smallest rectangle which contains all opaque pixels of the image and whose longer
+// edge is parallel to the principal axis of the pixels, found using the covariance of their
+// coordinates. The width of the rectangle is measured along the principal axis therefore the
+// rectangle is rotated by up to 90 degrees and extracting it makes the principal axis
+// horizontal.
+pub fn principal_axis_rect<I: Image>(img: &I) -> Option<RotatedRect> {
+    let mut pixels = vec![];
+    for x in 0..img.width() {
+        for y in 0..img.height() {
+            if !img.get_pixel(x, y).is_transparent() {
+                pixels.push(PreciseXY::new(x as f32 + 0.5, y as f32 + 0.5));
+            }
+        }
+    }
+
+    if pixels.is_empty() {
+        return None;
+    }
+
+    let count = pixels.len() as f32;
+    let mean_x = pixels.iter().map(|v| v.x).sum::<f32>() / count;
+    let mean_y = pixels.iter().map(|v| v.y).sum::<f32>() / count;
+
+    let mut covariance_xx = 0.0;
+    let mut covariance_yy = 0.0;
+    let mut covariance_xy = 0.0;
+    for pixel in &pixels {
+        let (dx, dy) = (pixel.x - mean_x, pixel.y - mean_y);
+        covariance_xx += dx * dx;
+        covariance_yy += dy * dy;
+        covariance_xy += dx * dy;
+    }
+
+    let angle = 0.5 * (2.0 * covariance_xy).atan2(covariance_xx - covariance_yy);
+    let (sin, cos) = angle.sin_cos();
+
+    // Each pixel is a square so its extent along both axes is the same.
+    let half_extent = (cos.abs() + sin.abs()) / 2.0;
+
+    let mut min_u = f32::MAX;
+    let mut max_u = f32::MIN;
+    let mut min_v = f32::MAX;
+    let mut max_v = f32::MIN;
+    for pixel in &pixels {
+        let u = pixel.x * cos + pixel.y * sin;
+        let v = -pixel.x * sin + pixel.y * cos;
+        min_u = min_u.min(u - half_extent);
+        max_u = max_u.max(u + half_extent);
+        min_v = min_v.min(v - half_extent);
+        max_v = max_v.max(v + half_extent);
+    }
+
+    let center_u = (min_u + max_u) / 2.0;
+    let center_v = (min_v + max_v) / 2.0;
+    Some(RotatedRect {
+        center: PreciseXY::new(
+            center_u * cos - center_v * sin,
+            center_u * sin + center_v * cos,
+        ),
+        width: max_u - min_u,
+        height: max_v - min_v,
+        angle,
+    })
+}
+
+// Traces the outlines of the pixels whose alpha is at least the given threshold along the edges
+// of the pixels. Each outline is a closed polygon whose vertices are corners of pixels, without
+// collinear vertices. Outer outlines run clockwise and the outlines of holes counterclockwise,
+// both as seen on the screen. Pixels touching only diagonally get separate outlines.
+pub fn trace_outlines<I: Image>(img: &I, alpha_threshold: u8) -> Vec<Vec<PreciseXY>> {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let is_inside = |x: i64, y: i64| {
+        x >= 0 && y >= 0 && x < width && y < height && {
+            let color = img.get_pixel(x as u32, y as u32);
+            !color.is_transparent() && color.alpha() >= alpha_threshold
+        }
+    };
+
+    // Edges between the pixels inside and outside, oriented so that the inside is on the right.
+    let mut edges: HashMap<(i64, i64), Vec<(i64, i64)>> = HashMap::new();
+    for y in 0..height {
+        for x in 0..width {
+            if !is_inside(x, y) {
+                continue;
+            }
+
+            for (is_edge, from, to) in [
+                (!is_inside(x, y - 1), (x, y), (x + 1, y)),
+                (!is_inside(x + 1, y), (x + 1, y), (x + 1, y + 1)),
+                (!is_inside(x, y + 1), (x + 1, y + 1), (x, y + 1)),
+                (!is_inside(x - 1, y), (x, y + 1), (x, y)),
+            ] {
+                if is_edge {
+                    edges.entry(from).or_default().push(to);
+                }
+            }
+        }
+    }
+
+    let mut starts: Vec<(i64, i64)> = edges.keys().copied().collect();
+    starts.sort_by_key(|(x, y)| (*y, *x));
+
+    let mut outlines = vec![];
+    for start in starts {
+        while edges.get(&start).is_some_and(|v| !v.is_empty()) {
+            let mut outline = vec![start];
+            let mut direction = (0, 0);
+            let mut current = start;
+            while let Some(next) = edges.get_mut(&current)
+                && !next.is_empty()
+            {
+                // Two outlines meet at a vertex if pixels touch diagonally, turning right
+                // keeps them apart.
+                let i = (0..next.len())
+                    .max_by_key(|i| {
+                        let (dx, dy) = (next[*i].0 - current.0, next[*i].1 - current.1);
+                        direction.0 * dy - direction.1 * dx
+                    })
+                    .unwrap();
+                let next = next.swap_remove(i);
+                direction = (next.0 - current.0, next.1 - current.1);
+                current = next;
+                if current == start {
+                    break;
+                }
+                outline.push(current);
+            }
+
+            outlines.push(remove_collinear_vertices(&outline));
+        }
+    }
+
+    outlines
+}
+
+fn remove_collinear_vertices(outline: &[(i64, i64)]) -> Vec<PreciseXY> {
+    let count = outline.len();
+    (0..count)
+        .filter(|i| {
+            let previous = outline[(i + count - 1) % count];
+            let next = outline[(i + 1) % count];
+            let current = outline[*i];
+            (current.0 - previous.0) * (next.1 - current.1)
+                != (current.1 - previous.1) * (next.0 - current.0)
+        })
+        .map(|i| PreciseXY::new(outline[i].0 as f32, outline[i].1 as f32))
+        .collect()
+}
+
+// Writes the outlines as a single SVG path of the given size, holes are cut out of the shapes
+// surrounding them.
+pub fn outlines_svg(width: u32, height: u32, outlines: &[Vec<PreciseXY>]) -> String {
+    let mut path = String::new();
+    for outline in outlines {
+        for (i, vertex) in outline.iter().enumerate() {
+            let command = if i == 0 { "M" } else { "L" };
+            path.push_str(&format!("{}{} {} ", command, vertex.x, vertex.y));
+        }
+        path.push_str("Z ");
+    }
+
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" viewBox=\"0 0 {width} {height}\">\n<path d=\"{}\" fill=\"black\" fill-rule=\"evenodd\"/>\n</svg>\n",
+        path.trim_end()
+    )
+}
+
+// Returns the convex hull of the given pixels e.g. of a region returned by flood_fill in the
+// same order as convex_hull_of_points. Pixels are treated as points so the hull of a region
+// passes through the centers of its outermost pixels.
+pub fn convex_hull(pixels: &HashSet<XY>) -> Vec<XY> {
+    let points = pixels
+        .iter()
+        .map(|v| PreciseXY::new(v.x as f32, v.y as f32))
+        .collect();
+
+    convex_hull_of_points(points)
+        .iter()
+        .map(|v| XY::new(v.x as u32, v.y as u32))
+        .collect()
+}
+
+// Monotone chain algorithm, returns the hull in counterclockwise order without collinear
+// points.
+fn convex_hull_of_points(mut points: Vec<PreciseXY>) -> Vec<PreciseXY> {
+    points.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+
+    let cross = |o: &PreciseXY, a: &PreciseXY, b: &PreciseXY| {
+        (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x)
+    };
+
+    let mut hull: Vec<PreciseXY> = vec![];
+    for pass in [points.clone(), points.into_iter().rev().collect()] {
+        let start = hull.len();
+        for point in pass {
+            while hull.len() >= start + 2
+                && cross(&hull[hull.len() - 2], &hull[hull.len() - 1], &point) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(point);
+        }
+        hull.pop();
+    }
+
+    hull
+}
+
+// Locates the markers of a single sheet without extracting anything and returns their centers
+// in the following order: top left, top right, bottom left, bottom right. The number and colors
+// of the markers, the scan steps and the highlight ceiling are taken from the config.
+pub fn find_markers(img: &RgbaImage, config: &ExtractionConfig) -> Result<[XY; 4]> {
+    let markers = Markers::find_with_colors_and_order(
+        img,
+        &config.marker_colors,
+        config.marker_count,
+        config.marker_scan_steps,
+        &config.corner_scan_steps,
+        config.highlight_ceiling,
+        config.marker_order,
+    )?;
+
+    Ok([
+        markers.top_left().center().to_xy(),
+        markers.top_right().center().to_xy(),
+        markers.bottom_left().center().to_xy(),
+        markers.bottom_right().center().to_xy(),
+    ])
+}
+
+pub fn is_at_least_this_much_of_image<I: Image>(pixels: usize, img: &I, threshold: f32) -> bool {
+    (pixels as f32) >= ((img.width() * img.height()) as f32 * threshold)
+}
+
+pub trait Image {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn get_pixel(&self, x: u32, y: u32) -> AlphaColor;
+
+    // Returns None instead of panicking if the coordinates are outside of the image.
+    fn try_get_pixel(&self, x: u32, y: u32) -> Option<AlphaColor> {
+        if x < self.width() && y < self.height() {
+            Some(self.get_pixel(x, y))
+        } else {
+            None
+        }
+    }
+
+    fn put_pixel(&mut self, x: u32, y: u32, color: &AlphaColor);
+    fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> Self;
+}
+
+impl Image for RgbaImage {
+    fn width(&self) -> u32 {
+        self.width()
+    }
+
+    fn height(&self) -> u32 {
+        self.height()
+    }
+
+    fn get_pixel(&self, x: u32, y: u32) -> AlphaColor {
+        let channels = self.get_pixel(x, y).channels();
+        AlphaColor::new(
+            RGB::new(channels[0], channels[1], channels[2]).into(),
+            channels[3],
+        )
+    }
+
+    fn put_pixel(&mut self, x: u32, y: u32, color: &AlphaColor) {
+        let rgb = color.color().rgb();
+        self.put_pixel(x, y, Rgba([rgb.r(), rgb.g(), rgb.b(), color.alpha()]));
+    }
+
+    fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> Self {
+        imageops::crop(self, x, y, width, height).to_image()
+    }
+}
+
+// Returns the part of the original image which was removed as the background. Each pixel has the
+// color of the original pixel and the opacity which it lost, 1 - alpha of the pixel in the image
+// with the background removed, so that partially transparent edges are split between the two
+// images. Adding the premultiplied colors of both images restores the original image.
+pub fn removed_background(original: &RgbaImage, removed: &RgbaImage) -> Result<RgbaImage> {
+    if original.dimensions() != removed.dimensions() {
+        return Err(anyhow!("images must have the same size"));
+    }
+
+    let mut background = original.clone();
+    for (pixel, removed_pixel) in background.pixels_mut().zip(removed.pixels()) {
+        let lost = 255 - removed_pixel.0[3] as u32;
+        pixel.0[3] = ((pixel.0[3] as u32 * lost + 127) / 255) as u8;
+        if pixel.0[3] == 0 {
+            *pixel = Rgba([0, 0, 0, 0]);
+        }
+    }
+    Ok(background)
+}
+
+// Encodes the image as a PNG with an indexed palette, preserving transparency, if it has at most
+// the given number of distinct colors, which must be at most 256. Stickers with few colors e.g.
+// pixel art are much smaller this way than in truecolor. Returns None if there are too many
+// colors. All fully transparent pixels share a single palette entry.
+pub fn encode_indexed_png(img: &RgbaImage, max_colors: usize) -> Result<Option<Vec<u8>>> {
+    if max_colors > 256 {
+        return Err(anyhow!("indexed palette can have at most 256 colors"));
+    }
+
+    let mut palette: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut entries = vec![];
+    let mut indices = Vec::with_capacity((img.width() * img.height()) as usize);
+    for pixel in img.pixels() {
+        let key = if pixel.0[3] == 0 { [0; 4] } else { pixel.0 };
+        let index = match palette.get(&key) {
+            Some(index) => *index,
+            None => {
+                if entries.len() >= max_colors {
+                    return Ok(None);
+                }
+                let index = entries.len() as u8;
+                palette.insert(key, index);
+                entries.push(key);
+                index
+            }
+        };
+        indices.push(index);
+    }
+
+    let mut buffer = vec![];
+    let mut encoder = png::Encoder::new(&mut buffer, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(
+        entries
+            .iter()
+            .flat_map(|v| [v[0], v[1], v[2]])
+            .collect::<Vec<_>>(),
+    );
+    if entries.iter().any(|v| v[3] != 255) {
+        encoder.set_trns(entries.iter().map(|v| v[3]).collect::<Vec<_>>());
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+
+    Ok(Some(buffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::Cancelled;
+
+    // Slightly darker than white so that large markers aren't mistaken for specular highlights.
+    const MARKER: Rgba<u8> = Rgba([240, 240, 240, 255]);
+
+    fn photo(width: u32, height: u32, color: [u8; 3]) -> RgbaImage {
+        RgbaImage::from_pixel(width, height, Rgba([color[0], color[1], color[2], 255]))
+    }
+
+    fn fill(img: &mut RgbaImage, area: Area, color: Rgba<u8>) {
+        for xy in area.pixels() {
+            img.put_pixel(xy.x, xy.y, color);
+        }
+    }
+
+    #[test]
+    fn flood_fill_ordered_returns_pixels_in_raster_order() {
+        let mut img = photo(50, 40, [30, 30, 30]);
+        fill(&mut img, Area::new(10, 5, 12, 9), MARKER);
+
+        let match_color = |_: &XY, color: &AlphaColor| color.color().rgb().r() == 240;
+        let pixels = flood_fill_ordered(&img, XY { x: 15, y: 8 }, match_color);
+        assert_eq!(pixels.len(), 12 * 9);
+        assert!(
+            pixels
+                .windows(2)
+                .all(|v| (v[0].y, v[0].x) < (v[1].y, v[1].x))
+        );
+        assert_eq!(
+            pixels,
+            flood_fill_ordered(&img, XY { x: 21, y: 13 }, match_color)
+        );
+    }
+
+    #[test]
+    fn marker_detection_is_stable() {
+        let mut img = photo(1000, 800, [30, 30, 30]);
+        for (x, y) in [(2, 3), (850, 3), (2, 650), (850, 650)] {
+            fill(&mut img, Area::new(x, y, 101, 87), MARKER);
+        }
+
+        let markers = Markers::find(&img).unwrap();
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(52.0, 46.0));
+        for _ in 0..3 {
+            let again = Markers::find(&img).unwrap();
+            for (a, b) in markers.markers().iter().zip(again.markers()) {
+                assert_eq!(a.center(), b.center());
+            }
+        }
+    }
+
+    #[test]
+    fn find_all_finds_every_sheet() {
+        let mut img = photo(700, 300, [20, 20, 20]);
+        for sheet in 0..3 {
+            let left = 10 + sheet * 230;
+            for (x, y) in [(left, 10), (left + 200, 10), (left, 270), (left + 200, 270)] {
+                fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+            }
+        }
+
+        let sheets = Markers::find_all(&img, DEFAULT_HIGHLIGHT_CEILING);
+        assert_eq!(sheets.len(), 3);
+        let mut lefts: Vec<f32> = sheets.iter().map(|v| v.top_left().center().x).collect();
+        lefts.sort_by(f32::total_cmp);
+        assert_eq!(lefts, [14.5, 244.5, 474.5]);
+    }
+
+    #[test]
+    fn clipped_marker_center_is_extrapolated() {
+        let mut img = photo(200, 200, [30, 30, 30]);
+        // The top markers are 10 pixels tall but only 4 rows of them are in the photo.
+        for (x, y, height) in [(5, 0, 4), (185, 0, 4), (5, 185, 10), (185, 185, 10)] {
+            fill(&mut img, Area::new(x, y, 10, height), MARKER);
+        }
+
+        let markers = Markers::find(&img).unwrap();
+        assert!(markers.top_left().is_clipped());
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(9.5, -1.5));
+        assert!(!markers.bottom_left().is_clipped());
+        assert_eq!(markers.bottom_left().center(), &PreciseXY::new(9.5, 189.5));
+    }
+
+    #[test]
+    fn try_get_pixel_checks_bounds() {
+        let img = photo(4, 3, [0, 0, 0]);
+        assert!(img.try_get_pixel(4, 0).is_none());
+        assert!(img.try_get_pixel(0, 3).is_none());
+        assert!(img.try_get_pixel(3, 2).is_some());
+    }
+
+    #[test]
+    fn average_opaque_color_ignores_transparent_pixels() {
+        let mut img = RgbaImage::new(10, 10);
+        fill(&mut img, Area::new(0, 0, 5, 10), Rgba([100, 50, 200, 255]));
+
+        let color = Area::new(0, 0, 10, 10).average_opaque_color(&img).unwrap();
+        let rgb = color.rgb();
+        assert_eq!((rgb.r(), rgb.g(), rgb.b()), (100, 50, 200));
+        assert!(Area::new(6, 0, 4, 4).average_opaque_color(&img).is_none());
+    }
+
+    #[test]
+    fn area_center_isnt_rounded() {
+        assert_eq!(Area::new(10, 20, 4, 3).center(), PreciseXY::new(11.5, 21.0));
+        assert_eq!(Area::new(10, 20, 5, 6).center(), PreciseXY::new(12.0, 22.5));
+        assert_eq!(Area::new(7, 7, 1, 1).center(), PreciseXY::new(7.0, 7.0));
+    }
+
+    #[test]
+    fn line_iterator_visits_both_ends() {
+        let diagonal: Vec<XY> = LineIterator::new(&XY::new(2, 3), &XY::new(7, 8)).collect();
+        assert_eq!(diagonal.len(), 6);
+        for (i, xy) in diagonal.iter().enumerate() {
+            assert_eq!(*xy, XY::new(2 + i as u32, 3 + i as u32));
+        }
+
+        let shallow: Vec<XY> = LineIterator::new(&XY::new(10, 0), &XY::new(0, 4)).collect();
+        assert_eq!(shallow.len(), 11);
+        assert_eq!(shallow.first(), Some(&XY::new(10, 0)));
+        assert_eq!(shallow.last(), Some(&XY::new(0, 4)));
+
+        assert_eq!(LineIterator::new(&XY::new(1, 1), &XY::new(1, 1)).count(), 1);
+    }
+
+    #[test]
+    fn glare_covering_neighbouring_samples_is_skipped() {
+        let mut img = photo(400, 400, [100, 100, 100]);
+        for (x, y) in [(20, 20), (360, 20), (20, 360), (360, 360)] {
+            fill(&mut img, Area::new(x, y, 20, 20), MARKER);
+        }
+        // Covers two neighbouring samples along the top edge.
+        fill(
+            &mut img,
+            Area::new(165, 20, 70, 20),
+            Rgba([170, 170, 170, 255]),
+        );
+
+        let markers = Markers::find(&img).unwrap();
+        let background = Background::analyse(&img, &markers, &ExtractionConfig::default()).unwrap();
+        assert!(!background.areas().is_empty());
+        assert!(background.areas().values().all(|v| v.rgb().r() < 140));
+    }
+
+    #[test]
+    fn exposure_is_evened_out_across_markers() {
+        let mut img = photo(200, 150, [60, 90, 40]);
+        for (x, y) in [(5, 5), (185, 5), (5, 135), (185, 135)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
+        // Darkens the photo towards the right and the bottom.
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let factor = 1.0 - 0.3 * (x as f32 / 200.0) - 0.15 * (y as f32 / 150.0);
+            let (r, g, b) = RGB::new(pixel[0], pixel[1], pixel[2]).to_linear();
+            let rgb = RGB::from_linear(r * factor, g * factor, b * factor);
+            *pixel = Rgba([rgb.r(), rgb.g(), rgb.b(), 255]);
+        }
+
+        let markers = Markers::find(&img).unwrap();
+        let spread = |img: &RgbaImage| {
+            let luminance: Vec<f32> = markers
+                .markers()
+                .iter()
+                .map(|v| {
+                    let xy = v.center().to_xy();
+                    Image::get_pixel(img, xy.x, xy.y)
+                        .color()
+                        .rgb()
+                        .to_linear()
+                        .0
+                })
+                .collect();
+            luminance.iter().copied().fold(f32::MIN, f32::max)
+                - luminance.iter().copied().fold(f32::MAX, f32::min)
+        };
+
+        let before = spread(&img);
+        correct_exposure_with_colors(
+            &mut img,
+            &markers,
+            &[MarkerColor::Black, MarkerColor::White],
+        )
+        .unwrap();
+        assert!(spread(&img) < before * 0.2);
+        assert!(correct_exposure_with_colors(&mut img, &markers, &[]).is_err());
+    }
+
+    #[test]
+    fn convex_hull_of_l_shape() {
+        let mut pixels = HashSet::new();
+        pixels.extend(Area::new(0, 0, 10, 3).pixels());
+        pixels.extend(Area::new(0, 0, 3, 10).pixels());
+
+        let hull = convex_hull(&pixels);
+        assert_eq!(hull.len(), 5);
+        let expected = [
+            XY::new(0, 0),
+            XY::new(9, 0),
+            XY::new(9, 2),
+            XY::new(2, 9),
+            XY::new(0, 9),
+        ];
+        assert!(expected.iter().all(|v| hull.contains(v)));
+    }
+
+    #[test]
+    fn area_scale_and_translate() {
+        let area = Area::new(3, 5, 7, 9);
+        assert_eq!(area.scale(2.0), Area::new(6, 10, 14, 18));
+        assert_eq!(area.scale(2.0).scale(0.5), area);
+        assert_eq!(area.scale(0.01).width, 1);
+
+        assert_eq!(area.translate(-3, 10), Some(Area::new(0, 15, 7, 9)));
+        assert_eq!(area.translate(-4, 0), None);
+        assert_eq!(area.translate(u32::MAX as i64, 0), None);
+    }
+
+    #[test]
+    fn area_pixels_visits_every_pixel_once() {
+        let area = Area::new(2, 3, 4, 5);
+        let pixels: HashSet<XY> = area.pixels().collect();
+        assert_eq!(pixels.len() as u32, area.area());
+        assert!(pixels.iter().all(|v| area.contains(v)));
+
+        assert_eq!(Area::new(1, 1, 0, 3).pixels().count(), 0);
+    }
+
+    #[test]
+    fn area_color_paints_the_corner_pixel() {
+        let mut img = photo(10, 10, [0, 0, 0]);
+        Area::new(2, 3, 4, 5).color(&mut img, &RGB::new(9, 9, 9).into());
+
+        assert_eq!(img.get_pixel(5, 7).0, [9, 9, 9, 255]);
+        assert_eq!(img.get_pixel(6, 7).0, [0, 0, 0, 255]);
+        assert_eq!(img.get_pixel(5, 8).0, [0, 0, 0, 255]);
+        assert_eq!(img.get_pixel(2, 3).0, [9, 9, 9, 255]);
+    }
+
+    #[test]
+    fn removed_background_restores_the_original() {
+        let mut original = RgbaImage::new(20, 10);
+        for (x, y, pixel) in original.enumerate_pixels_mut() {
+            *pixel = Rgba([x as u8 * 10, y as u8 * 20, 7, 255]);
+        }
+        // Removed on the left, kept on the right and partially removed in between.
+        let mut removed = original.clone();
+        for (x, _, pixel) in removed.enumerate_pixels_mut() {
+            pixel.0[3] = match x {
+                0..8 => 0,
+                8..12 => 100,
+                _ => 255,
+            };
+            if pixel.0[3] == 0 {
+                *pixel = Rgba([0, 0, 0, 0]);
+            }
+        }
+
+        let background = removed_background(&original, &removed).unwrap();
+        assert_eq!(background.get_pixel(15, 3).0, [0, 0, 0, 0]);
+        assert_eq!(background.get_pixel(3, 3).0, original.get_pixel(3, 3).0);
+        let premultiplied = |pixel: &Rgba<u8>| {
+            let rgb = AlphaColor::new(RGB::new(pixel[0], pixel[1], pixel[2]).into(), pixel[3])
+                .premultiplied();
+            [rgb.r(), rgb.g(), rgb.b()].map(|v| v as i32)
+        };
+        for ((original, removed), background) in original
+            .pixels()
+            .zip(removed.pixels())
+            .zip(background.pixels())
+        {
+            let (removed_channels, background_channels) =
+                (premultiplied(removed), premultiplied(background));
+            for (i, expected) in premultiplied(original).into_iter().enumerate() {
+                assert!((removed_channels[i] + background_channels[i] - expected).abs() <= 1);
+            }
+            assert_eq!(removed[3] as u32 + background[3] as u32, 255);
+        }
+
+        assert!(removed_background(&original, &RgbaImage::new(3, 3)).is_err());
+    }
+
+    #[test]
+    fn cancelled_processing_stops() {
+        let mut img = photo(120, 90, [90, 90, 90]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        let markers = Markers::find(&img).unwrap();
+        let config = ExtractionConfig::default();
+        config.cancel.store(true, Ordering::Relaxed);
+
+        let err = Background::analyse(&img, &markers, &config).err().unwrap();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        let err = flood_fill_mask(&img, XY::new(60, 45), &config.cancel, |_, _| true)
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+    }
+
+    #[test]
+    fn sticker_alpha_threshold_decides_if_faint_edges_are_included() {
+        let mut img = RgbaImage::new(60, 60);
+        fill(&mut img, Area::new(10, 10, 30, 30), Rgba([200, 0, 0, 10]));
+        fill(&mut img, Area::new(15, 15, 20, 20), Rgba([200, 0, 0, 255]));
+
+        let mut config = ExtractionConfig {
+            sticker_alpha_threshold: 32,
+            ..Default::default()
+        };
+        let stickers = IdentifiedStickers::new(&img, &config);
+        assert_eq!(stickers.stickers().len(), 1);
+        assert_eq!(stickers.stickers()[0].area, Area::new(15, 15, 20, 20));
+
+        config.sticker_alpha_threshold = 10;
+        let stickers = IdentifiedStickers::new(&img, &config);
+        assert_eq!(stickers.stickers().len(), 1);
+        assert_eq!(stickers.stickers()[0].area, Area::new(10, 10, 30, 30));
+    }
+
+    #[test]
+    fn marker_out_of_frame_requires_evidence() {
+        let find = |top_left: &[Area]| {
+            let mut img = photo(1000, 1000, [30, 30, 30]);
+            for area in top_left {
+                fill(&mut img, area.clone(), MARKER);
+            }
+            for (x, y) in [(980, 5), (5, 980), (980, 980)] {
+                fill(&mut img, Area::new(x, y, 12, 12), MARKER);
+            }
+            format!("{:#}", Markers::find(&img).err().unwrap())
+        };
+
+        let clipped = find(&[Area::new(0, 20, 4, 12)]);
+        assert!(clipped.contains("top left marker likely out of frame, only a small"));
+
+        // A speck and a diagonal scratch which touch the edge aren't cut off markers.
+        let mut noise = vec![Area::new(0, 20, 2, 2)];
+        noise.extend((0..40).map(|i| Area::new(i, 100 + i, 1, 1)));
+        let noise = find(&noise);
+        assert!(noise.contains("top left marker not found after scanning"));
+        assert!(!noise.contains("out of frame"));
+    }
+
+    #[test]
+    fn marker_out_of_frame_is_projected_from_the_others() {
+        let mut img = photo(200, 200, [30, 30, 30]);
+        for (x, y) in [(146, 1), (1, 176), (161, 161)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
+
+        let err = format!("{:#}", Markers::find(&img).err().unwrap());
+        assert!(err.contains("top left marker likely out of frame, it would be at -10,20"));
+    }
+
+    #[test]
+    fn rotated_markers_match_markers_found_in_rotated_photo() {
+        let mut img = photo(300, 200, [30, 30, 30]);
+        for (x, y) in [(5, 5), (280, 5), (5, 180), (280, 180)] {
+            fill(&mut img, Area::new(x, y, 12, 8), MARKER);
+        }
+
+        let markers = Markers::find(&img).unwrap();
+        assert!(markers.is_sideways(0.7));
+
+        let rotated = markers.rotate_clockwise(img.height());
+        let found = Markers::find(&imageops::rotate90(&img)).unwrap();
+        for (a, b) in rotated.markers().iter().zip(found.markers()) {
+            assert_eq!(a.center(), b.center());
+            assert_eq!(a.area(), b.area());
+        }
+    }
+
+    #[test]
+    fn tiled_background_difference_matches_whole_image() {
+        let mut img = photo(120, 90, [60, 90, 40]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        fill(
+            &mut img,
+            Area::new(40, 30, 20, 20),
+            Rgba([200, 30, 30, 255]),
+        );
+
+        let markers = Markers::find(&img).unwrap();
+        let background = Background::analyse(&img, &markers, &ExtractionConfig::default()).unwrap();
+        let normalisation = BackgroundNormalisation::Regional { regions: 3 };
+        let cancel = AtomicBool::new(false);
+        let whole = BackgroundDifference::new(&img, &background, &normalisation, &cancel).unwrap();
+
+        let mut visited = 0;
+        BackgroundDifference::for_each_tile(
+            &img,
+            |xy| background.check_color(xy).lab(),
+            17,
+            &normalisation,
+            |tile| {
+                for xy in tile.area().pixels() {
+                    visited += 1;
+                    assert_eq!(
+                        tile.get(&xy).diff_l.to_bits(),
+                        whole.get(&xy).diff_l.to_bits()
+                    );
+                    assert_eq!(
+                        tile.get(&xy).diff_b.to_bits(),
+                        whole.get(&xy).diff_b.to_bits()
+                    );
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(visited, 120 * 90);
+    }
+
+    #[test]
+    fn find_regions_matches_sequential_flood_fill() {
+        let mut img = RgbaImage::new(97, 83);
+        let opaque = Rgba([10, 200, 10, 255]);
+        for area in [
+            Area::new(3, 3, 20, 70),
+            Area::new(3, 3, 80, 5),
+            Area::new(50, 20, 10, 10),
+            Area::new(70, 40, 20, 40),
+            Area::new(30, 60, 2, 2),
+        ] {
+            fill(&mut img, area, opaque);
+        }
+        for i in 0..80 {
+            img.put_pixel(i, (i * 7) % 83, opaque);
+        }
+
+        let is_opaque = |_: &XY, color: &AlphaColor| !color.is_transparent();
+        fn sorted(pixels: impl IntoIterator<Item = XY>) -> Vec<(u32, u32)> {
+            let mut pixels: Vec<(u32, u32)> = pixels.into_iter().map(|v| (v.y, v.x)).collect();
+            pixels.sort();
+            pixels
+        }
+
+        let mut parallel: Vec<_> = find_regions(&img, is_opaque)
+            .into_iter()
+            .map(sorted)
+            .collect();
+        let mut visited = HashSet::new();
+        let mut sequential = vec![];
+        for xy in Area::new(0, 0, 97, 83).pixels() {
+            if !visited.contains(&xy) && !Image::get_pixel(&img, xy.x, xy.y).is_transparent() {
+                let pixels = flood_fill(&img, xy, is_opaque);
+                visited.extend(pixels.iter().cloned());
+                sequential.push(sorted(pixels));
+            }
+        }
+
+        parallel.sort();
+        sequential.sort();
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn overlay_draws_sticker_borders() {
+        let mut img = RgbaImage::new(200, 100);
+        for left in [10, 120] {
+            fill(
+                &mut img,
+                Area::new(left, 10, 40, 40),
+                Rgba([10, 10, 10, 255]),
+            );
+        }
+
+        let stickers = IdentifiedStickers::new(&img, &ExtractionConfig::default());
+        let overlay = stickers.render_overlay(&img);
+        assert_eq!(overlay.get_pixel(30, 10).0, [255, 0, 0, 255]);
+        assert_eq!(overlay.get_pixel(140, 10).0, [0, 200, 0, 255]);
+        assert_eq!(overlay.get_pixel(30, 30).0, [10, 10, 10, 255]);
+    }
+
+    #[test]
+    fn chroma_key_removes_green_background() {
+        let mut img = photo(20, 20, [40, 200, 60]);
+        fill(&mut img, Area::new(5, 5, 5, 5), Rgba([200, 40, 40, 255]));
+
+        remove_chroma_key(&mut img, &ChromaKey::default()).unwrap();
+        assert_eq!(img.get_pixel(0, 0).0[3], 0);
+        assert_eq!(img.get_pixel(6, 6).0, [200, 40, 40, 255]);
+    }
+
+    #[test]
+    fn shadow_next_to_sticker_is_removed() {
+        let mut img = RgbaImage::new(60, 60);
+        fill(
+            &mut img,
+            Area::new(10, 10, 40, 40),
+            Rgba([200, 200, 200, 255]),
+        );
+        // Shadow getting lighter away from the sticker.
+        for i in 0..6 {
+            let v = 150 + i as u8 * 8;
+            fill(
+                &mut img,
+                Area::new(15 + i, 15 + i, 30, 30),
+                Rgba([v, v, v, 255]),
+            );
+        }
+        fill(
+            &mut img,
+            Area::new(20, 20, 20, 20),
+            Rgba([200, 30, 30, 255]),
+        );
+
+        let background: CachedColor = Color::from(RGB::new(200, 200, 200)).into();
+        remove_shadows(&mut img, |_| background.clone());
+        assert_eq!(img.get_pixel(16, 16).0[3], 0);
+        assert_eq!(img.get_pixel(25, 25).0, [200, 30, 30, 255]);
+    }
+
+    #[test]
+    fn residual_background_is_measured_on_the_sheet() {
+        let mut img = photo(120, 90, [90, 90, 90]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        let markers = Markers::find(&img).unwrap();
+        let background = Background::analyse(&img, &markers, &ExtractionConfig::default()).unwrap();
+
+        assert!(background.residual(&img, &markers, 1.0) > 0.8);
+        let removed = RgbaImage::new(120, 90);
+        assert_eq!(background.residual(&removed, &markers, 1.0), 0.0);
+    }
+
+    #[test]
+    fn blurred_photo_is_less_sharp() {
+        let mut img = photo(120, 90, [90, 90, 90]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        for i in 0..10 {
+            fill(
+                &mut img,
+                Area::new(20 + i * 8, 30, 4, 30),
+                Rgba([0, 0, 0, 255]),
+            );
+        }
+        let markers = Markers::find(&img).unwrap();
+
+        let blurred = imageops::blur(&img, 1.5);
+        assert!(sharpness(&img, &markers) > 2.0 * sharpness(&blurred, &markers));
+    }
+
+    #[test]
+    fn grid_fit_reports_missing_cells() {
+        let mut img = RgbaImage::new(400, 600);
+        for column in 0..4 {
+            for row in 0..6 {
+                if (column, row) != (2, 3) {
+                    let area = Area::new(column * 100 + 20, row * 100 + 20, 60, 60);
+                    fill(&mut img, area, Rgba([255, 0, 0, 255]));
+                }
+            }
+        }
+
+        let stickers = IdentifiedStickers::new(&img, &ExtractionConfig::default());
+        let grid_fit = stickers.fit_to_grid(4, 6).unwrap();
+        assert_eq!(grid_fit.stickers.len(), 23);
+        assert_eq!(grid_fit.missing_cells, vec![(2, 3)]);
+        let sticker = grid_fit
+            .stickers
+            .iter()
+            .find(|v| v.column == 3 && v.row == 3)
+            .unwrap();
+        assert_eq!(sticker.index, 16);
+    }
+
+    #[test]
+    fn min_area_rect_of_rotated_rectangle() {
+        let mut img = RgbaImage::new(200, 200);
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f32 + 0.5 - 100.0, y as f32 + 0.5 - 100.0);
+            if (dx * cos + dy * sin).abs() <= 60.0 && (dy * cos - dx * sin).abs() <= 30.0 {
+                *pixel = Rgba([10, 20, 30, 255]);
+            }
+        }
+
+        let rect = min_area_rect(&img).unwrap();
+        assert!((rect.angle.to_degrees() - 30.0).abs() < 2.0);
+        assert!((rect.width - 120.0).abs() < 3.0 && (rect.height - 60.0).abs() < 3.0);
+        assert!((rect.center.x - 100.0).abs() < 1.5 && (rect.center.y - 100.0).abs() < 1.5);
+    }
+
+    #[test]
+    fn eight_connected_regions_join_diagonal_neighbours() {
+        let mut img = RgbaImage::new(60, 60);
+        fill(&mut img, Area::new(5, 5, 20, 20), Rgba([200, 0, 0, 255]));
+        fill(&mut img, Area::new(25, 25, 20, 20), Rgba([200, 0, 0, 255]));
+
+        let mut config = ExtractionConfig::default();
+        assert_eq!(IdentifiedStickers::new(&img, &config).stickers().len(), 2);
+        config.sticker_connectivity = Connectivity::Eight;
+        assert_eq!(IdentifiedStickers::new(&img, &config).stickers().len(), 1);
     }
-}
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone)]
-pub struct Area {
-    top: u32,
-    left: u32,
-    width: u32,
-    height: u32,
-}
+    #[test]
+    fn lab_interpolated_background_matches_samples() {
+        let mut img = photo(120, 90, [60, 90, 40]);
+        fill(
+            &mut img,
+            Area::new(0, 40, 120, 50),
+            Rgba([150, 120, 40, 255]),
+        );
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        let markers = Markers::find(&img).unwrap();
+        let config = ExtractionConfig {
+            background_interpolation: MixSpace::Lab,
+            ..Default::default()
+        };
 
-impl Area {
-    fn new_from_pixels(pixels: HashSet<XY>) -> Option<Area> {
-        if pixels.is_empty() {
-            return None;
+        let background = Background::analyse(&img, &markers, &config).unwrap();
+        for (area, color) in background.areas() {
+            let xy = area.center().to_xy();
+            assert_eq!(background.check_color(&xy).color().hex(), color.hex());
         }
+    }
 
-        let top = pixels.iter().map(|v| v.y).min().unwrap();
-        let bottom = pixels.iter().map(|v| v.y).max().unwrap();
-        let left = pixels.iter().map(|v| v.x).min().unwrap();
-        let right = pixels.iter().map(|v| v.x).max().unwrap();
+    #[test]
+    fn overexposed_glare_isnt_a_marker() {
+        let mut img = photo(400, 300, [60, 90, 40]);
+        fill(
+            &mut img,
+            Area::new(3, 3, 60, 50),
+            Rgba([255, 255, 255, 255]),
+        );
+        for (x, y) in [(70, 60), (360, 10), (10, 280), (380, 280)] {
+            fill(&mut img, Area::new(x, y, 8, 8), Rgba([235, 235, 235, 255]));
+        }
 
-        Some(Area {
-            top,
-            left,
-            width: right - left + 1,
-            height: bottom - top + 1,
-        })
+        let markers = Markers::find(&img).unwrap();
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(73.5, 63.5));
     }
 
-    pub fn center(&self) -> XY {
-        XY {
-            x: self.left + self.width / 2,
-            y: self.top + self.height / 2,
+    #[test]
+    fn gaussian_weighted_background_is_smoother() {
+        let mut img = photo(120, 90, [60, 90, 40]);
+        fill(&mut img, Area::new(0, 0, 60, 90), Rgba([120, 140, 90, 255]));
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
         }
-    }
+        let markers = Markers::find(&img).unwrap();
+
+        // Sum of the second differences of the lightness along a few rows.
+        let roughness = |config: &ExtractionConfig| {
+            let background = Background::analyse(&img, &markers, config).unwrap();
+            let lightness = |x: u32, y: u32| background.check_color(&XY::new(x, y)).lab().l();
+            let mut roughness = 0.0;
+            for y in [20, 45, 70] {
+                for x in 1..119 {
+                    roughness +=
+                        (lightness(x - 1, y) - 2.0 * lightness(x, y) + lightness(x + 1, y)).abs();
+                }
+            }
+            roughness
+        };
 
-    pub fn contains(&self, xy: &XY) -> bool {
-        xy.x >= self.left && xy.x <= self.right() && xy.y >= self.top && xy.y <= self.bottom()
+        let gaussian = ExtractionConfig {
+            background_weighting: BackgroundWeighting::Gaussian { sigma: 0.2 },
+            ..Default::default()
+        };
+        assert!(roughness(&gaussian) < roughness(&ExtractionConfig::default()));
     }
 
-    pub fn color<I: Image>(&self, img: &mut I, color: &Color) {
-        let color = AlphaColor::new_opaque(color.clone());
+    #[test]
+    fn residual_distortion_of_bowed_grid() {
+        let residual_distortion = |bow: u32| {
+            let mut img = RgbaImage::new(300, 300);
+            for column in 0..3 {
+                for row in 0..3 {
+                    let shift = if (column, row) == (1, 1) { bow } else { 0 };
+                    let area = Area::new(25 + column * 100 + shift, 25 + row * 100, 50, 50);
+                    fill(&mut img, area, Rgba([200, 0, 0, 255]));
+                }
+            }
+            IdentifiedStickers::new(&img, &ExtractionConfig::default())
+                .fit_to_grid(3, 3)
+                .unwrap()
+                .residual_distortion
+                .unwrap()
+        };
+
+        let flat = residual_distortion(0);
+        assert!(flat < 0.01);
+        assert!(residual_distortion(20) > flat);
+    }
 
-        for x in self.left..self.right() {
-            for y in self.top..self.bottom() {
-                img.put_pixel(x, y, &color);
+    #[test]
+    fn touching_stickers_are_split() {
+        let mut img = RgbaImage::new(120, 60);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let distance = |cx: f32| ((x as f32 - cx).powi(2) + (y as f32 - 30.0).powi(2)).sqrt();
+            let bridge = (50..=65).contains(&x) && (27..=33).contains(&y);
+            if distance(30.0) < 25.0 || distance(85.0) < 25.0 || bridge {
+                *pixel = Rgba([200, 0, 0, 255]);
             }
         }
+
+        let mut config = ExtractionConfig::default();
+        assert_eq!(IdentifiedStickers::new(&img, &config).stickers().len(), 1);
+        config.split_touching_stickers = true;
+        assert_eq!(IdentifiedStickers::new(&img, &config).stickers().len(), 2);
+
+        let mut rectangle = RgbaImage::new(120, 60);
+        fill(
+            &mut rectangle,
+            Area::new(10, 10, 100, 30),
+            Rgba([200, 0, 0, 255]),
+        );
+        assert_eq!(
+            IdentifiedStickers::new(&rectangle, &config)
+                .stickers()
+                .len(),
+            1
+        );
     }
 
-    fn average_color<I: Image>(&self, img: &I) -> Result<Color> {
-        //let mut y: Option<f32> = None;
-        //let mut u: Option<f32> = None;
-        //let mut v: Option<f32> = None;
+    #[test]
+    fn find_markers_returns_rounded_centers() {
+        let mut img = photo(120, 90, [60, 90, 40]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
 
-        let mut r: Option<f32> = None;
-        let mut g: Option<f32> = None;
-        let mut b: Option<f32> = None;
+        let markers = find_markers(&img, &ExtractionConfig::default()).unwrap();
+        assert_eq!(
+            markers,
+            [
+                XY::new(6, 6),
+                XY::new(112, 6),
+                XY::new(6, 84),
+                XY::new(112, 84)
+            ]
+        );
+    }
 
-        for px in self.left..(self.right() + 1) {
-            for py in self.top..(self.bottom() + 1) {
-                let pixel = img.get_pixel(px, py);
-                let rgb: RGB = pixel.color().rgb();
-                //let color: Color = rgb.into();
-                //let yuv: YUV = color.yuv();
-
-                //y = match y {
-                //    Some(y) => Some((y + yuv.y()) / 2.0),
-                //    None => Some(yuv.y()),
-                //};
-
-                //u = match u {
-                //    Some(u) => Some((u + yuv.u()) / 2.0),
-                //    None => Some(yuv.u()),
-                //};
-
-                //v = match v {
-                //    Some(v) => Some((v + yuv.v()) / 2.0),
-                //    None => Some(yuv.v()),
-                //};
-
-                r = match r {
-                    Some(r) => Some((r + rgb.r() as f32) / 2.0),
-                    None => Some(rgb.r() as f32),
-                };
+    #[test]
+    fn black_markers_are_found_after_white_ones() {
+        let mut img = photo(120, 90, [120, 140, 100]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), Rgba([10, 10, 10, 255]));
+        }
+        let colors = [MarkerColor::White, MarkerColor::Black];
+
+        assert!(Markers::find(&img).is_err());
+        let markers =
+            Markers::find_with_colors(&img, &colors, 4, 30, DEFAULT_HIGHLIGHT_CEILING).unwrap();
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(5.5, 5.5));
+        let sheets = Markers::find_all_with_colors(&img, &colors, DEFAULT_HIGHLIGHT_CEILING);
+        assert_eq!(sheets.len(), 1);
+    }
 
-                g = match g {
-                    Some(g) => Some((g + rgb.g() as f32) / 2.0),
-                    None => Some(rgb.g() as f32),
-                };
+    #[test]
+    fn markers_are_found_with_template() {
+        let mut img = photo(200, 200, [30, 30, 30]);
+        for (x, y) in [(5, 8), (183, 8), (5, 180), (183, 180)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
+        let mut template = photo(14, 14, [30, 30, 30]);
+        fill(&mut template, Area::new(2, 2, 10, 10), MARKER);
+
+        let markers = Markers::find_with_template(&img, &template, MARKER_SCAN_STEPS).unwrap();
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(9.5, 12.5));
+        assert_eq!(
+            markers.bottom_right().center(),
+            &PreciseXY::new(187.5, 184.5)
+        );
+
+        let uniform = photo(14, 14, [30, 30, 30]);
+        assert!(Markers::find_with_template(&img, &uniform, MARKER_SCAN_STEPS).is_err());
+        assert!(Markers::find_with_template(&template, &img, MARKER_SCAN_STEPS).is_err());
+    }
 
-                b = match b {
-                    Some(b) => Some((b + rgb.b() as f32) / 2.0),
-                    None => Some(rgb.b() as f32),
-                };
-            }
+    #[test]
+    fn regional_normalisation_stretches_local_differences() {
+        let mut img = photo(120, 120, [100, 100, 100]);
+        for (x, y) in [(2, 2), (110, 2), (2, 110), (110, 110)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
         }
+        fill(
+            &mut img,
+            Area::new(20, 20, 15, 15),
+            Rgba([250, 250, 250, 255]),
+        );
+        fill(
+            &mut img,
+            Area::new(55, 95, 10, 10),
+            Rgba([130, 130, 130, 255]),
+        );
+
+        let markers = Markers::find(&img).unwrap();
+        let background = Background::analyse(&img, &markers, &ExtractionConfig::default()).unwrap();
+        let cancel = AtomicBool::new(false);
+        let difference = |normalisation: &BackgroundNormalisation| {
+            BackgroundDifference::new(&img, &background, normalisation, &cancel)
+        };
 
-        //Ok(YUV::new(y.unwrap(), u.unwrap(), v.unwrap())?.into())
-        Ok(RGB::new(r.unwrap() as u8, g.unwrap() as u8, b.unwrap() as u8).into())
+        let xy = XY::new(60, 100);
+        let global = difference(&BackgroundNormalisation::Global).unwrap();
+        let regional = difference(&BackgroundNormalisation::Regional { regions: 3 }).unwrap();
+        let fixed = difference(&BackgroundNormalisation::Fixed {
+            l: 10.0,
+            a: 1.0,
+            b: 1.0,
+        })
+        .unwrap();
+        assert!(regional.get(&xy).diff_l > 2.0 * global.get(&xy).diff_l);
+        assert!(fixed.get(&xy).diff_l > 0.5);
+        assert!(difference(&BackgroundNormalisation::Regional { regions: 0 }).is_err());
     }
 
-    pub fn top(&self) -> u32 {
-        self.top
-    }
+    #[test]
+    fn principal_axis_rect_follows_elongated_shapes() {
+        let mut img = RgbaImage::new(100, 100);
+        let (sin, cos) = 30f32.to_radians().sin_cos();
+        for t in 0..70 {
+            for w in -4..=4 {
+                let x = 15.0 + t as f32 * cos - w as f32 * sin;
+                let y = 10.0 + t as f32 * sin + w as f32 * cos;
+                img.put_pixel(x as u32, y as u32, Rgba([200, 10, 10, 255]));
+            }
+        }
 
-    pub fn left(&self) -> u32 {
-        self.left
+        let rect = principal_axis_rect(&img).unwrap();
+        assert!((rect.angle.to_degrees() - 30.0).abs() < 2.0);
+        assert!(rect.width > 3.0 * rect.height);
+
+        let mut tall = RgbaImage::new(40, 40);
+        fill(&mut tall, Area::new(15, 2, 6, 30), Rgba([1, 2, 3, 255]));
+        let rect = principal_axis_rect(&tall).unwrap();
+        assert!((rect.angle.abs().to_degrees() - 90.0).abs() < 0.5);
+        assert!((rect.width - 30.0).abs() < 0.01 && (rect.height - 6.0).abs() < 0.01);
+        assert!((rect.center.x - 18.0).abs() < 0.01 && (rect.center.y - 17.0).abs() < 0.01);
+        assert!(principal_axis_rect(&RgbaImage::new(10, 10)).is_none());
     }
 
-    pub fn width(&self) -> u32 {
-        self.width
+    #[test]
+    fn only_small_holes_are_filled() {
+        let transparent = Rgba([0, 0, 0, 0]);
+        let mut original = photo(40, 20, [9, 9, 9]);
+        fill(
+            &mut original,
+            Area::new(5, 5, 10, 10),
+            Rgba([200, 0, 0, 255]),
+        );
+        fill(&mut original, Area::new(9, 9, 2, 2), Rgba([1, 2, 3, 255]));
+        let mut img = RgbaImage::new(40, 20);
+        fill(&mut img, Area::new(5, 5, 10, 10), Rgba([200, 0, 0, 255]));
+        fill(&mut img, Area::new(9, 9, 2, 2), transparent);
+        fill(&mut img, Area::new(20, 2, 16, 16), Rgba([0, 200, 0, 255]));
+        fill(&mut img, Area::new(22, 4, 12, 12), transparent);
+
+        assert_eq!(fill_holes(&mut img, &original, 10, Connectivity::Four), 4);
+        assert_eq!(*img.get_pixel(9, 9), Rgba([1, 2, 3, 255]));
+        assert!(Image::get_pixel(&img, 0, 0).is_transparent());
+        assert!(Image::get_pixel(&img, 25, 8).is_transparent());
     }
 
-    pub fn height(&self) -> u32 {
-        self.height
+    #[test]
+    fn background_is_sampled_at_given_points() {
+        let mut img = photo(60, 40, [100, 100, 100]);
+        fill(&mut img, Area::new(0, 0, 10, 10), Rgba([200, 50, 50, 255]));
+        fill(
+            &mut img,
+            Area::new(50, 30, 10, 10),
+            Rgba([50, 200, 50, 255]),
+        );
+        fill(
+            &mut img,
+            Area::new(25, 15, 10, 10),
+            Rgba([50, 50, 200, 255]),
+        );
+        let config = ExtractionConfig::default();
+        let points = [XY::new(1, 1), XY::new(58, 38), XY::new(30, 20)];
+
+        let background = Background::analyse_with_samples(&img, &points, 5, &config).unwrap();
+        assert_eq!(background.check_color(&points[0]).rgb().r(), 200);
+        assert_eq!(background.check_color(&points[1]).rgb().g(), 200);
+        assert_eq!(background.check_color(&points[2]).rgb().b(), 200);
+        assert_eq!(background.areas().len(), 3);
+        assert!(Background::analyse_with_samples(&img, &[], 5, &config).is_err());
     }
 
-    fn right(&self) -> u32 {
-        self.left + self.width - 1
-    }
+    #[test]
+    fn sheet_edges_are_found_inside_of_opaque_band() {
+        let mut img = photo(80, 60, [30, 30, 30]);
+        fill(&mut img, Area::new(7, 5, 66, 48), Rgba([0, 0, 0, 0]));
+        fill(&mut img, Area::new(20, 20, 10, 10), Rgba([200, 0, 0, 255]));
 
-    fn bottom(&self) -> u32 {
-        self.top + self.height - 1
+        assert_eq!(find_sheet_edges(&img).unwrap(), Area::new(7, 5, 66, 48));
+        assert!(find_sheet_edges(&photo(10, 10, [1, 1, 1])).is_err());
     }
 
-    pub fn area(&self) -> u32 {
-        self.width * self.height
+    #[test]
+    fn color_cast_outliers_are_found() {
+        let colors: Vec<Color> = [
+            "#c8c8c8", "#cac8c6", "#b4b4b4", "#c6c8ca", "#e6c8a0", "#d0d0d0",
+        ]
+        .iter()
+        .map(|v| Color::from_hex(v).unwrap())
+        .collect();
+
+        // Only the orange sample is off, the darker gray one merely has a different brightness.
+        assert_eq!(find_color_cast_outliers(&colors, 6.0), vec![4]);
+        assert!(find_color_cast_outliers(&colors[..2], 6.0).is_empty());
     }
-}
 
-struct EdgeIterator {
-    a: XY,
-    b: XY,
-    steps: usize,
-    next_step: usize,
-}
+    #[test]
+    fn indexed_png_round_trip() {
+        let mut img = RgbaImage::new(20, 10);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            *pixel = match (x / 5 + y / 5) % 3 {
+                0 => Rgba([255, 0, 0, 255]),
+                1 => Rgba([0, 0, 255, 128]),
+                _ => Rgba([0, 0, 0, 0]),
+            };
+        }
 
-impl EdgeIterator {
-    pub fn new(a: XY, b: XY, steps: usize) -> Result<Self> {
-        if steps < 2 {
-            return Err(anyhow!(
-                "requesting fewer than two steps seems a bit nonsensical"
-            ));
+        let bytes = encode_indexed_png(&img, 16).unwrap().unwrap();
+        let reader = png::Decoder::new(std::io::Cursor::new(&bytes))
+            .read_info()
+            .unwrap();
+        assert_eq!(reader.info().color_type, png::ColorType::Indexed);
+        assert_eq!(image::load_from_memory(&bytes).unwrap().to_rgba8(), img);
+        assert!(encode_indexed_png(&img, 2).unwrap().is_none());
+        assert!(encode_indexed_png(&img, 257).is_err());
+    }
+
+    #[test]
+    fn confidence_is_lower_for_poor_photos() {
+        let weights = ConfidenceWeights::default();
+        let mut img = photo(200, 200, [60, 90, 40]);
+        for (x, y) in [(2, 2), (188, 2), (2, 188), (188, 188)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
+        for i in 0..30 {
+            let area = Area::new(30 + (i * 37) % 140, 30 + (i * 53) % 140, 6, 6);
+            fill(&mut img, area, Rgba([250, 20, 20, 255]));
         }
+        let markers = Markers::find(&img).unwrap();
+        let good = ConfidenceMetrics::new(&markers, sharpness(&img, &markers), Some(0.01))
+            .confidence(&weights);
+
+        // A blank photo in which one of the markers is missing and a stray blob is used instead.
+        let mut flat = photo(200, 200, [60, 90, 40]);
+        for (x, y) in [(2, 2), (2, 188), (188, 188)] {
+            fill(&mut flat, Area::new(x, y, 10, 10), MARKER);
+        }
+        fill(&mut flat, Area::new(110, 60, 8, 8), MARKER);
+        let markers = Markers::find(&flat).unwrap();
+        let bad = ConfidenceMetrics::new(&markers, sharpness(&flat, &markers), Some(0.6))
+            .confidence(&weights);
 
-        Ok(Self {
-            a,
-            b,
-            steps,
-            next_step: 0,
-        })
+        assert!(good > 0.8);
+        assert!(bad < 0.6 && good - bad > 0.3);
     }
-}
 
-impl Iterator for EdgeIterator {
-    type Item = (usize, XY);
+    #[test]
+    fn outlines_of_disc_with_hole() {
+        let mut img = RgbaImage::new(60, 60);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (dx, dy) = (x as f32 + 0.5 - 30.0, y as f32 + 0.5 - 30.0);
+            if (dx * dx + dy * dy).sqrt() <= 20.0 {
+                *pixel = Rgba([10, 200, 10, 255]);
+            }
+        }
+        fill(&mut img, Area::new(28, 28, 4, 4), Rgba([0, 0, 0, 0]));
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let current_step = self.next_step;
-        if current_step >= self.steps {
-            return None;
+        let outlines = trace_outlines(&img, 1);
+        assert_eq!(outlines.len(), 2);
+        for vertex in &outlines[0] {
+            let radius = ((vertex.x - 30.0).powi(2) + (vertex.y - 30.0).powi(2)).sqrt();
+            assert!((radius - 20.0).abs() < 1.5);
         }
+        assert_eq!(outlines[1].len(), 4);
 
-        self.next_step += 1;
+        let svg = outlines_svg(60, 60, &outlines);
+        assert!(svg.contains("fill-rule=\"evenodd\""));
+        assert_eq!(svg.matches('Z').count(), 2);
 
-        let fraction = current_step as f32 / ((self.steps - 1) as f32);
-        let length_x = self.b.x as f32 - self.a.x as f32;
-        let length_y = self.b.y as f32 - self.a.y as f32;
-        let x = self.a.x as f32 + fraction * length_x;
-        let y = self.a.y as f32 + fraction * length_y;
-
-        Some((
-            current_step,
-            XY {
-                x: x as u32,
-                y: y as u32,
-            },
-        ))
+        // Diagonal neighbours are separate outlines.
+        let mut diagonal = RgbaImage::new(4, 4);
+        fill(&mut diagonal, Area::new(0, 0, 2, 2), Rgba([1, 1, 1, 255]));
+        fill(&mut diagonal, Area::new(2, 2, 2, 2), Rgba([1, 1, 1, 255]));
+        assert_eq!(trace_outlines(&diagonal, 1).len(), 2);
     }
-}
 
-pub struct IdentifiedSticker {
-    pub area: Area,
-    pub column: usize,
-    pub row: usize,
-}
+    #[test]
+    fn background_noise_is_measured() {
+        let config = ExtractionConfig::default();
+        let mut img = photo(120, 90, [120, 130, 110]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        let markers = Markers::find(&img).unwrap();
+        let clean = Background::analyse(&img, &markers, &config)
+            .unwrap()
+            .noise(&img, 1.0);
+
+        let mut noisy = img.clone();
+        let mut seed: u32 = 12345;
+        for pixel in noisy.pixels_mut() {
+            if *pixel == MARKER {
+                continue;
+            }
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let offset = ((seed >> 16) % 21) as i32 - 10;
+            for channel in &mut pixel.0[..3] {
+                *channel = (*channel as i32 + offset) as u8;
+            }
+        }
+        let noise = Background::analyse(&noisy, &markers, &config)
+            .unwrap()
+            .noise(&noisy, 1.0);
 
-pub struct IdentifiedStickers {
-    stickers: Vec<IdentifiedSticker>,
-}
+        assert!(clean < 0.01);
+        assert!(noise > 2.0);
+    }
 
-impl IdentifiedStickers {
-    pub fn new<I: Image>(img: &I) -> Self {
-        let mut areas: Vec<Area> = vec![];
+    #[test]
+    fn marker_order_policies() {
+        let marker = |x: f32, y: f32| {
+            Marker::new(
+                Area::new(x as u32, y as u32, 2, 2),
+                PreciseXY::new(x, y),
+                false,
+            )
+        };
+        let new = |centers: [(f32, f32); 4], order: MarkerOrder| {
+            let [a, b, c, d] = centers.map(|(x, y)| marker(x, y));
+            Markers::new_with_order(a, b, c, d, order)
+        };
 
-        for ix in 0..img.width() {
-            for iy in 0..img.height() {
-                let xy = XY::new(ix, iy);
+        let upside_down = [(100.0, 100.0), (0.0, 100.0), (100.0, 0.0), (0.0, 0.0)];
+        assert!(new(upside_down, MarkerOrder::Strict).is_err());
+        let markers = new(upside_down, MarkerOrder::AutoCorrect).unwrap();
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(0.0, 0.0));
+        assert_eq!(
+            markers.bottom_right().center(),
+            &PreciseXY::new(100.0, 100.0)
+        );
+
+        // The top left marker is still to the right of the bottom right one after sorting.
+        let degenerate = [(100.0, 0.0), (150.0, 1.0), (0.0, 10.0), (50.0, 11.0)];
+        assert!(new(degenerate, MarkerOrder::AutoCorrect).is_err());
+        assert!(new(degenerate, MarkerOrder::BestEffort).is_ok());
+    }
 
-                let area_with_this_pixel_exists = areas.iter().any(|v| v.contains(&xy));
-                if area_with_this_pixel_exists {
-                    continue;
-                }
+    #[test]
+    fn difference_heatmap_is_bright_where_colors_differ() {
+        let mut img = photo(60, 60, [60, 60, 60]);
+        fill(
+            &mut img,
+            Area::new(25, 25, 10, 10),
+            Rgba([250, 180, 60, 255]),
+        );
+        let points = [
+            XY::new(5, 5),
+            XY::new(54, 5),
+            XY::new(5, 54),
+            XY::new(54, 54),
+        ];
+        let background =
+            Background::analyse_with_samples(&img, &points, 4, &ExtractionConfig::default())
+                .unwrap();
+        let cancel = AtomicBool::new(false);
+        let difference =
+            BackgroundDifference::new(&img, &background, &BackgroundNormalisation::Global, &cancel)
+                .unwrap();
+
+        let heatmap = difference.render_heatmap();
+        assert!(heatmap.get_pixel(5, 30).0[..3].iter().all(|v| *v < 10));
+        assert!(heatmap.get_pixel(30, 30).0[..3].iter().any(|v| *v > 200));
+    }
 
-                let color = img.get_pixel(xy.x(), xy.y());
-                if color.is_transparent() {
-                    continue;
-                }
+    #[test]
+    fn corner_scan_steps_override_the_default() {
+        let mut img = photo(200, 200, [30, 30, 30]);
+        for (x, y) in [(66, 66), (185, 5), (5, 185)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
+        let find = |corner_scan_steps: &CornerScanSteps| {
+            Markers::find_with_colors_and_order(
+                &img,
+                &[MarkerColor::White],
+                3,
+                MARKER_SCAN_STEPS,
+                corner_scan_steps,
+                DEFAULT_HIGHLIGHT_CEILING,
+                MarkerOrder::Strict,
+            )
+        };
 
-                let pixels = flood_fill(img, xy, |xy: &XY, _color: &AlphaColor| {
-                    let color = img.get_pixel(xy.x(), xy.y());
-                    !color.is_transparent()
-                });
+        let mut corner_scan_steps = CornerScanSteps::default();
+        assert!(find(&corner_scan_steps).is_err());
+        corner_scan_steps.set("top-left=40").unwrap();
+        assert!(find(&corner_scan_steps).unwrap().top_left().center().x > 60.0);
+        assert!(corner_scan_steps.set("middle=3").is_err());
 
-                let area = Area::new_from_pixels(pixels).unwrap();
-                areas.push(area);
-            }
+        // Scanning past the middle of the image is rejected for each corner too.
+        corner_scan_steps.set("top-right=60").unwrap();
+        assert!(find(&corner_scan_steps).is_err());
+    }
+
+    #[test]
+    fn averaged_area_size_yields_target_number_of_areas() {
+        for (width, height, target) in [
+            (4000, 3000, 1200),
+            (800, 600, 1200),
+            (123, 457, 50),
+            (5000, 100, 10),
+        ] {
+            let size = averaged_area_size(width, height, target).unwrap();
+            let areas = width.div_ceil(size) * height.div_ceil(size);
+            assert!((areas as f32 - target as f32).abs() / (target as f32) < 0.2);
         }
 
-        areas.sort_by_key(|a| a.left());
+        // There can't be more areas than pixels.
+        assert_eq!(averaged_area_size(10, 10, 1000).unwrap(), 1);
+        assert!(averaged_area_size(0, 10, 5).is_err());
+        assert!(averaged_area_size(10, 10, 0).is_err());
+    }
 
-        let snap_distance = img.width() as f32 * SNAP_STICKERS_THRESHOLD;
+    #[test]
+    fn marker_center_is_centroid_of_its_pixels() {
+        let img = photo(100, 100, [30, 30, 30]);
+        // An L shaped marker, its centroid is closer to the corner than the center of its
+        // bounding box.
+        let mut pixels: HashSet<XY> = Area::new(10, 10, 10, 2).pixels().collect();
+        pixels.extend(Area::new(10, 12, 2, 8).pixels());
+
+        let marker = Marker::new_from_pixels(&img, &pixels).unwrap();
+        assert_eq!(marker.area(), &Area::new(10, 10, 10, 10));
+        let centroid = 458.0 / 36.0;
+        assert!((marker.center().x - centroid).abs() < 1e-4);
+        assert!((marker.center().y - centroid).abs() < 1e-4);
+
+        // A line is only as precise as its bounding box.
+        let line: HashSet<XY> = Area::new(40, 40, 7, 1).pixels().collect();
+        let marker = Marker::new_from_pixels(&img, &line).unwrap();
+        assert_eq!(marker.center(), &PreciseXY::new(43.0, 40.0));
+        assert!(Marker::new_from_pixels(&img, &HashSet::new()).is_none());
+    }
 
-        let mut stickers_assigned_to_columns = vec![];
-        for area in &areas {
-            if stickers_assigned_to_columns.is_empty() {
-                stickers_assigned_to_columns.push((area.clone(), 0));
-            } else {
-                let existing_column = stickers_assigned_to_columns
-                    .iter()
-                    .find(|v| {
-                        (v.0.center().x as f32 - area.center().x as f32).abs() < snap_distance
-                    })
-                    .map(|v| v.1);
-                match existing_column {
-                    Some(column) => {
-                        stickers_assigned_to_columns.push((area.clone(), column));
-                    }
-                    None => {
-                        let highest_column = stickers_assigned_to_columns
-                            .iter()
-                            .map(|v| v.1)
-                            .max()
-                            .unwrap();
-                        stickers_assigned_to_columns.push((area.clone(), highest_column + 1));
-                    }
-                }
-            }
+    #[test]
+    fn stickers_are_grouped_into_rows_by_snap_threshold() {
+        let mut img = RgbaImage::new(200, 200);
+        for (x, y) in [(10, 10), (110, 20), (10, 100), (110, 110)] {
+            fill(&mut img, Area::new(x, y, 20, 20), Rgba([200, 0, 0, 255]));
         }
+        let rows = |config: &ExtractionConfig| {
+            let stickers = IdentifiedStickers::new(&img, config);
+            let mut rows: Vec<(u32, u32, usize, usize)> = stickers
+                .stickers()
+                .iter()
+                .map(|v| (v.area.left(), v.area.top(), v.column, v.row))
+                .collect();
+            rows.sort();
+            rows
+        };
 
-        stickers_assigned_to_columns.sort_by(|a, b| match a.1.cmp(&b.1) {
-            cmp::Ordering::Less => cmp::Ordering::Less,
-            cmp::Ordering::Equal => a.0.top().partial_cmp(&b.0.top()).unwrap(),
-            cmp::Ordering::Greater => cmp::Ordering::Greater,
-        });
+        let mut config = ExtractionConfig::default();
+        assert_eq!(
+            rows(&config),
+            [
+                (10, 10, 0, 0),
+                (10, 100, 0, 1),
+                (110, 20, 1, 0),
+                (110, 110, 1, 1)
+            ]
+        );
+
+        config.snap_stickers_threshold_y = 0.02;
+        assert_eq!(
+            rows(&config),
+            [
+                (10, 10, 0, 0),
+                (10, 100, 0, 2),
+                (110, 20, 1, 1),
+                (110, 110, 1, 3)
+            ]
+        );
+    }
 
-        let mut stickers: Vec<IdentifiedSticker> = vec![];
-        let mut current_row = 0;
-        for (area, column) in stickers_assigned_to_columns {
-            match stickers.last() {
-                Some(last) => {
-                    if last.column != column {
-                        current_row = 0;
-                    } else {
-                        current_row += 1;
-                    }
-                    stickers.push(IdentifiedSticker {
-                        area,
-                        column,
-                        row: current_row,
-                    });
-                }
-                None => stickers.push(IdentifiedSticker {
-                    area,
-                    column,
-                    row: 0,
-                }),
-            }
+    #[test]
+    fn stickers_are_numbered_in_reading_order() {
+        let mut img = RgbaImage::new(300, 200);
+        for (x, y) in [(210, 10), (110, 110), (10, 10)] {
+            fill(&mut img, Area::new(x, y, 20, 20), Rgba([200, 0, 0, 255]));
         }
 
-        Self { stickers }
+        let stickers = IdentifiedStickers::new(&img, &ExtractionConfig::default());
+        let mut numbered: Vec<(usize, usize, usize)> = stickers
+            .stickers()
+            .iter()
+            .map(|v| (v.index, v.column, v.row))
+            .collect();
+        numbered.sort();
+        // Unlike the columns the indices don't have gaps.
+        assert_eq!(numbered, [(1, 0, 0), (2, 2, 0), (3, 1, 1)]);
     }
 
-    pub fn stickers(&self) -> &[IdentifiedSticker] {
-        &self.stickers
+    #[test]
+    fn markers_far_from_corners_are_found_by_scanning_whole_image() {
+        let mut img = photo(400, 400, [20, 20, 20]);
+        for (x, y) in [(150, 150), (240, 150), (150, 240), (240, 240)] {
+            fill(&mut img, Area::new(x, y, 6, 6), MARKER);
+        }
+
+        let markers = Markers::find(&img).unwrap();
+        assert_eq!(markers.top_left().center(), &PreciseXY::new(152.5, 152.5));
+        assert_eq!(
+            markers.bottom_right().center(),
+            &PreciseXY::new(242.5, 242.5)
+        );
     }
-}
 
-pub struct NormalisedBackgroundDifference {
-    pub diff_l: f32, // [-1, 1]
-    pub diff_a: f32, // [-1, 1]
-    pub diff_b: f32, // [-1, 1]
-}
+    #[test]
+    fn empty_regions_are_skipped() {
+        let img = RgbaImage::new(20, 20);
+        let is_opaque = |_: &XY, color: &AlphaColor| !color.is_transparent();
+        assert!(flood_fill(&img, XY::new(5, 5), is_opaque).is_empty());
+        assert!(Area::new_from_pixels(&HashSet::new()).is_none());
 
-pub struct BackgroundDifference {
-    distances: Vec<Vec<NormalisedBackgroundDifference>>,
-}
+        let stickers = IdentifiedStickers::new(&img, &ExtractionConfig::default());
+        assert!(stickers.stickers().is_empty());
+    }
 
-impl BackgroundDifference {
-    pub fn new<I: Image>(img: &I, background: &Background) -> Result<Self> {
-        let row_size = img.width() as usize;
-        let column_size = img.height() as usize;
+    #[test]
+    fn missing_markers_are_estimated() {
+        let find = |areas: &[(u32, u32)], marker_count: usize| {
+            let mut img = photo(200, 200, [30, 30, 30]);
+            for (x, y) in areas {
+                fill(&mut img, Area::new(*x, *y, 10, 10), MARKER);
+            }
+            Markers::find_with_count(
+                &img,
+                marker_count,
+                MARKER_SCAN_STEPS,
+                DEFAULT_HIGHLIGHT_CEILING,
+            )
+        };
 
-        let mut distances = Vec::with_capacity(row_size);
+        // The sheet is a parallelogram.
+        let markers = find(&[(10, 10), (180, 20), (20, 180)], 3).unwrap();
+        assert!(markers.bottom_right().is_estimated());
+        assert_eq!(
+            markers.bottom_right().center(),
+            &PreciseXY::new(194.5, 194.5)
+        );
 
-        let mut max_l = 0.0;
-        let mut max_a = 0.0;
-        let mut max_b = 0.0;
+        let markers = find(&[(10, 10), (180, 170)], 2).unwrap();
+        assert!(markers.top_right().is_estimated() && markers.bottom_left().is_estimated());
+        assert_eq!(markers.top_right().center(), &PreciseXY::new(184.5, 14.5));
+        assert_eq!(markers.bottom_left().center(), &PreciseXY::new(14.5, 174.5));
 
-        for xi in 0..img.width() {
-            let mut column = Vec::with_capacity(column_size);
+        assert!(find(&[(10, 10), (180, 170)], 1).is_err());
+    }
 
-            for yi in 0..img.height() {
-                let xy = XY::new(xi, yi);
+    #[test]
+    fn area_color_alpha_blends_over_the_image() {
+        let mut img = photo(10, 10, [0, 0, 255]);
+        let red = AlphaColor::new(RGB::new(255, 0, 0).into(), 128);
+        Area::new(2, 3, 4, 5).color_alpha(&mut img, &red);
 
-                let background_color: LAB = background.check_color(&xy).lab();
-                let alpha_color = img.get_pixel(xy.x(), xy.y());
-                let color = alpha_color.color().lab();
+        assert_eq!(img.get_pixel(5, 7).0, [128, 0, 127, 255]);
+        assert_eq!(img.get_pixel(6, 7).0, [0, 0, 255, 255]);
+    }
 
-                let distance_l = color.l() - background_color.l();
-                let distance_a = color.a() - background_color.a();
-                let distance_b = color.b() - background_color.b();
+    #[test]
+    fn mirrored_background_samples_are_averaged() {
+        let mut img = RgbaImage::new(120, 120);
+        for (x, _, pixel) in img.enumerate_pixels_mut() {
+            let value = 100 + x as u8;
+            *pixel = Rgba([value, value, value, 255]);
+        }
+        for (x, y) in [(2, 2), (110, 2), (2, 110), (110, 110)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        let markers = Markers::find(&img).unwrap();
+        let brightness = |mirror_background_samples: bool| {
+            let config = ExtractionConfig {
+                mirror_background_samples,
+                ..Default::default()
+            };
+            let background = Background::analyse(&img, &markers, &config).unwrap();
+            let left = background.check_color(&XY::new(15, 60)).rgb().r() as i32;
+            let right = background.check_color(&XY::new(104, 60)).rgb().r() as i32;
+            (left, right)
+        };
 
-                if distance_l > max_l {
-                    max_l = distance_l;
-                }
+        let (left, right) = brightness(false);
+        assert!(right - left > 50);
+        let (left, right) = brightness(true);
+        assert!((right - left).abs() <= 2);
+    }
 
-                if distance_a > max_a {
-                    max_a = distance_a;
-                }
+    #[test]
+    fn physical_size_is_proportional_to_sheet_size() {
+        let sheet = SheetSize {
+            width_mm: 200.0,
+            height_mm: 300.0,
+        };
+        let area = Area::new(10, 10, 50, 20);
+        assert_eq!(area.physical_size(400, 600, &sheet), (25.0, 10.0));
+    }
 
-                if distance_b > max_b {
-                    max_b = distance_b;
-                }
+    #[test]
+    fn failing_corner_is_named() {
+        let mut img = photo(200, 200, [30, 30, 30]);
+        for (x, y) in [(5, 5), (185, 5), (5, 185)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
 
-                column.push(NormalisedBackgroundDifference {
-                    diff_l: distance_l,
-                    diff_a: distance_a,
-                    diff_b: distance_b,
-                });
-            }
+        let err = Markers::find_with_scan_steps(&img, 10, DEFAULT_HIGHLIGHT_CEILING).err();
+        let err = format!("{:#}", err.unwrap());
+        assert!(err.contains("bottom right marker not found after scanning 10x10 positions"));
+        assert!(Markers::find_with_scan_steps(&img, 0, DEFAULT_HIGHLIGHT_CEILING).is_err());
+        assert!(Markers::find_with_scan_steps(&img, 50, DEFAULT_HIGHLIGHT_CEILING).is_err());
+    }
 
-            distances.push(column);
+    #[test]
+    fn corner_background_reference_is_uniform() {
+        let mut img = RgbaImage::new(120, 120);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            // Vignetting, the middle of the photo is brighter than the edges.
+            let distance = (x as i32 - 60).abs().max((y as i32 - 60).abs()) as u8;
+            let value = 200 - distance;
+            *pixel = Rgba([value, value, value, 255]);
         }
-
-        for xi in 0..img.width() {
-            for yi in 0..img.height() {
-                distances[xi as usize][yi as usize].diff_l /= max_l;
-                distances[xi as usize][yi as usize].diff_a /= max_a;
-                distances[xi as usize][yi as usize].diff_b /= max_b;
-            }
+        for (x, y) in [(2, 2), (110, 2), (2, 110), (110, 110)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
         }
+        let markers = Markers::find(&img).unwrap();
+        let analyse = |percentile: f32| {
+            let config = ExtractionConfig {
+                background_reference: BackgroundReference::Corners { percentile },
+                ..Default::default()
+            };
+            Background::analyse(&img, &markers, &config)
+        };
 
-        Ok(Self { distances })
+        let background = analyse(0.5).unwrap();
+        let corner = background.check_color(&XY::new(15, 15)).rgb().r();
+        let middle = background.check_color(&XY::new(60, 60)).rgb().r();
+        assert_eq!(corner, middle);
+        assert!(analyse(1.5).is_err());
     }
 
-    pub fn get(&self, xy: &XY) -> &NormalisedBackgroundDifference {
-        &self.distances[xy.x() as usize][xy.y() as usize]
+    #[test]
+    fn prepared_image_analyses_background_once() {
+        let mut img = photo(120, 90, [60, 90, 40]);
+        for (x, y) in [(2, 2), (108, 2), (2, 80), (108, 80)] {
+            fill(&mut img, Area::new(x, y, 8, 8), MARKER);
+        }
+        let markers = Markers::find(&img).unwrap();
+
+        let mut previews = vec![];
+        let config = ExtractionConfig::default();
+        let prepared = PreparedImage::new(img.clone(), &markers, &config, |_, name| {
+            previews.push(name.to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(previews, ["markers", "background_samples"]);
+        assert_eq!(prepared.img().get_pixel(5, 5).0, [255, 0, 0, 255]);
+        assert!(matches!(prepared.background(), BackgroundModel::Full(_)));
+        assert!(prepared.rotated_photo_height().is_none());
+
+        let config = ExtractionConfig {
+            tile_size: Some(32),
+            ..Default::default()
+        };
+        let prepared = PreparedImage::new(img, &markers, &config, |_, _| Ok(())).unwrap();
+        assert!(matches!(
+            prepared.background(),
+            BackgroundModel::Tiled { tile_size: 32, .. }
+        ));
     }
-}
 
-pub fn is_at_least_this_much_of_image<I: Image>(pixels: usize, img: &I, threshold: f32) -> bool {
-    (pixels as f32) >= ((img.width() * img.height()) as f32 * threshold)
-}
+    #[test]
+    fn exposure_correction_requires_all_markers() {
+        let mut img = photo(200, 200, [60, 90, 40]);
+        for (x, y) in [(10, 10), (180, 20), (20, 180)] {
+            fill(&mut img, Area::new(x, y, 10, 10), MARKER);
+        }
+        let markers =
+            Markers::find_with_count(&img, 3, MARKER_SCAN_STEPS, DEFAULT_HIGHLIGHT_CEILING)
+                .unwrap();
 
-pub trait Image {
-    fn width(&self) -> u32;
-    fn height(&self) -> u32;
-    fn get_pixel(&self, x: u32, y: u32) -> AlphaColor;
-    fn put_pixel(&mut self, x: u32, y: u32, color: &AlphaColor);
-    fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> Self;
+        let original = img.clone();
+        assert!(correct_exposure(&mut img, &markers, MarkerColor::White).is_err());
+        assert_eq!(img, original);
+    }
 }
-
@@ -0,0 +1,178 @@
+use crate::{errors::Result, extractor::PreciseXY};
+use anyhow::anyhow;
+
+// Determinants and pivots smaller than this make the transformation degenerate e.g. when three
+// of the points are collinear.
+const SINGULARITY_THRESHOLD: f64 = 1e-10;
+
+// Projective transformation of the plane, maps the points of a photographed sheet to the points
+// of the perspective corrected image and the other way around.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Homography {
+    matrix: [[f64; 3]; 3],
+}
+
+impl Homography {
+    // Calculates the transformation which maps each source point to the destination point with
+    // the same index.
+    pub fn from_point_pairs(src: [PreciseXY; 4], dst: [PreciseXY; 4]) -> Result<Homography> {
+        // Each pair of points gives two equations for the eight unknown elements of the matrix,
+        // the last element is fixed to 1.
+        let mut equations = [[0.0; 9]; 8];
+        for (i, (src, dst)) in src.iter().zip(dst.iter()).enumerate() {
+            let (x, y) = (src.x() as f64, src.y() as f64);
+            let (u, v) = (dst.x() as f64, dst.y() as f64);
+            equations[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+            equations[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+        }
+
+        let h = solve(equations)?;
+        Ok(Homography {
+            matrix: [[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], 1.0]],
+        })
+    }
+
+    pub fn apply(&self, xy: &PreciseXY) -> PreciseXY {
+        let m = &self.matrix;
+        let (x, y) = (xy.x() as f64, xy.y() as f64);
+        let w = m[2][0] * x + m[2][1] * y + m[2][2];
+        PreciseXY::new(
+            ((m[0][0] * x + m[0][1] * y + m[0][2]) / w) as f32,
+            ((m[1][0] * x + m[1][1] * y + m[1][2]) / w) as f32,
+        )
+    }
+
+    // Returns the transformation which maps the destination points back to the source points.
+    pub fn inverse(&self) -> Result<Homography> {
+        let m = &self.matrix;
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+
+        let adjugate = [
+            [
+                cofactor(1, 2, 1, 2),
+                -cofactor(0, 2, 1, 2),
+                cofactor(0, 1, 1, 2),
+            ],
+            [
+                -cofactor(1, 2, 0, 2),
+                cofactor(0, 2, 0, 2),
+                -cofactor(0, 1, 0, 2),
+            ],
+            [
+                cofactor(1, 2, 0, 1),
+                -cofactor(0, 2, 0, 1),
+                cofactor(0, 1, 0, 1),
+            ],
+        ];
+
+        let determinant =
+            m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+        if determinant.abs() < SINGULARITY_THRESHOLD {
+            return Err(anyhow!("homography can't be inverted"));
+        }
+
+        // Scaling the matrix doesn't change the transformation so it's normalised to keep the
+        // last element equal to 1 like in the matrices created from point pairs.
+        let scale = adjugate[2][2];
+        let scale = if scale.abs() < SINGULARITY_THRESHOLD {
+            determinant
+        } else {
+            scale
+        };
+
+        Ok(Homography {
+            matrix: adjugate.map(|row| row.map(|v| v / scale)),
+        })
+    }
+}
+
+// Solves a system of linear equations given as an augmented matrix using Gaussian elimination
+// with partial pivoting.
+pub(crate) fn solve<const N: usize, const M: usize>(
+    mut equations: [[f64; M]; N],
+) -> Result<[f64; N]> {
+    for column in 0..N {
+        let pivot = (column..N)
+            .max_by(|a, b| {
+                equations[*a][column]
+                    .abs()
+                    .total_cmp(&equations[*b][column].abs())
+            })
+            .unwrap();
+
+        if equations[pivot][column].abs() < SINGULARITY_THRESHOLD {
+            return Err(anyhow!("equations don't have a unique solution"));
+        }
+        equations.swap(column, pivot);
+
+        let pivot_row = equations[column];
+        for (row, equation) in equations.iter_mut().enumerate() {
+            if row == column {
+                continue;
+            }
+
+            let factor = equation[column] / pivot_row[column];
+            for (value, pivot_value) in equation.iter_mut().zip(pivot_row.iter()) {
+                *value -= factor * pivot_value;
+            }
+        }
+    }
+
+    let mut solution = [0.0; N];
+    for (i, value) in solution.iter_mut().enumerate() {
+        *value = equations[i][M - 1] / equations[i][i];
+    }
+
+    Ok(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &PreciseXY, b: &PreciseXY) {
+        assert!(
+            (a.x() - b.x()).abs() < 1e-3 && (a.y() - b.y()).abs() < 1e-3,
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn homography_round_trip() {
+        let src = [
+            PreciseXY::new(12.0, 30.5),
+            PreciseXY::new(410.0, 22.0),
+            PreciseXY::new(5.0, 590.0),
+            PreciseXY::new(395.0, 610.0),
+        ];
+        let dst = [
+            PreciseXY::new(0.0, 0.0),
+            PreciseXY::new(400.0, 0.0),
+            PreciseXY::new(0.0, 600.0),
+            PreciseXY::new(400.0, 600.0),
+        ];
+
+        let homography = Homography::from_point_pairs(src.clone(), dst.clone()).unwrap();
+        let inverse = homography.inverse().unwrap();
+        for (src, dst) in src.iter().zip(dst.iter()) {
+            assert_close(&homography.apply(src), dst);
+            assert_close(&inverse.apply(dst), src);
+        }
+
+        let xy = PreciseXY::new(123.0, 321.0);
+        assert_close(&inverse.apply(&homography.apply(&xy)), &xy);
+    }
+
+    #[test]
+    fn homography_rejects_collinear_points() {
+        let collinear = [
+            PreciseXY::new(0.0, 0.0),
+            PreciseXY::new(1.0, 1.0),
+            PreciseXY::new(2.0, 2.0),
+            PreciseXY::new(3.0, 3.0),
+        ];
+        assert!(Homography::from_point_pairs(collinear.clone(), collinear).is_err());
+    }
+}
@@ -1,6 +1,11 @@
 #![forbid(unsafe_code)]
 
 pub mod color;
+pub mod config;
 pub mod errors;
 pub mod extractor;
 pub mod fixtures;
+pub mod homography;
+pub mod manifest;
+
+pub use extractor::find_markers;
@@ -1,25 +1,45 @@
-use anyhow::Context;
-use clap::{Arg, ArgAction};
+use anyhow::{Context, anyhow};
+use clap::{Arg, ArgAction, ArgMatches};
 use core::panic;
 use env_logger::Env;
 use extractor_rust::{
-    color::{AlphaColor, Color, RGB},
-    errors::Result,
+    color::{AlphaColor, Color, LAB, MixSpace, RGB},
+    config::{
+        BackgroundReference, BackgroundWeighting, ChromaKey, ExtractionConfig, InitialCrop,
+        InvertColors, MarkerPositions, OutputFormat, OutputTarget, ResampleFilter, TrimRotation,
+        VirtualPixel,
+    },
+    errors::{Cancelled, EmptySheet, Result, check_cancelled},
     extractor::{
-        Background, BackgroundDifference, IdentifiedStickers, Image, Markers, XY, flood_fill,
-        is_at_least_this_much_of_image,
+        Area, Background, BackgroundDifference, BackgroundModel, ConfidenceMetrics,
+        IdentifiedSticker, IdentifiedStickers, Image, Marker, Markers,
+        NormalisedBackgroundDifference, PixelMask, PreciseXY, PreparedImage, RotatedRect,
+        TILED_PROCESSING_DOWNSCALED_SIZE, XY, dominant_color, downscale, encode_indexed_png,
+        fill_holes, find_color_cast_outliers, find_regions, find_sheet_edges, flood_fill_mask,
+        is_at_least_this_much_of_image, min_area_rect, outlines_svg, principal_axis_rect,
+        remove_chroma_key, remove_shadows, removed_background, sharpness, trace_outlines,
+    },
+    homography::Homography,
+    manifest::{
+        Manifest, ManifestBackgroundSample, ManifestMarker, ManifestPoint, ManifestSheet,
+        ManifestSticker,
     },
 };
 use image::{
-    ImageReader, Pixel, Rgba, RgbaImage,
-    imageops::{self},
+    DynamicImage, ImageFormat, ImageReader, Pixel, Rgba, Rgba32FImage, RgbaImage,
+    codecs::jpeg::JpegEncoder,
+    imageops::{self, FilterType},
 };
-use log::info;
+use log::{info, warn};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use std::{collections::HashSet, fs, path::Path, process::Command, time::Instant};
-use tempfile::TempDir;
-
-const INITIAL_CROP_FACTOR: f32 = 0.05; // 5%;
+use std::{
+    cmp, fs,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::Instant,
+};
+use zip::{ZipWriter, write::SimpleFileOptions};
 
 const BACKGROUND_DETECTION_FACTOR_L_POSITIVE: f32 = 0.30;
 const BACKGROUND_DETECTION_FACTOR_L_NEGATIVE: f32 = 0.15;
@@ -30,15 +50,121 @@ const BACKGROUND_DETECTION_FACTOR_A_NEGATIVE: f32 = 0.15;
 const BACKGROUND_DETECTION_FACTOR_B_POSITIVE: f32 = 0.30;
 const BACKGROUND_DETECTION_FACTOR_B_NEGATIVE: f32 = 0.30;
 
+// Noise, the standard deviation of lightness in the background samples, at which the background
+// detection factors are used as they are. If the thresholds adapt to the noise the factors are
+// multiplied by the ratio of the noise and this value limited to the given range.
+const REFERENCE_NOISE: f32 = 1.5;
+const MIN_NOISE_THRESHOLD_SCALE: f32 = 0.75;
+const MAX_NOISE_THRESHOLD_SCALE: f32 = 2.0;
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+// Quality of JPEG stickers unless it was set.
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+// Transparency of the perspective corrected image is checked at this many points along each
+// axis. Only points which were surrounded by transparent pixels within the radius in the photo
+// are checked as the pixels near the edges of the stickers are interpolated. A small fraction of
+// the points is allowed to fail.
+const TRANSPARENCY_CHECK_STEPS: u32 = 50;
+const TRANSPARENCY_CHECK_RADIUS: u32 = 4;
+const TRANSPARENCY_CHECK_MAX_FAILURES: f32 = 0.01;
+
+// Warn if more than 10% of the sheet is similar to the background after removing it.
+const RESIDUAL_BACKGROUND_WARNING_THRESHOLD: f32 = 0.1;
+
+// Warn if the centers of the stickers are on average further than 10% of the size of a grid
+// cell from the straight rows and columns of the grid.
+const RESIDUAL_DISTORTION_WARNING_THRESHOLD: f32 = 0.1;
+
 // If a group of non-transparent pixels constitutes
 // less than 2% of the image it will be made
 // transparent.
 const BACKGROUND_CLEANUP_FACTOR: f32 = 0.02;
 
+// Warn about sheets whose background differs from the median background of the batch by more
+// than this distance in the a and b channels of LAB.
+const COLOR_CAST_WARNING_THRESHOLD: f32 = 6.0;
+
 fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let command = clap::Command::new("extractor")
+    // Spans of the extraction stages are written to stderr when they are closed together with
+    // their durations.
+    #[cfg(feature = "tracing")]
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::fmt()
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .finish(),
+    )?;
+
+    let matches = command().get_matches();
+
+    match matches.subcommand() {
+        Some(("file", sub_matches)) => extract_file(sub_matches),
+        Some(("directory", sub_matches)) => {
+            let source_directory = sub_matches.get_one::<String>("SOURCE_DIRECTORY").unwrap();
+            let target_directory = sub_matches.get_one::<String>("TARGET_DIRECTORY").unwrap();
+
+            let readdir =
+                fs::read_dir(source_directory).context("error listing the source directory")?;
+            let mut paths: Vec<String> = vec![];
+            for v in readdir {
+                paths.push(v?.path().to_string_lossy().to_string());
+            }
+
+            let config = extraction_config(sub_matches)?;
+            configure_thread_pool(&config)?;
+            if config.deterministic {
+                paths.sort();
+            }
+            let fail_fast = sub_matches.get_flag("fail-fast");
+            let results = extract_all(&paths, target_directory, &config, fail_fast)?;
+
+            let mut manifest = Manifest::default();
+            let mut failures = 0;
+            for result in results {
+                match result {
+                    Ok(v) => manifest.extend(v),
+                    Err(err) => {
+                        warn!("{err:#}");
+                        failures += 1;
+                    }
+                }
+            }
+            warn_about_color_casts(&manifest)?;
+
+            save_manifest(target_directory, manifest, &config)?;
+
+            if failures > 0 {
+                return Err(anyhow!(
+                    "{} of {} photos couldn't be processed",
+                    failures,
+                    paths.len()
+                ));
+            }
+            Ok(())
+        }
+        Some(("reexport", sub_matches)) => {
+            let file_path = sub_matches.get_one::<String>("INPUT_FILE").unwrap();
+            let manifest_path = sub_matches.get_one::<String>("MANIFEST").unwrap();
+            let target_directory = sub_matches.get_one::<String>("TARGET_DIRECTORY").unwrap();
+
+            let config = extraction_config(sub_matches)?;
+            configure_thread_pool(&config)?;
+            let manifest = Manifest::load(manifest_path)?;
+            let manifest = reexport(file_path, &manifest, target_directory, &config)?;
+            save_manifest(target_directory, manifest, &config)?;
+            Ok(())
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Command line interface with a subcommand for each way of running the extraction.
+fn command() -> clap::Command {
+    clap::Command::new("extractor")
         .about("A program which processes photos of stickers")
         .subcommand_required(true)
         .arg_required_else_help(true)
@@ -51,6 +177,20 @@ fn main() -> Result<()> {
                         .action(ArgAction::SetTrue)
                         .help("save intermediate images for debugging purposes"),
                 )
+                .arg(
+                    Arg::new("preview-dir")
+                        .long("preview-dir")
+                        .value_name("DIRECTORY")
+                        .help("directory into which intermediate images are written"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("DIRECTORY")
+                        .default_value("./")
+                        .help("directory into which the stickers are written"),
+                )
+                .args(extraction_args())
                 .arg(clap::arg!(<INPUT_FILE> "The input file to process"))
                 .arg_required_else_help(true),
         )
@@ -58,72 +198,1771 @@ fn main() -> Result<()> {
             clap::Command::new("directory")
                 .about("Run the extraction process for a directory")
                 .arg(clap::arg!(<SOURCE_DIRECTORY> "The source directory"))
-                .arg(clap::arg!(<TARGET_DIRECTORY> "The target directory")),
-        );
+                .arg(clap::arg!(<TARGET_DIRECTORY> "The target directory"))
+                .arg(
+                    Arg::new("fail-fast")
+                        .long("fail-fast")
+                        .action(ArgAction::SetTrue)
+                        .overrides_with("continue")
+                        .help("stop processing the directory after the first photo which fails"),
+                )
+                .arg(
+                    Arg::new("continue")
+                        .long("continue")
+                        .action(ArgAction::SetTrue)
+                        .overrides_with("fail-fast")
+                        .help("process the remaining photos if one fails and report it at the end (default)"),
+                )
+                .args(extraction_args()),
+        )
+        .subcommand(
+            clap::Command::new("reexport")
+                .about("Export the stickers again using the markers and stickers from a manifest")
+                .arg(clap::arg!(<INPUT_FILE> "The photo the manifest was created for"))
+                .arg(clap::arg!(<MANIFEST> "The manifest"))
+                .arg(clap::arg!(<TARGET_DIRECTORY> "The target directory"))
+                .args(extraction_args()),
+        )
+}
 
-    let matches = command.get_matches();
+// Extracts the stickers from a single photo into the output directory given by the flags of the
+// file subcommand, creating the directory if it doesn't exist.
+fn extract_file(sub_matches: &ArgMatches) -> Result<()> {
+    let file_path = sub_matches.get_one::<String>("INPUT_FILE").unwrap();
 
-    match matches.subcommand() {
-        Some(("file", sub_matches)) => {
-            let file_path = sub_matches.get_one::<String>("INPUT_FILE").unwrap();
-            extract(file_path, "./", sub_matches.get_flag("save-intermediate"))?;
-            Ok(())
+    let mut config = extraction_config(sub_matches)?;
+    configure_thread_pool(&config)?;
+    if sub_matches.get_flag("save-intermediate") {
+        config.save_intermediate_images = true;
+    }
+    if let Some(preview_directory) = sub_matches.get_one::<String>("preview-dir") {
+        config.preview_directory = preview_directory.into();
+    }
+
+    let output_directory = sub_matches.get_one::<String>("out").unwrap();
+    fs::create_dir_all(output_directory).context("error creating the output directory")?;
+
+    let manifest = extract(file_path, output_directory, &config)?;
+    save_manifest(output_directory, manifest, &config)?;
+    Ok(())
+}
+
+// Processes the photos in parallel and returns the result of each of them. After the first
+// failure with fail_fast set the photos which are being processed are cancelled, the ones which
+// weren't started yet are skipped and the error is returned.
+fn extract_all(
+    paths: &[String],
+    target_directory: &str,
+    config: &ExtractionConfig,
+    fail_fast: bool,
+) -> Result<Vec<Result<Manifest>>> {
+    let extract = |file_path: &String| {
+        let result = extract(file_path, target_directory, config)
+            .with_context(|| format!("error processing {file_path}"));
+        if fail_fast && result.is_err() {
+            config.cancel.store(true, Ordering::Relaxed);
         }
-        Some(("directory", sub_matches)) => {
-            let source_directory = sub_matches.get_one::<String>("SOURCE_DIRECTORY").unwrap();
-            let target_directory = sub_matches.get_one::<String>("TARGET_DIRECTORY").unwrap();
+        result
+    };
 
-            let readdir =
-                fs::read_dir(source_directory).context("error listing the source directory")?;
-            let mut paths: Vec<String> = vec![];
-            for v in readdir {
-                paths.push(v?.path().to_string_lossy().to_string());
-            }
+    if !fail_fast {
+        return Ok(paths.par_iter().map(extract).collect());
+    }
 
-            paths.par_iter().for_each(|file_path| {
-                extract(file_path, target_directory, false).unwrap();
-            });
+    let mut results = vec![];
+    for result in paths.par_iter().map(extract).collect::<Vec<_>>() {
+        match result {
+            Err(err) if err.downcast_ref::<Cancelled>().is_none() => return Err(err),
+            result => results.push(result),
+        }
+    }
+    Ok(results)
+}
 
-            Ok(())
+// Warns about the sheets whose background has a different color cast than the backgrounds of
+// most sheets of the batch which suggests that the lighting changed.
+fn warn_about_color_casts(manifest: &Manifest) -> Result<()> {
+    let mut sheets = vec![];
+    let mut colors = vec![];
+    for sheet in &manifest.sheets {
+        if let Some(hex) = &sheet.background_hex {
+            sheets.push(sheet);
+            colors.push(Color::from_hex(hex)?);
         }
-        _ => unreachable!(),
     }
+
+    for i in find_color_cast_outliers(&colors, COLOR_CAST_WARNING_THRESHOLD) {
+        warn!(
+            "Background of sheet {} from {} has a different color cast than the rest of the batch",
+            sheets[i].name, sheets[i].source
+        );
+    }
+
+    Ok(())
 }
 
-fn extract(input_path: &str, output_directory: &str, save_intermediate_images: bool) -> Result<()> {
-    let mut preview = PreviewImagesSaver::new(input_path, save_intermediate_images)?;
-    let transparent = &AlphaColor::new_transparent();
+fn save_manifest(
+    output_directory: &str,
+    manifest: Manifest,
+    config: &ExtractionConfig,
+) -> Result<()> {
+    if config.save_manifest {
+        info!("Writing manifest...");
+        manifest.save(Path::new(output_directory).join(MANIFEST_FILE_NAME))?;
+    }
+    Ok(())
+}
+
+fn extraction_args() -> Vec<Arg> {
+    vec![
+        Arg::new("config")
+            .long("config")
+            .value_name("FILE")
+            .help("TOML or JSON file with extraction settings, command line flags take precedence"),
+        Arg::new("multiple-sheets")
+            .long("multiple-sheets")
+            .action(ArgAction::SetTrue)
+            .help("look for multiple sheets in each photo"),
+        Arg::new("sheet-aspect-ratio")
+            .long("sheet-aspect-ratio")
+            .value_name("RATIO")
+            .value_parser(clap::value_parser!(f32))
+            .help("expected width to height ratio of the sheet, used to detect sideways photos"),
+        Arg::new("markers")
+            .long("markers")
+            .value_name("COUNT")
+            .value_parser(clap::value_parser!(u32).range(2..=4))
+            .help(
+                "number of markers on the sheet, with fewer than four the alignment is approximate",
+            ),
+        Arg::new("marker-scan-steps")
+            .long("marker-scan-steps")
+            .value_name("STEPS")
+            .value_parser(clap::value_parser!(u32).range(1..50))
+            .help("how far from the corners to look for markers, in steps of 1% of the image size"),
+        Arg::new("corner-scan-steps")
+            .long("corner-scan-steps")
+            .value_name("CORNER=STEPS")
+            .action(ArgAction::Append)
+            .help("override --marker-scan-steps for a single corner e.g. top-left=45, can be repeated"),
+        Arg::new("marker-colors")
+            .long("marker-colors")
+            .value_name("COLORS")
+            .value_delimiter(',')
+            .value_parser(["white", "black"])
+            .help("colors of the markers tried in order, separated by commas"),
+        Arg::new("marker-template")
+            .long("marker-template")
+            .value_name("FILE")
+            .help("image of a marker used to find the markers if they can't be found by their color"),
+        Arg::new("marker-positions")
+            .long("marker-positions")
+            .value_name("X,Y,X,Y,X,Y,X,Y,SIZE")
+            .help("use markers at these positions, top left, top right, bottom left and bottom right, instead of looking for them"),
+        Arg::new("marker-order")
+            .long("marker-order")
+            .value_name("POLICY")
+            .value_parser(["strict", "auto-correct", "best-effort"])
+            .help("what to do if the markers aren't in the expected positions relative to each other"),
+        Arg::new("highlight-ceiling")
+            .long("highlight-ceiling")
+            .value_name("VALUE")
+            .value_parser(clap::value_parser!(u8))
+            .help("ignore large marker-like regions whose channels are all at least this bright, e.g. glare"),
+        Arg::new("roi")
+            .long("roi")
+            .value_name("LEFT,TOP,WIDTH,HEIGHT")
+            .help("process only this region of the photo"),
+        Arg::new("invert")
+            .long("invert")
+            .action(ArgAction::SetTrue)
+            .overrides_with("auto-invert")
+            .help("invert the colors of the photo e.g. for negative scans"),
+        Arg::new("auto-invert")
+            .long("auto-invert")
+            .action(ArgAction::SetTrue)
+            .overrides_with("invert")
+            .help("invert the colors of the photo if the markers are only found after inverting it"),
+        Arg::new("min-sharpness")
+            .long("min-sharpness")
+            .value_name("VARIANCE")
+            .value_parser(clap::value_parser!(f32))
+            .help("reject photos whose variance of the Laplacian is below this value"),
+        Arg::new("manifest")
+            .long("manifest")
+            .action(ArgAction::SetTrue)
+            .help("write a JSON manifest describing the extracted stickers"),
+        Arg::new("manifest-background-samples")
+            .long("manifest-background-samples")
+            .action(ArgAction::SetTrue)
+            .help("include the areas used to sample the background in the manifest"),
+        Arg::new("correct-exposure")
+            .long("correct-exposure")
+            .action(ArgAction::SetTrue)
+            .help("even out the lighting using the brightness of the four markers"),
+        Arg::new("adaptive-thresholds")
+            .long("adaptive-thresholds")
+            .action(ArgAction::SetTrue)
+            .help("loosen the background thresholds for noisy photos and tighten them for clean ones"),
+        Arg::new("remove-shadows")
+            .long("remove-shadows")
+            .action(ArgAction::SetTrue)
+            .help("remove shadows cast by the stickers onto the sheet"),
+        Arg::new("check-residual-background")
+            .long("check-residual-background")
+            .action(ArgAction::SetTrue)
+            .help("warn if a lot of the sheet still looks like the background after removing it"),
+        Arg::new("fill-holes")
+            .long("fill-holes")
+            .value_name("PIXELS")
+            .value_parser(clap::value_parser!(usize))
+            .help("restore transparent holes of at most this many pixels inside stickers"),
+        Arg::new("numbered")
+            .long("numbered")
+            .action(ArgAction::SetTrue)
+            .help("name the stickers using their position in reading order"),
+        Arg::new("archive")
+            .long("archive")
+            .action(ArgAction::SetTrue)
+            .help("write the stickers of each photo and their manifest into a zip archive"),
+        Arg::new("outlines")
+            .long("outlines")
+            .action(ArgAction::SetTrue)
+            .help("write the outline of each sticker as an SVG file next to it"),
+        Arg::new("thumbnails")
+            .long("thumbnails")
+            .value_name("SIZE")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("also write a thumbnail of each sticker whose longer side is at most this many pixels"),
+        Arg::new("full")
+            .long("full")
+            .action(ArgAction::SetTrue)
+            .help("also write the entire sheet with corrected perspective and removed background"),
+        Arg::new("background-image")
+            .long("background-image")
+            .action(ArgAction::SetTrue)
+            .help("also write the pixels which were removed as the background"),
+        Arg::new("deterministic")
+            .long("deterministic")
+            .action(ArgAction::SetTrue)
+            .help("use a single thread so that repeated runs produce identical output"),
+        Arg::new("marker-image")
+            .long("marker-image")
+            .action(ArgAction::SetTrue)
+            .help("also write the uncropped sheet with the markers colored red"),
+        Arg::new("tile-size")
+            .long("tile-size")
+            .value_name("PIXELS")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .help("process very large images in tiles of this size to limit memory usage"),
+        Arg::new("mirror-background")
+            .long("mirror-background")
+            .action(ArgAction::SetTrue)
+            .help("average background samples from opposite edges, useful with symmetric lighting"),
+        Arg::new("lab-background")
+            .long("lab-background")
+            .action(ArgAction::SetTrue)
+            .help("interpolate the background in LAB, the space in which it's compared with the photo"),
+        Arg::new("gaussian-background")
+            .long("gaussian-background")
+            .value_name("SIGMA")
+            .value_parser(clap::value_parser!(f32))
+            .help("weight background samples using a gaussian with this width relative to the image size, e.g. 0.2"),
+        Arg::new("corner-background")
+            .long("corner-background")
+            .value_name("PERCENTILE")
+            .value_parser(clap::value_parser!(f32))
+            .help("use the background sample next to the markers with this luminance percentile, e.g. 0.25, for the entire sheet"),
+        Arg::new("chroma-key")
+            .long("chroma-key")
+            .value_name("COLOR")
+            .help("remove pixels similar in hue to this #rrggbb color instead of analysing the background"),
+        Arg::new("sheet-size")
+            .long("sheet-size")
+            .value_name("WIDTHxHEIGHT")
+            .help("distance between the marker centers in millimeters, e.g. 190x277"),
+        Arg::new("connectivity")
+            .long("connectivity")
+            .value_name("NEIGHBOURS")
+            .value_parser(clap::value_parser!(u8))
+            .help("4 or 8, with 8 diagonally touching pixels belong to the same sticker"),
+        Arg::new("sticker-alpha-threshold")
+            .long("sticker-alpha-threshold")
+            .value_name("ALPHA")
+            .value_parser(clap::value_parser!(u8).range(1..))
+            .help("ignore pixels with lower alpha when looking for stickers"),
+        Arg::new("split-touching")
+            .long("split-touching")
+            .action(ArgAction::SetTrue)
+            .help("separate touching stickers which are pinched where they meet"),
+        Arg::new("grid")
+            .long("grid")
+            .value_name("COLUMNSxROWS")
+            .help("expected shape of the sticker grid, empty cells are reported instead of renumbering"),
+        Arg::new("delta-e")
+            .long("delta-e")
+            .value_name("THRESHOLD")
+            .value_parser(clap::value_parser!(f32))
+            .help("remove pixels within this CIEDE2000 difference of the background color"),
+        Arg::new("lab-distance")
+            .long("lab-distance")
+            .value_name("THRESHOLD")
+            .value_parser(clap::value_parser!(f32))
+            .conflicts_with("delta-e")
+            .help("remove pixels within this LAB distance of the background color"),
+        Arg::new("virtual-pixel")
+            .long("virtual-pixel")
+            .value_name("FILL")
+            .help("fill for areas outside of the photo: transparent, edge or a #rrggbb color"),
+        Arg::new("perspective-margin")
+            .long("perspective-margin")
+            .value_name("PIXELS")
+            .value_parser(clap::value_parser!(u32))
+            .help("margin added around the sheet when correcting the perspective"),
+        Arg::new("chroma-exemption")
+            .long("chroma-exemption")
+            .value_name("CHROMA")
+            .value_parser(clap::value_parser!(f32))
+            .help("keep pixels whose LAB chroma exceeds the background chroma by this much"),
+        Arg::new("trim-rotated")
+            .long("trim-rotated")
+            .action(ArgAction::SetTrue)
+            .help("crop each sticker to its smallest rotated bounding box and straighten it"),
+        Arg::new("trim-rotation")
+            .long("trim-rotation")
+            .value_name("METHOD")
+            .value_parser(["min-area-rect", "principal-axis"])
+            .help("how the rotation of trimmed stickers is determined, implies --trim-rotated"),
+        Arg::new("format")
+            .long("format")
+            .value_name("FORMAT")
+            .value_parser(["png", "jpeg", "webp"])
+            .help("format in which the stickers are written"),
+        Arg::new("quality")
+            .long("quality")
+            .value_name("QUALITY")
+            .value_parser(clap::value_parser!(u8).range(1..=100))
+            .help("quality of JPEG from 1 to 100, defaults to 90, can't be used with WebP which is always lossless"),
+        Arg::new("indexed-png")
+            .long("indexed-png")
+            .value_name("COLORS")
+            .value_parser(clap::value_parser!(u16).range(1..=256))
+            .help("write PNG stickers with at most this many colors using an indexed palette"),
+        Arg::new("resample-filter")
+            .long("resample-filter")
+            .value_name("FILTER")
+            .value_parser(["nearest", "bilinear", "lanczos"])
+            .help("filter used when correcting the perspective and resizing"),
+    ]
+}
+
+// Rayon uses as many threads as there are CPUs unless the processing must be deterministic. This
+// has to be called before anything runs in the thread pool.
+fn configure_thread_pool(config: &ExtractionConfig) -> Result<()> {
+    if config.deterministic {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build_global()
+            .context("error configuring the thread pool")?;
+    }
+    Ok(())
+}
+
+fn extraction_config(matches: &ArgMatches) -> Result<ExtractionConfig> {
+    let mut config = match matches.get_one::<String>("config") {
+        Some(path) => ExtractionConfig::from_path(path)?,
+        None => ExtractionConfig::default(),
+    };
+
+    if matches.get_flag("multiple-sheets") {
+        config.multiple_sheets = true;
+    }
+
+    if let Some(marker_count) = matches.get_one::<u32>("markers") {
+        config.marker_count = *marker_count as usize;
+    }
+
+    if let Some(scan_steps) = matches.get_one::<u32>("marker-scan-steps") {
+        config.marker_scan_steps = *scan_steps;
+    }
+
+    if let Some(corner_scan_steps) = matches.get_many::<String>("corner-scan-steps") {
+        for corner_scan_steps in corner_scan_steps {
+            config.corner_scan_steps.set(corner_scan_steps)?;
+        }
+    }
+
+    if let Some(marker_colors) = matches.get_many::<String>("marker-colors") {
+        config.marker_colors = marker_colors
+            .map(|v| v.parse())
+            .collect::<Result<Vec<_>>>()?;
+    }
 
+    if let Some(marker_template) = matches.get_one::<String>("marker-template") {
+        config.marker_template = Some(marker_template.into());
+    }
+
+    if let Some(marker_positions) = matches.get_one::<String>("marker-positions") {
+        config.marker_positions = Some(marker_positions.parse()?);
+    }
+
+    if let Some(marker_order) = matches.get_one::<String>("marker-order") {
+        config.marker_order = marker_order.parse()?;
+    }
+
+    if let Some(highlight_ceiling) = matches.get_one::<u8>("highlight-ceiling") {
+        config.highlight_ceiling = *highlight_ceiling;
+    }
+
+    if let Some(roi) = matches.get_one::<String>("roi") {
+        config.region_of_interest = Some(roi.parse()?);
+    }
+
+    if matches.get_flag("invert") {
+        config.invert_colors = InvertColors::Always;
+    }
+
+    if matches.get_flag("auto-invert") {
+        config.invert_colors = InvertColors::Auto;
+    }
+
+    if let Some(min_sharpness) = matches.get_one::<f32>("min-sharpness") {
+        config.min_sharpness = Some(*min_sharpness);
+    }
+
+    if matches.get_flag("manifest") {
+        config.save_manifest = true;
+    }
+
+    if matches.get_flag("manifest-background-samples") {
+        config.save_background_samples = true;
+    }
+
+    if matches.get_flag("correct-exposure") {
+        config.correct_exposure = true;
+    }
+
+    if matches.get_flag("remove-shadows") {
+        config.remove_shadows = true;
+    }
+
+    if matches.get_flag("check-residual-background") {
+        config.check_residual_background = true;
+    }
+
+    if matches.get_flag("adaptive-thresholds") {
+        config.noise_adaptive_thresholds = true;
+    }
+
+    if let Some(max_hole_size) = matches.get_one::<usize>("fill-holes") {
+        config.max_hole_size = Some(*max_hole_size);
+    }
+
+    if matches.get_flag("numbered") {
+        config.numbered_file_names = true;
+    }
+
+    if matches.get_flag("archive") {
+        config.archive = true;
+    }
+
+    if matches.get_flag("outlines") {
+        config.save_outlines = true;
+    }
+
+    if let Some(thumbnail_size) = matches.get_one::<u32>("thumbnails") {
+        config.thumbnail_size = Some(*thumbnail_size);
+    }
+
+    if matches.get_flag("full") {
+        config.save_full_image = true;
+    }
+
+    if matches.get_flag("background-image") {
+        config.save_background_image = true;
+    }
+
+    if matches.get_flag("deterministic") {
+        config.deterministic = true;
+    }
+
+    if matches.get_flag("marker-image") {
+        config.save_marker_image = true;
+    }
+
+    if matches.get_flag("mirror-background") {
+        config.mirror_background_samples = true;
+    }
+
+    if matches.get_flag("lab-background") {
+        config.background_interpolation = MixSpace::Lab;
+    }
+
+    if let Some(sigma) = matches.get_one::<f32>("gaussian-background") {
+        config.background_weighting = BackgroundWeighting::Gaussian { sigma: *sigma };
+    }
+
+    if let Some(percentile) = matches.get_one::<f32>("corner-background") {
+        config.background_reference = BackgroundReference::Corners {
+            percentile: *percentile,
+        };
+    }
+
+    if let Some(color) = matches.get_one::<String>("chroma-key") {
+        Color::from_hex(color)?;
+        let chroma_key = config.chroma_key.take().unwrap_or_default();
+        config.chroma_key = Some(ChromaKey {
+            color: color.clone(),
+            ..chroma_key
+        });
+    }
+
+    if let Some(sheet_size) = matches.get_one::<String>("sheet-size") {
+        config.sheet_size = Some(sheet_size.parse()?);
+    }
+
+    if let Some(connectivity) = matches.get_one::<u8>("connectivity") {
+        config.sticker_connectivity = (*connectivity).try_into()?;
+    }
+
+    if let Some(threshold) = matches.get_one::<u8>("sticker-alpha-threshold") {
+        config.sticker_alpha_threshold = *threshold;
+    }
+
+    if matches.get_flag("split-touching") {
+        config.split_touching_stickers = true;
+    }
+
+    if let Some(grid) = matches.get_one::<String>("grid") {
+        config.grid = Some(grid.parse()?);
+    }
+
+    if let Some(threshold) = matches.get_one::<f32>("delta-e") {
+        config.delta_e_threshold = Some(*threshold);
+        config.lab_distance_threshold = None;
+    }
+
+    if let Some(threshold) = matches.get_one::<f32>("lab-distance") {
+        config.lab_distance_threshold = Some(*threshold);
+        config.delta_e_threshold = None;
+    }
+
+    if let Some(virtual_pixel) = matches.get_one::<String>("virtual-pixel") {
+        config.virtual_pixel = virtual_pixel.parse()?;
+    }
+
+    if let Some(perspective_margin) = matches.get_one::<u32>("perspective-margin") {
+        config.perspective_margin = *perspective_margin;
+    }
+
+    if let Some(chroma_exemption) = matches.get_one::<f32>("chroma-exemption") {
+        config.chroma_exemption = Some(*chroma_exemption);
+    }
+
+    if matches.get_flag("trim-rotated") {
+        config.trim_rotated = true;
+    }
+
+    if let Some(trim_rotation) = matches.get_one::<String>("trim-rotation") {
+        config.trim_rotated = true;
+        config.trim_rotation = trim_rotation.parse()?;
+    }
+
+    if let Some(output_format) = matches.get_one::<String>("format") {
+        config.output_format = output_format.parse()?;
+    }
+
+    if let Some(quality) = matches.get_one::<u8>("quality") {
+        config.quality = Some(*quality);
+    }
+
+    if let Some(max_colors) = matches.get_one::<u16>("indexed-png") {
+        config.indexed_png_max_colors = Some(*max_colors as usize);
+    }
+
+    if let Some(resample_filter) = matches.get_one::<String>("resample-filter") {
+        config.resample_filter = Some(resample_filter.parse()?);
+    }
+
+    if let Some(sheet_aspect_ratio) = matches.get_one::<f32>("sheet-aspect-ratio") {
+        config.sheet_aspect_ratio = Some(*sheet_aspect_ratio);
+    }
+
+    if let Some(tile_size) = matches.get_one::<u32>("tile-size") {
+        config.tile_size = Some(*tile_size);
+    }
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "extract", skip_all, fields(input_path = %input_path))
+)]
+fn extract(
+    input_path: &str,
+    output_directory: &str,
+    config: &ExtractionConfig,
+) -> Result<Manifest> {
+    let (img, sheets) = open_photo(input_path, config)?;
+    check_cancelled(&config.cancel)?;
+
+    let path = Path::new(&input_path);
+    let file_stem = path.file_stem().unwrap().to_str().unwrap();
+
+    let sheets = match sheets {
+        Some(sheets) => sheets,
+        None => {
+            info!("Locating markers...");
+            find_markers(&img, config)?
+        }
+    };
+    check_cancelled(&config.cancel)?;
+
+    if let Some(min_sharpness) = config.min_sharpness {
+        for markers in &sheets {
+            let sharpness = sharpness(&img, markers);
+            if sharpness < min_sharpness {
+                return Err(anyhow!(
+                    "photo is too blurry, sharpness {sharpness:.1} is below {min_sharpness:.1}"
+                ));
+            }
+        }
+    }
+
+    let mut output = StickerOutput::new(output_directory, file_stem, config)?;
+    let mut manifest = Manifest::default();
+    if config.multiple_sheets {
+        for (i, markers) in sheets.iter().enumerate() {
+            info!("Processing sheet {i}...");
+            let name = format!("{file_stem}_sheet{i}");
+            let sheet = SheetSource::new(input_path, &name);
+            manifest.extend(extract_sheet(
+                img.clone(),
+                markers,
+                &sheet,
+                output_directory,
+                &mut output,
+                config,
+            )?);
+        }
+    } else {
+        let sheet = SheetSource::new(input_path, file_stem);
+        manifest.extend(extract_sheet(
+            img,
+            &sheets[0],
+            &sheet,
+            output_directory,
+            &mut output,
+            config,
+        )?);
+    }
+
+    output.finish(&manifest)?;
+    Ok(manifest)
+}
+
+// Opens the photo and crops it to the region of interest if one was specified. If the markers
+// had to be located to decide whether to invert the colors they are returned as well.
+fn open_photo(
+    input_path: &str,
+    config: &ExtractionConfig,
+) -> Result<(ImageWrapper, Option<Vec<Markers>>)> {
     info!("Opening image {input_path}...");
     let img = ImageReader::open(input_path)?.decode()?.to_rgba8();
     let mut img = ImageWrapper::new(img);
 
-    info!("Locating markers...");
-    let markers = Markers::find(&img)?;
+    if let Some(roi) = &config.region_of_interest {
+        if roi.left.saturating_add(roi.width) > img.width()
+            || roi.top.saturating_add(roi.height) > img.height()
+        {
+            return Err(anyhow!(
+                "region of interest {}x{} at {},{} is outside of the {}x{} photo",
+                roi.width,
+                roi.height,
+                roi.left,
+                roi.top,
+                img.width(),
+                img.height()
+            ));
+        }
+        img = img.crop(roi.left, roi.top, roi.width, roi.height);
+    }
+
+    let mut sheets = None;
+    match config.invert_colors {
+        InvertColors::Never => {}
+        InvertColors::Always => imageops::invert(&mut img.img),
+        InvertColors::Auto => {
+            info!("Locating markers...");
+            match find_markers(&img, config) {
+                Ok(found) => sheets = Some(found),
+                Err(err) => {
+                    let mut inverted = img.clone();
+                    imageops::invert(&mut inverted.img);
+                    if let Ok(found) = find_markers(&inverted, config) {
+                        info!(
+                            "Markers found only after inverting the colors, the photo is a negative: {err}"
+                        );
+                        img = inverted;
+                        sheets = Some(found);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((img, sheets))
+}
+
+// Exports the stickers found in the photo during a previous run again, the markers and the
+// positions of the stickers are taken from the manifest instead of looking for them.
+fn reexport(
+    input_path: &str,
+    manifest: &Manifest,
+    output_directory: &str,
+    config: &ExtractionConfig,
+) -> Result<Manifest> {
+    let file_name = Path::new(input_path).file_name();
+    let sheets: Vec<&ManifestSheet> = manifest
+        .sheets
+        .iter()
+        .filter(|v| Path::new(&v.source).file_name() == file_name)
+        .collect();
+
+    if sheets.is_empty() {
+        return Err(anyhow!(
+            "manifest doesn't describe any sheets in {input_path}"
+        ));
+    }
+
+    let (img, _) = open_photo(input_path, config)?;
+
+    let file_stem = Path::new(input_path).file_stem().unwrap().to_str().unwrap();
+    let mut output = StickerOutput::new(output_directory, file_stem, config)?;
+
+    let mut result = Manifest::default();
+    for manifest_sheet in sheets {
+        info!("Processing sheet {}...", manifest_sheet.name);
+        let markers = markers_from_manifest(manifest_sheet)?;
+        let sheet = SheetSource::new(input_path, &manifest_sheet.name);
+
+        let stickers: Vec<IdentifiedSticker> = manifest
+            .stickers
+            .iter()
+            .filter(|v| v.sheet == manifest_sheet.name)
+            .map(|v| IdentifiedSticker {
+                area: Area::new(v.left, v.top, v.width, v.height),
+                column: v.column,
+                row: v.row,
+                index: v.index,
+            })
+            .collect();
+
+        let mut preview = PreviewImagesSaver::new(&sheet.name, config)?;
+        let image = PreparedImage::new(img.img.clone(), &markers, config, |img, name| {
+            preview.save_image(img, name)
+        })?;
+        check_cancelled(&config.cancel)?;
+        let prepared = prepare_sheet(
+            &image,
+            config.save_background_image.then_some(&img.img),
+            &sheet,
+            output_directory,
+            config,
+            &mut preview,
+        )?;
+
+        for sticker in &stickers {
+            if sticker.area.left() + sticker.area.width() > prepared.img.width()
+                || sticker.area.top() + sticker.area.height() > prepared.img.height()
+            {
+                return Err(anyhow!(
+                    "sticker {}_{}_{} is outside of the sheet",
+                    sheet.name,
+                    sticker.column,
+                    sticker.row
+                ));
+            }
+        }
+
+        let stickers = export_stickers(&prepared, &stickers, &sheet, &mut output, config)?;
+        result.extend(Manifest {
+            stickers,
+            sheets: vec![manifest_sheet.clone()],
+            background_samples: prepared.background_samples,
+        });
+    }
+
+    output.finish(&result)?;
+
+    Ok(result)
+}
+
+fn markers_from_manifest(sheet: &ManifestSheet) -> Result<Markers> {
+    let markers: Vec<Marker> = sheet
+        .markers
+        .iter()
+        .map(|v| {
+            Marker::new(
+                Area::new(v.left, v.top, v.width, v.height),
+                PreciseXY::new(v.x, v.y),
+                v.estimated,
+            )
+        })
+        .collect();
+
+    match <[Marker; 4]>::try_from(markers) {
+        Ok([top_left, top_right, bottom_left, bottom_right]) => {
+            Markers::new(top_left, top_right, bottom_left, bottom_right)
+        }
+        Err(_) => Err(anyhow!("sheet {} must have four markers", sheet.name)),
+    }
+}
+
+fn markers_from_positions(positions: &MarkerPositions, img: &ImageWrapper) -> Result<Markers> {
+    let centers = [
+        positions.top_left,
+        positions.top_right,
+        positions.bottom_left,
+        positions.bottom_right,
+    ];
+
+    for [x, y] in centers {
+        if x < 0.0 || y < 0.0 || x >= img.width() as f32 || y >= img.height() as f32 {
+            return Err(anyhow!("marker position {x},{y} is outside of the photo"));
+        }
+    }
+
+    // The areas are kept inside of the photo as the background is sampled around them.
+    let [top_left, top_right, bottom_left, bottom_right] = centers.map(|[x, y]| {
+        let half = (positions.size / 2) as f32;
+        let left = (x - half).max(0.0) as u32;
+        let top = (y - half).max(0.0) as u32;
+        let width = cmp::min(positions.size, img.width() - left);
+        let height = cmp::min(positions.size, img.height() - top);
+        Marker::new(
+            Area::new(left, top, width, height),
+            PreciseXY::new(x, y),
+            false,
+        )
+    });
+    Markers::new(top_left, top_right, bottom_left, bottom_right)
+}
+
+// Destination of the exported stickers of a single photo. The stickers are either written as
+// separate files into the output directory or into a zip archive named after the photo which
+// also contains the manifest describing them.
+enum StickerOutput {
+    Files(PathBuf),
+    Archive(Box<ZipWriter<fs::File>>),
+}
+
+impl StickerOutput {
+    fn new(output_directory: &str, file_stem: &str, config: &ExtractionConfig) -> Result<Self> {
+        if !config.archive {
+            return Ok(StickerOutput::Files(PathBuf::from(output_directory)));
+        }
+
+        let path = Path::new(output_directory).join(format!("{file_stem}.zip"));
+        let file = fs::File::create(&path)
+            .with_context(|| format!("error creating archive {}", path.display()))?;
+        Ok(StickerOutput::Archive(Box::new(ZipWriter::new(file))))
+    }
+
+    // Writes the file at the given path relative to the output directory or the root of the
+    // archive.
+    fn write(&mut self, file: &Path, contents: &[u8]) -> Result<()> {
+        match self {
+            StickerOutput::Files(directory) => {
+                let path = directory.join(file);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, contents)?;
+            }
+            StickerOutput::Archive(writer) => {
+                let name = file
+                    .components()
+                    .map(|v| v.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                writer.start_file(name, SimpleFileOptions::default())?;
+                writer.write_all(contents)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the manifest describing the stickers of the photo to the archive.
+    fn finish(self, manifest: &Manifest) -> Result<()> {
+        if let StickerOutput::Archive(mut writer) = self {
+            writer.start_file(MANIFEST_FILE_NAME, SimpleFileOptions::default())?;
+            writer.write_all(serde_json::to_string_pretty(manifest)?.as_bytes())?;
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+// Identifies the sheet which is being processed.
+struct SheetSource {
+    input_path: String,
+    name: String,
+}
+
+impl SheetSource {
+    fn new(input_path: &str, name: &str) -> Self {
+        Self {
+            input_path: input_path.into(),
+            name: name.into(),
+        }
+    }
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "find_markers", skip_all)
+)]
+fn find_markers(img: &ImageWrapper, config: &ExtractionConfig) -> Result<Vec<Markers>> {
+    if let Some(positions) = &config.marker_positions {
+        if config.multiple_sheets {
+            return Err(anyhow!(
+                "marker positions can't be given for multiple sheets"
+            ));
+        }
+
+        info!("Using the given marker positions...");
+        return Ok(vec![markers_from_positions(positions, img)?]);
+    }
+
+    // when processing in tiles the images are too large to search them directly
+    let downscaled;
+    let (img, scale) = if config.tile_size.is_some() {
+        downscaled = img.downscale(TILED_PROCESSING_DOWNSCALED_SIZE, config.resample_filter);
+        (&downscaled.0, downscaled.1)
+    } else {
+        (img, 1.0)
+    };
+
+    let sheets = if config.multiple_sheets {
+        if config.marker_count != 4 {
+            return Err(anyhow!("looking for multiple sheets requires four markers"));
+        }
+
+        let sheets =
+            Markers::find_all_with_colors(img, &config.marker_colors, config.highlight_ceiling);
+        if sheets.is_empty() {
+            return Err(anyhow!("no sheets found"));
+        }
+        sheets
+    } else {
+        let markers = Markers::find_with_colors_and_order(
+            img,
+            &config.marker_colors,
+            config.marker_count,
+            config.marker_scan_steps,
+            &config.corner_scan_steps,
+            config.highlight_ceiling,
+            config.marker_order,
+        );
+
+        match (markers, &config.marker_template) {
+            (Ok(markers), _) => vec![markers],
+            (Err(err), Some(template_path)) => {
+                if config.marker_count != 4 {
+                    return Err(err);
+                }
+
+                warn!("Markers not found by their color, matching the marker template: {err}");
+                let template = ImageReader::open(template_path)
+                    .with_context(|| {
+                        format!("error opening marker template {}", template_path.display())
+                    })?
+                    .decode()?
+                    .to_rgba8();
+                let template = if scale == 1.0 {
+                    template
+                } else {
+                    let width = cmp::max(1, (template.width() as f32 * scale).round() as u32);
+                    let height = cmp::max(1, (template.height() as f32 * scale).round() as u32);
+                    imageops::resize(&template, width, height, FilterType::Triangle)
+                };
+
+                vec![
+                    Markers::find_with_template(img, &template, config.marker_scan_steps).map_err(
+                        |template_err| anyhow!("{err}, template matching failed: {template_err}"),
+                    )?,
+                ]
+            }
+            (Err(err), None) => return Err(err),
+        }
+    };
+
+    if scale == 1.0 {
+        return Ok(sheets);
+    }
+
+    Ok(sheets.iter().map(|v| v.scale(1.0 / scale)).collect())
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "extract_sheet", skip_all, fields(sheet = %sheet.name))
+)]
+fn extract_sheet(
+    img: ImageWrapper,
+    markers: &Markers,
+    sheet: &SheetSource,
+    output_directory: &str,
+    output: &mut StickerOutput,
+    config: &ExtractionConfig,
+) -> Result<Manifest> {
+    // Measured before the markers are painted over and the exposure is corrected.
+    let sharpness = sharpness(&img, markers);
+    let original = config.save_background_image.then(|| img.img.clone());
+
+    let mut preview = PreviewImagesSaver::new(&sheet.name, config)?;
+    let image = PreparedImage::new(img.img, markers, config, |img, name| {
+        preview.save_image(img, name)
+    })?;
+    check_cancelled(&config.cancel)?;
+    let prepared = prepare_sheet(
+        &image,
+        original.as_ref(),
+        sheet,
+        output_directory,
+        config,
+        &mut preview,
+    )?;
+
+    info!("Final crop...");
+    let stickers = IdentifiedStickers::new(&prepared.img, config);
+
+    if config.save_intermediate_images {
+        let overlay = ImageWrapper::new(stickers.render_overlay(&prepared.img.img));
+        preview.save(&overlay, "identified_stickers")?;
+    }
+
+    let grid_fit = match &config.grid {
+        Some(grid) => {
+            let grid_fit = stickers.fit_to_grid(grid.columns, grid.rows)?;
+            for (column, row) in &grid_fit.missing_cells {
+                warn!(
+                    "No sticker found in column {} row {} of the {}x{} grid",
+                    column, row, grid.columns, grid.rows
+                );
+            }
+
+            if let Some(residual_distortion) = grid_fit.residual_distortion {
+                info!(
+                    "Residual distortion after correcting the perspective is {:.1}% of a grid cell",
+                    residual_distortion * 100.0
+                );
+                if residual_distortion > RESIDUAL_DISTORTION_WARNING_THRESHOLD {
+                    warn!(
+                        "The rows and columns of the stickers aren't straight, the sheet may be bowed"
+                    );
+                }
+            }
+            Some(grid_fit)
+        }
+        None => None,
+    };
+
+    let stickers = export_stickers(
+        &prepared,
+        grid_fit
+            .as_ref()
+            .map_or(stickers.stickers(), |v| v.stickers.as_slice()),
+        sheet,
+        output,
+        config,
+    )?;
+
+    let confidence = ConfidenceMetrics::new(markers, sharpness, prepared.residual_background)
+        .confidence(&config.confidence_weights);
+    info!("Confidence of the extraction is {:.2}", confidence);
+
+    let mut manifest_sheet = manifest_sheet(markers, sheet, image.background());
+    manifest_sheet.confidence = Some(confidence);
+
+    Ok(Manifest {
+        stickers,
+        sheets: vec![manifest_sheet],
+        background_samples: prepared.background_samples,
+    })
+}
+
+// Sheet after removing the background, correcting the perspective and cleaning up.
+struct PreparedSheet {
+    img: ImageWrapper,
+
+    // Size of the image after correcting the perspective but before cropping it.
+    corrected_width: u32,
+    corrected_height: u32,
+
+    background_samples: Vec<ManifestBackgroundSample>,
+
+    // Part of the photo which was removed as the background if it should be written.
+    background_image: Option<ImageWrapper>,
+
+    // Fraction of the sheet which still looked like the background after removing it, not known
+    // if a chroma key was used.
+    residual_background: Option<f32>,
+
+    photo_mapping: PhotoMapping,
+}
+
+// Maps positions in the perspective corrected and cropped sheet back to the photo.
+struct PhotoMapping {
+    // Maps the perspective corrected image to the photo after rotating it.
+    homography: Homography,
+
+    crop_left: u32,
+    crop_top: u32,
+
+    // Height of the photo before rotating it if the sheet was sideways.
+    rotated_photo_height: Option<u32>,
+
+    // Position of the region of interest in the photo.
+    roi_left: u32,
+    roi_top: u32,
+}
+
+impl PhotoMapping {
+    fn map(&self, xy: &PreciseXY) -> PreciseXY {
+        let xy = self.homography.apply(&PreciseXY::new(
+            xy.x() + self.crop_left as f32,
+            xy.y() + self.crop_top as f32,
+        ));
+
+        let xy = match self.rotated_photo_height {
+            Some(height) => PreciseXY::new(xy.y(), (height - 1) as f32 - xy.x()),
+            None => xy,
+        };
+
+        PreciseXY::new(xy.x() + self.roi_left as f32, xy.y() + self.roi_top as f32)
+    }
+
+    // Returns the corners of the area in the photo in the following order: top left, top
+    // right, bottom left, bottom right.
+    fn map_area(&self, area: &Area) -> [PreciseXY; 4] {
+        let left = area.left() as f32;
+        let top = area.top() as f32;
+        let right = (area.left() + area.width()) as f32;
+        let bottom = (area.top() + area.height()) as f32;
+        [
+            self.map(&PreciseXY::new(left, top)),
+            self.map(&PreciseXY::new(right, top)),
+            self.map(&PreciseXY::new(left, bottom)),
+            self.map(&PreciseXY::new(right, bottom)),
+        ]
+    }
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "prepare_sheet", skip_all, fields(sheet = %sheet.name))
+)]
+fn prepare_sheet(
+    image: &PreparedImage,
+    original: Option<&RgbaImage>,
+    sheet: &SheetSource,
+    output_directory: &str,
+    config: &ExtractionConfig,
+    preview: &mut PreviewImagesSaver,
+) -> Result<PreparedSheet> {
+    let transparent = &AlphaColor::new_transparent();
+
+    let mut img = ImageWrapper::new(image.img().clone());
+    let markers = image.markers();
+
+    let background = match image.background() {
+        BackgroundModel::ChromaKey(chroma_key) => {
+            info!("Removing chroma key...");
+            remove_chroma_key(&mut img, chroma_key)?;
+            preview.save(&img, "chroma_key")?;
+            None
+        }
+        BackgroundModel::Full(background) => {
+            remove_background(&mut img, markers, background, config, preview)?;
+            Some((background, 1.0))
+        }
+        BackgroundModel::Tiled {
+            background,
+            scale,
+            tile_size,
+        } => {
+            remove_background_tiled(&mut img, markers, background, *scale, *tile_size, config)?;
+            Some((background, *scale))
+        }
+    };
+
+    let mut background_samples = vec![];
+    let mut residual_background = None;
+    if let Some((background, scale)) = &background {
+        if config.remove_shadows {
+            info!("Removing shadows...");
+            remove_shadows(&mut img, |xy: &XY| {
+                background.check_color_scaled(xy, *scale).clone()
+            });
+            preview.save(&img, "shadows_removed")?;
+        }
+
+        if config.check_residual_background {
+            let residual = background.residual(&img, markers, *scale);
+            residual_background = Some(residual);
+            if residual > RESIDUAL_BACKGROUND_WARNING_THRESHOLD {
+                warn!(
+                    "{:.0}% of the sheet still looks like the background after removing it",
+                    residual * 100.0
+                );
+            }
+        }
+
+        if config.save_background_samples {
+            background_samples = manifest_background_samples(background, *scale, sheet);
+        }
+    }
+
+    if let Some(max_hole_size) = config.max_hole_size {
+        info!("Filling holes...");
+        let filled = fill_holes(
+            &mut img,
+            image.img(),
+            max_hole_size,
+            config.sticker_connectivity,
+        );
+        info!("Filled {} pixels", filled);
+        preview.save(&img, "holes_filled")?;
+    }
+
+    // Created from the photo as it was taken in the same orientation so that it can be
+    // composited with it.
+    let background_image = match original {
+        Some(original) => {
+            let removed = match image.rotated_photo_height() {
+                Some(_) => imageops::rotate270(&img.img),
+                None => img.img.clone(),
+            };
+            Some(ImageWrapper::new(removed_background(original, &removed)?))
+        }
+        None => None,
+    };
+
+    check_cancelled(&config.cancel)?;
+
+    info!("Correcting perspective...");
+    let (sheet_width, sheet_height) = corrected_sheet_size(img.width(), img.height(), config);
+    let margin = config.perspective_margin;
+    let (left, top) = (margin as f32, margin as f32);
+    let (right, bottom) = (
+        (margin + sheet_width) as f32,
+        (margin + sheet_height) as f32,
+    );
+    let corners = [
+        PreciseXY::new(left, top),
+        PreciseXY::new(right, top),
+        PreciseXY::new(left, bottom),
+        PreciseXY::new(right, bottom),
+    ];
+    let marker_centers = [
+        markers.top_left().center().clone(),
+        markers.top_right().center().clone(),
+        markers.bottom_left().center().clone(),
+        markers.bottom_right().center().clone(),
+    ];
+    let homography = Homography::from_point_pairs(corners, marker_centers)?;
+
+    let (output_width, output_height) = (sheet_width + 2 * margin, sheet_height + 2 * margin);
+    let corrected = img.warp_perspective(
+        &homography,
+        output_width,
+        output_height,
+        &config.virtual_pixel,
+        config.resample_filter.unwrap_or(ResampleFilter::Bilinear),
+    )?;
+    check_transparency_preserved(&img, &corrected, &homography)?;
+    let mut img = corrected;
+
+    preview.save(&img, "corrected_perspective")?;
+
+    if config.save_marker_image {
+        let name = &sheet.name;
+        info!("Writing marker image...");
+        img.save(Path::new(output_directory).join(format!("{name}_markers.png")))?;
+    }
+
+    let corrected_width = img.width();
+    let corrected_height = img.height();
+
+    info!("Cropping...");
+    let width = img.width();
+    let height = img.height();
+
+    let crop = match config.initial_crop {
+        InitialCrop::Fixed { fraction } => {
+            let horizontal = (width as f32 * fraction) as u32;
+            let vertical = (height as f32 * fraction) as u32;
+            CropInsets {
+                left: horizontal,
+                top: vertical,
+                right: horizontal,
+                bottom: vertical,
+            }
+        }
+        InitialCrop::Markers { margin } => {
+            marker_crop_insets(markers, &homography.inverse()?, width, height, margin)?
+        }
+        InitialCrop::Edges { margin } => sheet_edge_crop_insets(&img, margin)?,
+    };
+
+    let crop_left = crop.left;
+    let crop_top = crop.top;
+    let mut img = img.crop(
+        crop.left,
+        crop.top,
+        width - crop.left - crop.right,
+        height - crop.top - crop.bottom,
+    );
+
+    preview.save(&img, "pre_background_cleanup_crop")?;
+    check_cancelled(&config.cancel)?;
+
+    info!("Cleaning up background...");
+    let regions = find_regions(&img, |_xy: &XY, color: &AlphaColor| !color.is_transparent());
+
+    let mut remaining_regions = 0;
+    for pixels in regions {
+        if is_at_least_this_much_of_image(pixels.len(), &img, BACKGROUND_CLEANUP_FACTOR) {
+            remaining_regions += 1;
+        } else {
+            for pixel in &pixels {
+                img.put_pixel(pixel.x(), pixel.y(), transparent);
+            }
+        }
+    }
+
+    preview.save(&img, "background_cleanup")?;
+
+    if remaining_regions == 0 {
+        return Err(EmptySheet.into());
+    }
+
+    if config.save_full_image {
+        let name = &sheet.name;
+        info!("Writing full image...");
+        img.save(Path::new(output_directory).join(format!("{name}_full.png")))?;
+    }
+
+    Ok(PreparedSheet {
+        img,
+        corrected_width,
+        corrected_height,
+        background_samples,
+        background_image,
+        residual_background,
+        photo_mapping: PhotoMapping {
+            homography,
+            crop_left,
+            crop_top,
+            rotated_photo_height: image.rotated_photo_height(),
+            roi_left: config.region_of_interest.as_ref().map_or(0, |v| v.left),
+            roi_top: config.region_of_interest.as_ref().map_or(0, |v| v.top),
+        },
+    })
+}
+
+// Returns the size of the perspective corrected sheet. If the proportions of the sheet are known
+// then it's the largest rectangle with those proportions which fits into the photo, otherwise
+// the sheet is stretched to the size of the photo.
+fn corrected_sheet_size(width: u32, height: u32, config: &ExtractionConfig) -> (u32, u32) {
+    let aspect_ratio = config
+        .sheet_aspect_ratio
+        .or_else(|| config.sheet_size.as_ref().map(|v| v.width_mm / v.height_mm));
+
+    match aspect_ratio {
+        Some(aspect_ratio) if width as f32 / aspect_ratio <= height as f32 => {
+            let height = (width as f32 / aspect_ratio).round() as u32;
+            (width, cmp::max(height, 1))
+        }
+        Some(aspect_ratio) => {
+            let width = (height as f32 * aspect_ratio).round() as u32;
+            (cmp::max(width, 1), height)
+        }
+        None => (width, height),
+    }
+}
+
+struct CropInsets {
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+}
+
+// Calculates how much has to be cropped off each side of the perspective corrected image to
+// remove the markers. The homography maps the photo to the corrected image, the corners of the
+// bounding box of each marker are mapped to find out how far the marker reaches into the
+// corrected image.
+fn marker_crop_insets(
+    markers: &Markers,
+    homography: &Homography,
+    width: u32,
+    height: u32,
+    margin: u32,
+) -> Result<CropInsets> {
+    let corners = |marker: &Marker| {
+        let area = marker.area();
+        let (left, top) = (area.left() as f32, area.top() as f32);
+        let (right, bottom) = (left + area.width() as f32, top + area.height() as f32);
+        [
+            PreciseXY::new(left, top),
+            PreciseXY::new(right, top),
+            PreciseXY::new(left, bottom),
+            PreciseXY::new(right, bottom),
+        ]
+        .map(|v| homography.apply(&v))
+    };
+
+    let (top_left, top_right) = (corners(markers.top_left()), corners(markers.top_right()));
+    let (bottom_left, bottom_right) = (
+        corners(markers.bottom_left()),
+        corners(markers.bottom_right()),
+    );
+
+    let left = top_left
+        .iter()
+        .chain(&bottom_left)
+        .map(|v| v.x())
+        .fold(0.0, f32::max);
+    let top = top_left
+        .iter()
+        .chain(&top_right)
+        .map(|v| v.y())
+        .fold(0.0, f32::max);
+    let right = top_right
+        .iter()
+        .chain(&bottom_right)
+        .map(|v| width as f32 - v.x())
+        .fold(0.0, f32::max);
+    let bottom = bottom_left
+        .iter()
+        .chain(&bottom_right)
+        .map(|v| height as f32 - v.y())
+        .fold(0.0, f32::max);
+
+    let inset = |v: f32| v.ceil() as u32 + margin;
+    let insets = CropInsets {
+        left: inset(left),
+        top: inset(top),
+        right: inset(right),
+        bottom: inset(bottom),
+    };
+
+    if insets.left + insets.right >= width || insets.top + insets.bottom >= height {
+        return Err(anyhow!("the markers cover the entire sheet"));
+    }
+
+    Ok(insets)
+}
+
+// Calculates how much has to be cropped off each side of the perspective corrected image to
+// remove everything outside of the detected edges of the sheet.
+fn sheet_edge_crop_insets(img: &ImageWrapper, margin: u32) -> Result<CropInsets> {
+    let sheet = find_sheet_edges(img)?;
+    let insets = CropInsets {
+        left: sheet.left() + margin,
+        top: sheet.top() + margin,
+        right: img.width() - sheet.left() - sheet.width() + margin,
+        bottom: img.height() - sheet.top() - sheet.height() + margin,
+    };
+
+    if insets.left + insets.right >= img.width() || insets.top + insets.bottom >= img.height() {
+        return Err(anyhow!("the margin covers the entire sheet"));
+    }
+
+    Ok(insets)
+}
+
+// Checks that the pixels of the perspective corrected image which come from regions of the photo
+// where the background was removed are still transparent. Otherwise the alpha channel was lost
+// when resampling and the background would be mistaken for the stickers. The homography maps
+// the corrected image to the photo.
+fn check_transparency_preserved(
+    photo: &ImageWrapper,
+    corrected: &ImageWrapper,
+    homography: &Homography,
+) -> Result<()> {
+    let radius = TRANSPARENCY_CHECK_RADIUS as i64;
+    let is_transparent_around = |x: i64, y: i64| {
+        (x - radius..=x + radius).all(|x| {
+            (y - radius..=y + radius).all(|y| {
+                x >= 0
+                    && y >= 0
+                    && photo
+                        .try_get_pixel(x as u32, y as u32)
+                        .is_some_and(|v| v.is_transparent())
+            })
+        })
+    };
+
+    let mut checked = 0;
+    let mut failed = 0;
+    for ix in 0..TRANSPARENCY_CHECK_STEPS {
+        for iy in 0..TRANSPARENCY_CHECK_STEPS {
+            let x = corrected.width() * ix / TRANSPARENCY_CHECK_STEPS;
+            let y = corrected.height() * iy / TRANSPARENCY_CHECK_STEPS;
+
+            let source = homography.apply(&PreciseXY::new(x as f32, y as f32));
+            if !is_transparent_around(source.x().round() as i64, source.y().round() as i64) {
+                continue;
+            }
+
+            checked += 1;
+            if !corrected.get_pixel(x, y).is_transparent() {
+                failed += 1;
+            }
+        }
+    }
+
+    if checked > 0 && failed as f32 / checked as f32 > TRANSPARENCY_CHECK_MAX_FAILURES {
+        return Err(anyhow!(
+            "transparency was lost when correcting the perspective, {failed} out of {checked} checked pixels which should be transparent aren't"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "export_stickers",
+        skip_all,
+        fields(sheet = %sheet.name, stickers = stickers.len())
+    )
+)]
+fn export_stickers(
+    prepared: &PreparedSheet,
+    stickers: &[IdentifiedSticker],
+    sheet: &SheetSource,
+    output: &mut StickerOutput,
+    config: &ExtractionConfig,
+) -> Result<Vec<ManifestSticker>> {
+    let name = &sheet.name;
+    let mut img = prepared.img.clone();
+
+    let targets = if config.output_targets.is_empty() {
+        vec![OutputTarget {
+            format: config.output_format,
+            directory: PathBuf::new(),
+        }]
+    } else {
+        config.output_targets.clone()
+    };
+
+    if let Some(background_image) = &prepared.background_image {
+        info!("Writing background image...");
+        let file = targets[0].directory.join(format!("{name}_background.png"));
+        output.write(&file, &background_image.encode(OutputFormat::Png, config)?)?;
+    }
+
+    let mut manifest_stickers = vec![];
+    for sticker in stickers {
+        check_cancelled(&config.cancel)?;
+        let img = img.crop(
+            sticker.area.left(),
+            sticker.area.top(),
+            sticker.area.width(),
+            sticker.area.height(),
+        );
+
+        let img = if config.trim_rotated
+            && let Some(rect) = match config.trim_rotation {
+                TrimRotation::MinAreaRect => min_area_rect(&img),
+                TrimRotation::PrincipalAxis => principal_axis_rect(&img),
+            } {
+            img.extract_rotated(&rect)
+        } else {
+            img
+        };
+
+        let stem = if config.numbered_file_names {
+            format!("{}_{}", name, sticker.index)
+        } else {
+            format!("{}_{}_{}", name, sticker.column, sticker.row)
+        };
+
+        // The manifest points to the file written for the first target.
+        let mut files = vec![];
+        for target in &targets {
+            let file = target
+                .directory
+                .join(format!("{}.{}", stem, target.format.extension()));
+
+            info!("Writing final image...");
+            output.write(&file, &img.encode(target.format, config)?)?;
+            files.push(file);
+        }
+        let file = files[0].to_string_lossy().into_owned();
+
+        if config.save_outlines {
+            let outlines = trace_outlines(&img, config.sticker_alpha_threshold);
+            let svg = outlines_svg(img.width(), img.height(), &outlines);
+            output.write(&files[0].with_extension("svg"), svg.as_bytes())?;
+        }
+
+        if let Some(thumbnail_size) = config.thumbnail_size {
+            // Unless a filter was chosen a high quality one is used as the stickers are usually
+            // downscaled a lot.
+            let filter = config.resample_filter.unwrap_or(ResampleFilter::Lanczos);
+            let thumbnail = img.thumbnail(thumbnail_size, filter);
+            let file = targets[0].directory.join(format!("{stem}_thumb.png"));
+            output.write(&file, &thumbnail.encode(OutputFormat::Png, config)?)?;
+        }
+
+        if config.save_manifest || config.archive {
+            let dominant_color = dominant_color(&img).ok_or(anyhow!("sticker is transparent"))?;
+            let physical_size = config.sheet_size.as_ref().map(|sheet_size| {
+                sticker.area.physical_size(
+                    prepared.corrected_width,
+                    prepared.corrected_height,
+                    sheet_size,
+                )
+            });
+            manifest_stickers.push(ManifestSticker {
+                file,
+                source: sheet.input_path.clone(),
+                sheet: name.clone(),
+                left: sticker.area.left(),
+                top: sticker.area.top(),
+                column: sticker.column,
+                row: sticker.row,
+                index: sticker.index,
+                dominant_hex: dominant_color.hex(),
+                width: img.width(),
+                height: img.height(),
+                opaque_pixels: img.count_opaque_pixels(),
+                width_mm: physical_size.map(|v| v.0),
+                height_mm: physical_size.map(|v| v.1),
+                photo_corners: prepared
+                    .photo_mapping
+                    .map_area(&sticker.area)
+                    .iter()
+                    .map(|v| ManifestPoint { x: v.x(), y: v.y() })
+                    .collect(),
+            });
+        }
+    }
+
+    Ok(manifest_stickers)
+}
 
-    let red: Color = RGB::new(255, 0, 0).into();
-    for marker in markers.markers() {
-        marker.color(&mut img, &red);
+// Records the markers so that the stickers can be exported again without looking for them.
+fn manifest_sheet(
+    markers: &Markers,
+    sheet: &SheetSource,
+    background: &BackgroundModel,
+) -> ManifestSheet {
+    let markers = markers
+        .markers()
+        .into_iter()
+        .map(|marker| ManifestMarker {
+            x: marker.center().x(),
+            y: marker.center().y(),
+            left: marker.area().left(),
+            top: marker.area().top(),
+            width: marker.area().width(),
+            height: marker.area().height(),
+            estimated: marker.is_estimated(),
+        })
+        .collect();
+
+    ManifestSheet {
+        name: sheet.name.clone(),
+        source: sheet.input_path.clone(),
+        markers,
+        background_hex: match background {
+            BackgroundModel::ChromaKey(_) => None,
+            BackgroundModel::Full(background) | BackgroundModel::Tiled { background, .. } => {
+                background.mean_color().map(|v| v.hex())
+            }
+        },
+        confidence: None,
     }
-    preview.save(&img, "markers")?;
+}
 
-    info!("Analysing background...");
-    let t = Timer::new("analysing background");
-    let background = Background::analyse(&img, &markers)?;
-    t.done();
+// Describes the areas used to sample the background. The areas are scaled back to the size of
+// the photo if the background was analysed using a downscaled copy.
+fn manifest_background_samples(
+    background: &Background,
+    scale: f32,
+    sheet: &SheetSource,
+) -> Vec<ManifestBackgroundSample> {
+    let mut samples: Vec<ManifestBackgroundSample> = background
+        .areas()
+        .iter()
+        .map(|(area, color)| ManifestBackgroundSample {
+            sheet: sheet.name.clone(),
+            source: sheet.input_path.clone(),
+            left: (area.left() as f32 / scale) as u32,
+            top: (area.top() as f32 / scale) as u32,
+            width: (area.width() as f32 / scale) as u32,
+            height: (area.height() as f32 / scale) as u32,
+            color_hex: color.hex(),
+        })
+        .collect();
 
-    info!("Calculating background deltas...");
-    let t = Timer::new("calculating background deltas");
-    let background_difference = BackgroundDifference::new(&img, &background)?;
-    t.done();
+    samples.sort_by_key(|v| (v.top, v.left));
+    samples
+}
 
-    if save_intermediate_images {
+fn remove_background(
+    img: &mut ImageWrapper,
+    markers: &Markers,
+    background: &Background,
+    config: &ExtractionConfig,
+    preview: &mut PreviewImagesSaver,
+) -> Result<()> {
+    let single_threshold = uses_single_threshold(config);
+
+    // The differences aren't needed to remove the background if a single threshold is used but
+    // they are still previewed.
+    let background_difference = if !single_threshold || config.save_intermediate_images {
+        info!("Calculating background deltas...");
+        let t = Timer::new("calculating background deltas");
+        let background_difference = BackgroundDifference::new(
+            img,
+            background,
+            &config.background_normalisation,
+            &config.cancel,
+        )?;
+        t.done();
+        Some(background_difference)
+    } else {
+        None
+    };
+
+    if config.save_intermediate_images
+        && let Some(background_difference) = &background_difference
+    {
         // generate background measurements preview
         let mut preview_img = img.clone();
         for x in 0..preview_img.width() {
             for y in 0..preview_img.height() {
                 let xy = XY::new(x, y);
                 let color = background.check_color(&xy);
+
+                // Colors interpolated in LAB may be outside of the sRGB gamut.
+                let color = if config.background_interpolation == MixSpace::Lab {
+                    Color::from(color.lab().clip_to_srgb())
+                } else {
+                    color.color().clone()
+                };
                 preview_img.put_pixel(x, y, &color.opaque());
             }
         }
@@ -131,10 +1970,10 @@ fn extract(input_path: &str, output_directory: &str, save_intermediate_images: b
         // color background measurement points in the preview and in the actual image
         for (area, color) in background.areas().iter() {
             area.color(&mut preview_img, color);
-            area.color(&mut img, color);
+            area.color(img, color);
         }
 
-        preview.save(&img, "markers_and_background_measurements")?;
+        preview.save(img, "markers_and_background_measurements")?;
         preview.save(&preview_img, "interpolated_background")?;
 
         let mut preview_img = img.clone();
@@ -175,209 +2014,271 @@ fn extract(input_path: &str, output_directory: &str, save_intermediate_images: b
             }
         }
         preview.save(&preview_img, "background_distance_b")?;
-    }
-
-    info!("Removing background...");
-    let pixels = flood_fill(
-        &img,
-        markers.middle_of_top_edge(),
-        |xy: &XY, _color: &AlphaColor| {
-            let difference = background_difference.get(xy);
 
-            if difference.diff_l > 0.0
-                && difference.diff_l.abs() > BACKGROUND_DETECTION_FACTOR_L_POSITIVE
-            {
-                return false;
-            }
+        let preview_img = ImageWrapper::new(background_difference.render_heatmap());
+        preview.save(&preview_img, "background_distance_heatmap")?;
+    }
 
-            if difference.diff_l < 0.0
-                && difference.diff_l.abs() > BACKGROUND_DETECTION_FACTOR_L_NEGATIVE
-            {
-                return false;
+    let threshold_scale = if single_threshold {
+        1.0
+    } else {
+        noise_threshold_scale(img, background, 1.0, config)
+    };
+
+    clear_background(img, markers, config, |xy: &XY, color: &AlphaColor| {
+        match &background_difference {
+            Some(background_difference) if !single_threshold => {
+                is_background(background_difference.get(xy), threshold_scale)
+                    && !is_chroma_exempt(config, color, &background.check_color(xy).lab())
             }
+            _ => is_single_threshold_background(config, color, &background.check_color(xy).lab()),
+        }
+    })?;
 
-            if difference.diff_a > 0.0
-                && difference.diff_a.abs() > BACKGROUND_DETECTION_FACTOR_A_POSITIVE
-            {
-                return false;
-            }
+    Ok(())
+}
 
-            if difference.diff_a < 0.0
-                && difference.diff_a.abs() > BACKGROUND_DETECTION_FACTOR_A_NEGATIVE
-            {
-                return false;
-            }
+// Makes the matching pixels connected to the middle of the top edge of the sheet transparent.
+fn clear_background<FM>(
+    img: &mut ImageWrapper,
+    markers: &Markers,
+    config: &ExtractionConfig,
+    match_color: FM,
+) -> Result<()>
+where
+    FM: Fn(&XY, &AlphaColor) -> bool,
+{
+    info!("Removing background...");
+    let pixels = flood_fill_mask(
+        img,
+        markers.middle_of_top_edge(),
+        &config.cancel,
+        match_color,
+    )?;
 
-            if difference.diff_b > 0.0
-                && difference.diff_b.abs() > BACKGROUND_DETECTION_FACTOR_B_POSITIVE
-            {
-                return false;
-            }
+    let transparent = AlphaColor::new_transparent();
+    for pixel in pixels.iter() {
+        img.put_pixel(pixel.x(), pixel.y(), &transparent);
+    }
+    Ok(())
+}
 
-            if difference.diff_b < 0.0
-                && difference.diff_b.abs() > BACKGROUND_DETECTION_FACTOR_B_NEGATIVE
-            {
-                return false;
+// Removes the background without keeping the per-pixel differences for the entire image in
+// memory. The background was analysed using a copy of the image downscaled by the given factor
+// and the differences are calculated tile by tile.
+fn remove_background_tiled(
+    img: &mut ImageWrapper,
+    markers: &Markers,
+    background: &Background,
+    scale: f32,
+    tile_size: u32,
+    config: &ExtractionConfig,
+) -> Result<()> {
+    let background_color = |xy: &XY| background.check_color_scaled(xy, scale).lab();
+
+    // The differences aren't needed to remove the background if a single threshold is used.
+    let candidates = if uses_single_threshold(config) {
+        None
+    } else {
+        let threshold_scale = noise_threshold_scale(img, background, scale, config);
+
+        info!("Calculating background deltas...");
+        let t = Timer::new("calculating background deltas");
+        let mut candidates = PixelMask::new(img.width(), img.height());
+        BackgroundDifference::for_each_tile(
+            img,
+            background_color,
+            tile_size,
+            &config.background_normalisation,
+            |difference| {
+                check_cancelled(&config.cancel)?;
+                let area = difference.area();
+                for xy in area.pixels() {
+                    if is_background(difference.get(&xy), threshold_scale) {
+                        candidates.insert(&xy);
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        t.done();
+        Some(candidates)
+    };
+
+    clear_background(
+        img,
+        markers,
+        config,
+        |xy: &XY, color: &AlphaColor| match &candidates {
+            Some(candidates) => {
+                candidates.contains(xy) && !is_chroma_exempt(config, color, &background_color(xy))
             }
-
-            true
+            None => is_single_threshold_background(config, color, &background_color(xy)),
         },
-    );
-    for pixel in pixels {
-        img.put_pixel(pixel.x(), pixel.y(), transparent);
-    }
-
-    info!("Correcting perspective...");
-    let tmp_dir = TempDir::new()?;
-    let magick_input = tmp_dir.path().join("input.png");
-    let magick_output = tmp_dir.path().join("output.png");
-
-    info!("Writing image...");
-    img.img.save(&magick_input)?;
-
-    let perspective_params = format!(
-        "{},{} {},{} {},{} {},{} {},{} {},{} {},{} {},{}",
-        markers.top_left().center().x(),
-        markers.top_left().center().y(),
-        0,
-        0,
-        markers.top_right().center().x(),
-        markers.top_right().center().y(),
-        img.width(),
-        0,
-        markers.bottom_left().center().x(),
-        markers.bottom_left().center().y(),
-        0,
-        img.height(),
-        markers.bottom_right().center().x(),
-        markers.bottom_right().center().y(),
-        img.width(),
-        img.height(),
-    );
+    )?;
 
-    Command::new("magick")
-        .arg(&magick_input)
-        .arg("-alpha")
-        .arg("set")
-        .arg("-virtual-pixel")
-        .arg("transparent")
-        .arg("-distort")
-        .arg("Perspective")
-        .arg(perspective_params)
-        .arg(&magick_output)
-        .output()?;
-
-    let img = ImageReader::open(magick_output)?.decode()?.to_rgba8();
-    let mut img = ImageWrapper::new(img);
+    Ok(())
+}
 
-    preview.save(&img, "corrected_perspective")?;
+// Pixels which are much more colorful than the background e.g. holographic parts of stickers
+// are kept even if their brightness is similar to the brightness of the background.
+fn is_chroma_exempt(config: &ExtractionConfig, color: &AlphaColor, background: &LAB) -> bool {
+    match config.chroma_exemption {
+        Some(threshold) => {
+            let background: Color = background.clone().into();
+            color.color().chroma() - background.chroma() >= threshold
+        }
+        None => false,
+    }
+}
 
-    info!("Cropping...");
-    let width = img.width();
-    let height = img.height();
+// Returns the factor by which the background detection factors are multiplied, 1 unless the
+// thresholds adapt to the noise of the photo.
+fn noise_threshold_scale(
+    img: &ImageWrapper,
+    background: &Background,
+    scale: f32,
+    config: &ExtractionConfig,
+) -> f32 {
+    if !config.noise_adaptive_thresholds {
+        return 1.0;
+    }
 
-    let mut img = img.crop(
-        (width as f32 * INITIAL_CROP_FACTOR) as u32,
-        (height as f32 * INITIAL_CROP_FACTOR) as u32,
-        (width as f32 * (1.0 - 2.0 * INITIAL_CROP_FACTOR)) as u32,
-        (height as f32 * (1.0 - 2.0 * INITIAL_CROP_FACTOR)) as u32,
+    let noise = background.noise(img, scale);
+    let threshold_scale =
+        (noise / REFERENCE_NOISE).clamp(MIN_NOISE_THRESHOLD_SCALE, MAX_NOISE_THRESHOLD_SCALE);
+    info!(
+        "Noise of the background is {:.2}, scaling the thresholds by {:.2}",
+        noise, threshold_scale
     );
+    threshold_scale
+}
 
-    preview.save(&img, "pre_background_cleanup_crop")?;
-
-    info!("Cleaning up background...");
-    let mut skip: HashSet<XY> = HashSet::new();
-
-    for ix in 0..img.width() {
-        for iy in 0..img.height() {
-            let xy = XY::new(ix, iy);
-
-            if skip.contains(&xy) {
-                continue;
-            }
-
-            let color = img.get_pixel(xy.x(), xy.y());
-            if color.is_transparent() {
-                continue;
-            }
-
-            let pixels = flood_fill(&img, xy, |xy: &XY, _color: &AlphaColor| {
-                let color = img.get_pixel(xy.x(), xy.y());
-                !color.is_transparent()
-            });
+// Pixels are compared with the background color using a single threshold instead of checking
+// the differences of each LAB channel if either the delta E or the LAB distance threshold is set.
+fn uses_single_threshold(config: &ExtractionConfig) -> bool {
+    config.delta_e_threshold.is_some() || config.lab_distance_threshold.is_some()
+}
 
-            if !is_at_least_this_much_of_image(pixels.len(), &img, BACKGROUND_CLEANUP_FACTOR) {
-                for pixel in &pixels {
-                    img.put_pixel(pixel.x(), pixel.y(), transparent);
-                }
-            }
+fn is_single_threshold_background(
+    config: &ExtractionConfig,
+    color: &AlphaColor,
+    background: &LAB,
+) -> bool {
+    let lab = color.color().lab();
+    let is_within_threshold = match (config.delta_e_threshold, config.lab_distance_threshold) {
+        (Some(threshold), _) => lab.delta_e_2000(background) <= threshold,
+        (None, Some(threshold)) => lab.distance(background) <= threshold,
+        (None, None) => false,
+    };
+
+    is_within_threshold && !is_chroma_exempt(config, color, background)
+}
 
-            for pixel in &pixels {
-                skip.insert(pixel.clone());
-            }
-        }
+fn is_background(difference: &NormalisedBackgroundDifference, threshold_scale: f32) -> bool {
+    if difference.diff_l > 0.0
+        && difference.diff_l.abs() > BACKGROUND_DETECTION_FACTOR_L_POSITIVE * threshold_scale
+    {
+        return false;
     }
 
-    preview.save(&img, "background_cleanup")?;
+    if difference.diff_l < 0.0
+        && difference.diff_l.abs() > BACKGROUND_DETECTION_FACTOR_L_NEGATIVE * threshold_scale
+    {
+        return false;
+    }
 
-    info!("Final crop...");
-    let path = Path::new(&input_path);
-    let file_stem = path.file_stem().unwrap();
+    if difference.diff_a > 0.0
+        && difference.diff_a.abs() > BACKGROUND_DETECTION_FACTOR_A_POSITIVE * threshold_scale
+    {
+        return false;
+    }
 
-    let stickers = IdentifiedStickers::new(&img);
-    for sticker in stickers.stickers() {
-        let img = img.crop(
-            sticker.area.left(),
-            sticker.area.top(),
-            sticker.area.width(),
-            sticker.area.height(),
-        );
+    if difference.diff_a < 0.0
+        && difference.diff_a.abs() > BACKGROUND_DETECTION_FACTOR_A_NEGATIVE * threshold_scale
+    {
+        return false;
+    }
 
-        let output_path = Path::new(output_directory).join(format!(
-            "{}_{}_{}.png",
-            file_stem.to_str().unwrap(),
-            sticker.column,
-            sticker.row
-        ));
+    if difference.diff_b > 0.0
+        && difference.diff_b.abs() > BACKGROUND_DETECTION_FACTOR_B_POSITIVE * threshold_scale
+    {
+        return false;
+    }
 
-        info!("Writing final image...");
-        img.save(output_path)?;
+    if difference.diff_b < 0.0
+        && difference.diff_b.abs() > BACKGROUND_DETECTION_FACTOR_B_NEGATIVE * threshold_scale
+    {
+        return false;
     }
 
-    Ok(())
+    true
 }
 
 struct PreviewImagesSaver {
     stem: String,
     save_intermediate_images: bool,
+    directory: PathBuf,
     stage_number: u32,
 }
 
 impl PreviewImagesSaver {
-    fn new(input_path: impl Into<String>, save_intermediate_images: bool) -> Result<Self> {
-        let input_path: String = input_path.into();
-        let path = Path::new(&input_path);
-        let stem = path.file_stem().unwrap();
+    fn new(stem: impl Into<String>, config: &ExtractionConfig) -> Result<Self> {
+        if config.save_intermediate_images {
+            fs::create_dir_all(&config.preview_directory)
+                .context("error creating the preview directory")?;
+        }
+
         Ok(Self {
-            stem: stem.to_str().unwrap().into(),
-            save_intermediate_images,
+            stem: stem.into(),
+            save_intermediate_images: config.save_intermediate_images,
+            directory: config.preview_directory.clone(),
             stage_number: 0,
         })
     }
 
     fn save(&mut self, img: &ImageWrapper, name: &str) -> Result<()> {
+        self.save_image(&img.img, name)
+    }
+
+    fn save_image(&mut self, img: &RgbaImage, name: &str) -> Result<()> {
         if self.save_intermediate_images {
             info!("Writing preview image...");
-            img.img.save(format!(
+            img.save(self.directory.join(format!(
                 "{}_stage{}_{}.png",
                 self.stem, self.stage_number, name
-            ))?;
+            )))?;
             self.stage_number += 1;
         }
         Ok(())
     }
 }
 
+// Returns the channels of the pixel with the color multiplied by alpha so that it can be
+// interpolated without the transparent pixels darkening the result, all channels are in
+// [0, 255].
+fn premultiply(pixel: &Rgba<u8>) -> [f32; 4] {
+    let [r, g, b, a] = pixel.0;
+    let rgb = AlphaColor::new(RGB::new(r, g, b).into(), a).premultiplied();
+    [rgb.r() as f32, rgb.g() as f32, rgb.b() as f32, a as f32]
+}
+
+// Reverses premultiply. Pixels whose alpha rounds to 0 are transparent black.
+fn unpremultiply(channels: [f32; 4]) -> Rgba<u8> {
+    let alpha = channels[3];
+    if alpha < 0.5 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let unpremultiply = |v: f32| (v * 255.0 / alpha).round().clamp(0.0, 255.0) as u8;
+    Rgba([
+        unpremultiply(channels[0]),
+        unpremultiply(channels[1]),
+        unpremultiply(channels[2]),
+        alpha.round().clamp(0.0, 255.0) as u8,
+    ])
+}
+
 #[derive(Clone)]
 struct ImageWrapper {
     img: RgbaImage,
@@ -388,6 +2289,156 @@ impl ImageWrapper {
         Self { img }
     }
 
+    // Resizes the image so that it's longer side is at most of the given size. Returns the
+    // resized image and the factor by which it was scaled.
+    fn downscale(&self, max_size: u32, filter: Option<ResampleFilter>) -> (Self, f32) {
+        let (img, scale) = downscale(&self.img, max_size, filter);
+        (Self { img }, scale)
+    }
+
+    // Same as downscale but the colors are premultiplied by alpha while resizing so that the
+    // transparent pixels around the sticker don't darken its edges.
+    fn thumbnail(&self, max_size: u32, filter: ResampleFilter) -> Self {
+        let longer_side = cmp::max(self.width(), self.height());
+        if longer_side <= max_size {
+            return self.clone();
+        }
+
+        let scale = max_size as f32 / longer_side as f32;
+        let width = cmp::max(1, (self.width() as f32 * scale).round() as u32);
+        let height = cmp::max(1, (self.height() as f32 * scale).round() as u32);
+
+        let premultiplied = Rgba32FImage::from_fn(self.width(), self.height(), |x, y| {
+            Rgba(premultiply(self.img.get_pixel(x, y)).map(|v| v / 255.0))
+        });
+        let resized = imageops::resize(&premultiplied, width, height, filter.filter_type());
+
+        let img = RgbaImage::from_fn(width, height, |x, y| {
+            unpremultiply(resized.get_pixel(x, y).0.map(|v| v * 255.0))
+        });
+        Self { img }
+    }
+
+    // Returns the contents of the rotated rectangle rotated back so that its edges are aligned
+    // with the edges of the image. Pixels are interpolated bilinearly using premultiplied alpha
+    // so that the transparent pixels don't darken the edges.
+    fn extract_rotated(&self, rect: &RotatedRect) -> Self {
+        let width = cmp::max(1, rect.width.ceil() as u32);
+        let height = cmp::max(1, rect.height.ceil() as u32);
+        let (sin, cos) = rect.angle.sin_cos();
+
+        let sample = |x: i64, y: i64| -> [f32; 4] {
+            if x < 0 || y < 0 || x >= self.width() as i64 || y >= self.height() as i64 {
+                return [0.0; 4];
+            }
+            premultiply(self.img.get_pixel(x as u32, y as u32))
+        };
+
+        let img = RgbaImage::from_fn(width, height, |x, y| {
+            let dx = x as f32 + 0.5 - width as f32 / 2.0;
+            let dy = y as f32 + 0.5 - height as f32 / 2.0;
+            let source_x = rect.center.x() + dx * cos - dy * sin - 0.5;
+            let source_y = rect.center.y() + dx * sin + dy * cos - 0.5;
+
+            let left = source_x.floor();
+            let top = source_y.floor();
+            let fx = source_x - left;
+            let fy = source_y - top;
+            let (left, top) = (left as i64, top as i64);
+
+            let mut channels = [0.0; 4];
+            for (x, y, weight) in [
+                (left, top, (1.0 - fx) * (1.0 - fy)),
+                (left + 1, top, fx * (1.0 - fy)),
+                (left, top + 1, (1.0 - fx) * fy),
+                (left + 1, top + 1, fx * fy),
+            ] {
+                for (channel, value) in channels.iter_mut().zip(sample(x, y)) {
+                    *channel += value * weight;
+                }
+            }
+
+            unpremultiply(channels)
+        });
+
+        Self { img }
+    }
+
+    // Maps each pixel of the output back into the image using the homography, which maps the
+    // output to the image, and samples the image there with the filter. Pixels outside of the
+    // image are filled as configured.
+    fn warp_perspective(
+        &self,
+        homography: &Homography,
+        width: u32,
+        height: u32,
+        fill: &VirtualPixel,
+        filter: ResampleFilter,
+    ) -> Result<Self> {
+        let fill = match fill {
+            VirtualPixel::Transparent => Some([0.0; 4]),
+            VirtualPixel::Edge => None,
+            VirtualPixel::Color(color) => {
+                let rgb = Color::from_hex(color)?.rgb();
+                Some(premultiply(&Rgba([rgb.r(), rgb.g(), rgb.b(), 255])))
+            }
+        };
+
+        let (max_x, max_y) = (self.width() as i64 - 1, self.height() as i64 - 1);
+        let sample = |x: i64, y: i64| -> [f32; 4] {
+            let inside = x >= 0 && y >= 0 && x <= max_x && y <= max_y;
+            match fill {
+                Some(fill) if !inside => fill,
+                _ => premultiply(
+                    self.img
+                        .get_pixel(x.clamp(0, max_x) as u32, y.clamp(0, max_y) as u32),
+                ),
+            }
+        };
+
+        let support = filter.support();
+        let mut img = RgbaImage::new(width, height);
+        img.par_enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+            let source = homography.apply(&PreciseXY::new(x as f32, y as f32));
+            let (source_x, source_y) = (source.x(), source.y());
+
+            let mut channels = [0.0; 4];
+            let mut total_weight = 0.0;
+            for row in (source_y - support).ceil() as i64..=(source_y + support).floor() as i64 {
+                let row_weight = filter.weight(row as f32 - source_y);
+                if row_weight == 0.0 {
+                    continue;
+                }
+
+                let columns =
+                    (source_x - support).ceil() as i64..=(source_x + support).floor() as i64;
+                for column in columns {
+                    let weight = row_weight * filter.weight(column as f32 - source_x);
+                    if weight == 0.0 {
+                        continue;
+                    }
+
+                    for (channel, value) in channels.iter_mut().zip(sample(column, row)) {
+                        *channel += value * weight;
+                    }
+                    total_weight += weight;
+                }
+            }
+
+            *pixel = if total_weight > 0.0 {
+                unpremultiply(channels.map(|v| v / total_weight))
+            } else {
+                Rgba([0, 0, 0, 0])
+            };
+        });
+
+        Ok(Self { img })
+    }
+
+    fn count_opaque_pixels(&self) -> usize {
+        self.img.pixels().filter(|v| v.0[3] != 0).count()
+    }
+
     fn save<Q>(&self, path: Q) -> Result<()>
     where
         Q: AsRef<Path>,
@@ -395,6 +2446,38 @@ impl ImageWrapper {
         self.img.save(path)?;
         Ok(())
     }
+
+    // Encodes the image in the given format, JPEG doesn't support transparency so the image is
+    // flattened onto a white background first.
+    fn encode(&self, format: OutputFormat, config: &ExtractionConfig) -> Result<Vec<u8>> {
+        let mut buffer = Cursor::new(vec![]);
+        match format {
+            OutputFormat::Png => {
+                if let Some(max_colors) = config.indexed_png_max_colors
+                    && let Some(indexed) = encode_indexed_png(&self.img, max_colors)?
+                {
+                    return Ok(indexed);
+                }
+                self.img.write_to(&mut buffer, ImageFormat::Png)?
+            }
+            OutputFormat::Webp => self.img.write_to(&mut buffer, ImageFormat::WebP)?,
+            OutputFormat::Jpeg => {
+                let mut img = self.img.clone();
+                for pixel in img.pixels_mut() {
+                    let mut background = Rgba([255, 255, 255, 255]);
+                    background.blend(pixel);
+                    *pixel = background;
+                }
+
+                let mut encoder = JpegEncoder::new_with_quality(
+                    &mut buffer,
+                    config.quality.unwrap_or(DEFAULT_JPEG_QUALITY),
+                );
+                encoder.encode_image(&DynamicImage::ImageRgba8(img).into_rgb8())?;
+            }
+        }
+        Ok(buffer.into_inner())
+    }
 }
 
 impl Image for ImageWrapper {
@@ -454,3 +2537,804 @@ impl Timer {
         info!("Done {} in {}ms", self.name, duration.as_millis());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "tracing")]
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    fn corners(width: f32, height: f32) -> [PreciseXY; 4] {
+        [
+            PreciseXY::new(0.0, 0.0),
+            PreciseXY::new(width, 0.0),
+            PreciseXY::new(0.0, height),
+            PreciseXY::new(width, height),
+        ]
+    }
+
+    #[test]
+    fn warp_perspective_preserves_alpha() {
+        let mut img = RgbaImage::new(60, 40);
+        for x in 20..40 {
+            for y in 10..30 {
+                img.put_pixel(x, y, Rgba([200, 30, 30, 255]));
+            }
+        }
+        let photo = ImageWrapper::new(img);
+        let identity =
+            Homography::from_point_pairs(corners(59.0, 39.0), corners(59.0, 39.0)).unwrap();
+
+        for filter in [
+            ResampleFilter::Nearest,
+            ResampleFilter::Bilinear,
+            ResampleFilter::Lanczos,
+        ] {
+            let corrected = photo
+                .warp_perspective(&identity, 60, 40, &VirtualPixel::Transparent, filter)
+                .unwrap();
+            assert_eq!(corrected.img, photo.img);
+            check_transparency_preserved(&photo, &corrected, &identity).unwrap();
+        }
+
+        let opaque = ImageWrapper::new(RgbaImage::from_pixel(60, 40, Rgba([0, 0, 0, 255])));
+        assert!(check_transparency_preserved(&photo, &opaque, &identity).is_err());
+    }
+
+    #[test]
+    fn open_photo_rejects_region_of_interest_outside_of_photo() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let path = directory.path().join("photo.png");
+        RgbaImage::from_pixel(60, 40, Rgba([90, 90, 90, 255]))
+            .save(&path)
+            .unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut config = ExtractionConfig {
+            region_of_interest: Some("10,10,50,30".parse().unwrap()),
+            ..Default::default()
+        };
+        let (img, _) = open_photo(path, &config).unwrap();
+        assert_eq!((img.width(), img.height()), (50, 30));
+
+        config.region_of_interest = Some("11,10,50,30".parse().unwrap());
+        assert!(open_photo(path, &config).is_err());
+    }
+
+    #[test]
+    fn deterministic_runs_write_identical_files() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+
+        let config = ExtractionConfig {
+            deterministic: true,
+            save_manifest: true,
+            ..Default::default()
+        };
+        let outputs = ["first", "second"].map(|name| {
+            let output = directory.path().join(name);
+            fs::create_dir(&output).unwrap();
+            let manifest = extract(&photo, output.to_str().unwrap(), &config).unwrap();
+
+            let mut files: Vec<(PathBuf, Vec<u8>)> = fs::read_dir(&output)
+                .unwrap()
+                .map(|v| {
+                    let path = v.unwrap().path();
+                    let contents = fs::read(&path).unwrap();
+                    (path.strip_prefix(&output).unwrap().to_path_buf(), contents)
+                })
+                .collect();
+            files.sort();
+            (serde_json::to_string(&manifest).unwrap(), files)
+        });
+
+        assert!(outputs[0].0.contains("dominant_hex"));
+        assert_eq!(outputs[0].1.len(), 3);
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    // Writes a photo of a sheet with three stickers into the directory and returns its path.
+    fn sheet_photo(directory: &Path) -> String {
+        let photo = directory.join("photo.png");
+        let mut img = RgbaImage::from_pixel(400, 300, Rgba([120, 125, 130, 255]));
+        let mut fill = |left: u32, top: u32, width: u32, height: u32, color: [u8; 3]| {
+            for x in left..left + width {
+                for y in top..top + height {
+                    img.put_pixel(x, y, Rgba([color[0], color[1], color[2], 255]));
+                }
+            }
+        };
+        for (x, y) in [(20, 17), (367, 23), (27, 273), (360, 267)] {
+            fill(x, y, 10, 10, [250, 250, 250]);
+        }
+        fill(100, 100, 50, 40, [200, 30, 30]);
+        fill(200, 83, 33, 67, [30, 60, 200]);
+        fill(267, 183, 47, 33, [240, 200, 20]);
+        img.save(&photo).unwrap();
+        photo.to_str().unwrap().to_string()
+    }
+
+    // Extracts the stickers from the photo into a new directory and returns the manifest
+    // together with the sorted names of the written files.
+    fn extract_into(
+        photo: &str,
+        output: &Path,
+        config: &ExtractionConfig,
+    ) -> Result<(Manifest, Vec<String>)> {
+        fs::create_dir(output)?;
+        let manifest = extract(photo, output.to_str().unwrap(), config)?;
+        let mut files: Vec<String> = fs::read_dir(output)?
+            .map(|v| v.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        Ok((manifest, files))
+    }
+
+    #[test]
+    fn negative_scans_are_inverted() {
+        let mut img = RgbaImage::from_pixel(200, 200, Rgba([30, 160, 30, 255]));
+        for (left, top) in [(5, 5), (180, 5), (5, 180), (180, 180)] {
+            for x in left..left + 12 {
+                for y in top..top + 12 {
+                    img.put_pixel(x, y, Rgba([240, 240, 240, 255]));
+                }
+            }
+        }
+        imageops::invert(&mut img);
+        let directory = tempfile::TempDir::new().unwrap();
+        let path = directory.path().join("photo.png");
+        img.save(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let open = |invert_colors: InvertColors| {
+            let config = ExtractionConfig {
+                invert_colors,
+                ..Default::default()
+            };
+            let (img, _) = open_photo(path, &config).unwrap();
+            find_markers(&img, &config)
+        };
+        assert!(open(InvertColors::Never).is_err());
+        assert!(open(InvertColors::Always).is_ok());
+        assert!(open(InvertColors::Auto).is_ok());
+    }
+
+    #[test]
+    fn lab_distance_removes_gradient_background() {
+        let mut img = RgbaImage::new(120, 120);
+        for (x, _, pixel) in img.enumerate_pixels_mut() {
+            let value = 150 + x as u8 / 2;
+            *pixel = Rgba([value, value, value, 255]);
+        }
+        for (left, top) in [(2, 2), (110, 2), (2, 110), (110, 110)] {
+            for x in left..left + 8 {
+                for y in top..top + 8 {
+                    img.put_pixel(x, y, Rgba([240, 240, 240, 255]));
+                }
+            }
+        }
+        for x in 40..70 {
+            for y in 40..70 {
+                img.put_pixel(x, y, Rgba([200, 120, 140, 255]));
+            }
+        }
+        let mut img = ImageWrapper::new(img);
+
+        let config = ExtractionConfig {
+            lab_distance_threshold: Some(8.0),
+            ..Default::default()
+        };
+        let markers = Markers::find(&img).unwrap();
+        let background = Background::analyse(&img, &markers, &config).unwrap();
+        let mut preview = PreviewImagesSaver::new("photo", &config).unwrap();
+        remove_background(&mut img, &markers, &background, &config, &mut preview).unwrap();
+
+        assert!(img.get_pixel(20, 60).is_transparent());
+        assert!(img.get_pixel(100, 60).is_transparent());
+        assert!(!img.get_pixel(55, 55).is_transparent());
+    }
+
+    #[test]
+    fn preview_images_are_written_into_preview_directory() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let preview_directory = directory.path().join("previews");
+        let img = ImageWrapper::new(RgbaImage::new(4, 4));
+
+        let config = ExtractionConfig {
+            preview_directory: preview_directory.clone(),
+            ..Default::default()
+        };
+        let mut preview = PreviewImagesSaver::new("photo", &config).unwrap();
+        preview.save(&img, "markers").unwrap();
+        assert!(!preview_directory.exists());
+
+        let config = ExtractionConfig {
+            save_intermediate_images: true,
+            ..config
+        };
+        let mut preview = PreviewImagesSaver::new("photo", &config).unwrap();
+        preview.save(&img, "markers").unwrap();
+        preview.save(&img, "background").unwrap();
+        assert!(preview_directory.join("photo_stage0_markers.png").exists());
+        assert!(
+            preview_directory
+                .join("photo_stage1_background.png")
+                .exists()
+        );
+    }
+
+    #[test]
+    fn full_sheet_is_written_on_request() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+
+        let (_, files) = extract_into(
+            &photo,
+            &directory.path().join("stickers"),
+            &ExtractionConfig::default(),
+        )
+        .unwrap();
+        assert!(!files.contains(&"photo_full.png".to_string()));
+
+        let config = ExtractionConfig {
+            save_full_image: true,
+            ..Default::default()
+        };
+        let output = directory.path().join("full");
+        let (_, files) = extract_into(&photo, &output, &config).unwrap();
+        assert!(files.contains(&"photo_full.png".to_string()));
+        let full = image::open(output.join("photo_full.png"))
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(full.get_pixel(0, 0).0[3], 0);
+    }
+
+    #[test]
+    fn manifest_describes_extracted_stickers() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            save_manifest: true,
+            ..Default::default()
+        };
+        let output = directory.path().join("stickers");
+        let (manifest, files) = extract_into(&photo, &output, &config).unwrap();
+
+        let mut colors: Vec<&str> = manifest
+            .stickers
+            .iter()
+            .map(|v| v.dominant_hex.as_str())
+            .collect();
+        colors.sort();
+        assert_eq!(colors, ["#1e3cc8", "#c81e1e", "#f0c814"]);
+        for sticker in &manifest.stickers {
+            assert!(files.contains(&sticker.file));
+            assert!(sticker.opaque_pixels > 0);
+            assert!(sticker.opaque_pixels <= (sticker.width * sticker.height) as usize);
+        }
+
+        let path = directory.path().join("manifest.json");
+        manifest.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(
+            serde_json::to_string(&loaded).unwrap(),
+            serde_json::to_string(&manifest).unwrap()
+        );
+    }
+
+    #[test]
+    fn sheet_without_stickers_is_empty() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = directory.path().join("blank.png");
+        let mut img = RgbaImage::from_pixel(400, 300, Rgba([120, 125, 130, 255]));
+        for (left, top) in [(20, 17), (367, 23), (27, 273), (360, 267)] {
+            for x in left..left + 10 {
+                for y in top..top + 10 {
+                    img.put_pixel(x, y, Rgba([250, 250, 250, 255]));
+                }
+            }
+        }
+        img.save(&photo).unwrap();
+
+        let err = extract(
+            photo.to_str().unwrap(),
+            directory.path().to_str().unwrap(),
+            &ExtractionConfig::default(),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<EmptySheet>().is_some());
+    }
+
+    #[test]
+    fn background_samples_are_included_in_manifest_on_request() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let mut config = ExtractionConfig {
+            save_manifest: true,
+            ..Default::default()
+        };
+
+        let (manifest, _) = extract_into(&photo, &directory.path().join("a"), &config).unwrap();
+        assert!(manifest.background_samples.is_empty());
+
+        config.save_background_samples = true;
+        let (manifest, _) = extract_into(&photo, &directory.path().join("b"), &config).unwrap();
+        assert!(!manifest.background_samples.is_empty());
+        for sample in &manifest.background_samples {
+            assert_eq!(sample.color_hex, "#787d82");
+            assert!(sample.left + sample.width <= 400 && sample.top + sample.height <= 300);
+        }
+    }
+
+    #[test]
+    fn reexport_writes_same_stickers() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            save_manifest: true,
+            ..Default::default()
+        };
+        let (manifest, files) =
+            extract_into(&photo, &directory.path().join("extracted"), &config).unwrap();
+
+        let output = directory.path().join("reexported");
+        fs::create_dir(&output).unwrap();
+        let reexported = reexport(&photo, &manifest, output.to_str().unwrap(), &config).unwrap();
+        let mut reexported_files: Vec<String> = fs::read_dir(&output)
+            .unwrap()
+            .map(|v| v.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        reexported_files.sort();
+        assert_eq!(reexported_files, files);
+        assert_eq!(
+            serde_json::to_string(&reexported.stickers).unwrap(),
+            serde_json::to_string(&manifest.stickers).unwrap()
+        );
+
+        let other = directory.path().join("other.png");
+        fs::copy(&photo, &other).unwrap();
+        let other = other.to_str().unwrap();
+        assert!(reexport(other, &manifest, output.to_str().unwrap(), &config).is_err());
+    }
+
+    #[test]
+    fn warp_perspective_fills_outside_of_photo() {
+        let photo = ImageWrapper::new(RgbaImage::from_pixel(60, 40, Rgba([10, 20, 30, 255])));
+        let identity =
+            Homography::from_point_pairs(corners(59.0, 39.0), corners(59.0, 39.0)).unwrap();
+        let outside = |fill: VirtualPixel| {
+            let corrected = photo
+                .warp_perspective(&identity, 80, 60, &fill, ResampleFilter::Nearest)
+                .unwrap();
+            assert_eq!(corrected.img.get_pixel(30, 20).0, [10, 20, 30, 255]);
+            corrected.img.get_pixel(70, 50).0
+        };
+
+        assert_eq!(outside(VirtualPixel::Transparent)[3], 0);
+        assert_eq!(outside(VirtualPixel::Edge), [10, 20, 30, 255]);
+        assert_eq!(outside("#ff8000".parse().unwrap()), [255, 128, 0, 255]);
+        assert!("#ff80".parse::<VirtualPixel>().is_err());
+    }
+
+    #[test]
+    fn colorful_pixels_are_exempt_from_removal() {
+        let background = Color::from(RGB::new(128, 128, 128)).lab();
+        let colorful = AlphaColor::new_opaque(RGB::new(200, 90, 90).into());
+        let gray = AlphaColor::new_opaque(RGB::new(140, 140, 140).into());
+
+        let mut config = ExtractionConfig::default();
+        assert!(!is_chroma_exempt(&config, &colorful, &background));
+
+        config.chroma_exemption = Some(20.0);
+        assert!(is_chroma_exempt(&config, &colorful, &background));
+        assert!(!is_chroma_exempt(&config, &gray, &background));
+    }
+
+    #[test]
+    fn stickers_are_written_as_jpeg() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            output_format: OutputFormat::Jpeg,
+            ..Default::default()
+        };
+        let output = directory.path().join("stickers");
+        let (_, files) = extract_into(&photo, &output, &config).unwrap();
+
+        assert_eq!(files.len(), 3);
+        for file in files {
+            assert!(file.ends_with(".jpg"));
+            let img = image::open(output.join(file)).unwrap();
+            assert_eq!(img.color(), image::ColorType::Rgb8);
+        }
+    }
+
+    #[test]
+    fn lower_jpeg_quality_produces_smaller_files() {
+        let img = RgbaImage::from_fn(64, 48, |x, y| {
+            Rgba([(x * 4) as u8, (y * 5) as u8, ((x * y) % 256) as u8, 255])
+        });
+        let crop = ImageWrapper::new(img);
+        let encode = |quality: u8| {
+            let config = ExtractionConfig {
+                output_format: OutputFormat::Jpeg,
+                quality: Some(quality),
+                ..Default::default()
+            };
+            crop.encode(OutputFormat::Jpeg, &config).unwrap()
+        };
+
+        assert!(encode(50).len() < encode(95).len());
+    }
+
+    #[test]
+    fn sticker_corners_are_mapped_back_to_photo() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            save_manifest: true,
+            ..Default::default()
+        };
+        let (manifest, _) = extract_into(&photo, &directory.path().join("out"), &config).unwrap();
+
+        let red = manifest
+            .stickers
+            .iter()
+            .find(|v| v.dominant_hex == "#c81e1e")
+            .unwrap();
+        let corners: Vec<(f32, f32)> = red.photo_corners.iter().map(|v| (v.x, v.y)).collect();
+        let expected = [
+            (100.0, 100.0),
+            (150.0, 100.0),
+            (100.0, 140.0),
+            (150.0, 140.0),
+        ];
+        assert_eq!(corners.len(), 4);
+        for (corner, expected) in corners.iter().zip(expected) {
+            assert!((corner.0 - expected.0).abs() < 3.0 && (corner.1 - expected.1).abs() < 3.0);
+        }
+    }
+
+    #[test]
+    fn only_marker_bands_are_cropped() {
+        let marker = |left: u32, top: u32, size: u32| {
+            let area = Area::new(left, top, size, size);
+            Marker::new(area.clone(), area.center(), false)
+        };
+        let markers = Markers::new(
+            marker(0, 0, 10),
+            marker(92, 0, 8),
+            marker(0, 88, 12),
+            marker(90, 90, 10),
+        )
+        .unwrap();
+        let identity =
+            Homography::from_point_pairs(corners(99.0, 99.0), corners(99.0, 99.0)).unwrap();
+
+        let insets = marker_crop_insets(&markers, &identity, 100, 100, 2).unwrap();
+        assert_eq!(
+            (insets.left, insets.top, insets.right, insets.bottom),
+            (14, 12, 12, 14)
+        );
+        assert!(marker_crop_insets(&markers, &identity, 100, 100, 40).is_err());
+    }
+
+    #[test]
+    fn stickers_are_written_once_per_target() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            output_targets: vec![
+                OutputTarget {
+                    format: OutputFormat::Png,
+                    directory: "png".into(),
+                },
+                OutputTarget {
+                    format: OutputFormat::Jpeg,
+                    directory: "jpeg".into(),
+                },
+            ],
+            ..Default::default()
+        };
+        let output = directory.path().join("out");
+        let (_, files) = extract_into(&photo, &output, &config).unwrap();
+        assert_eq!(files, ["jpeg", "png"]);
+
+        for (target, extension) in [("png", "png"), ("jpeg", "jpg")] {
+            let files: Vec<PathBuf> = fs::read_dir(output.join(target))
+                .unwrap()
+                .map(|v| v.unwrap().path())
+                .collect();
+            assert_eq!(files.len(), 3);
+            assert!(files.iter().all(|v| v.extension().unwrap() == extension));
+        }
+    }
+
+    #[test]
+    fn marker_image_is_written_uncropped() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            save_marker_image: true,
+            ..Default::default()
+        };
+        let output = directory.path().join("out");
+        let (_, files) = extract_into(&photo, &output, &config).unwrap();
+        assert!(files.contains(&"photo_markers.png".to_string()));
+
+        // The centers of the markers are in the corners of the perspective corrected sheet.
+        let img = image::open(output.join("photo_markers.png"))
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(img.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        let (right, bottom) = (img.width() - 1, img.height() - 1);
+        assert_eq!(img.get_pixel(right, bottom).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn corrected_sheet_has_proportions_of_real_sheet() {
+        let mut config = ExtractionConfig::default();
+        assert_eq!(corrected_sheet_size(400, 300, &config), (400, 300));
+
+        config.sheet_size = Some("210x297".parse().unwrap());
+        assert_eq!(corrected_sheet_size(400, 300, &config), (212, 300));
+        assert_eq!(corrected_sheet_size(400, 1000, &config), (400, 566));
+
+        // The aspect ratio takes precedence over the size of the sheet.
+        config.sheet_aspect_ratio = Some(2.0);
+        assert_eq!(corrected_sheet_size(400, 300, &config), (400, 200));
+    }
+
+    #[test]
+    fn stickers_are_written_into_archive() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            archive: true,
+            ..Default::default()
+        };
+        let output = directory.path().join("out");
+        let (manifest, files) = extract_into(&photo, &output, &config).unwrap();
+        assert_eq!(files, ["photo.zip"]);
+
+        let file = fs::File::open(output.join("photo.zip")).unwrap();
+        let mut archive = zip::ZipArchive::new(file).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(String::from).collect();
+        names.sort();
+        let mut expected: Vec<String> = manifest.stickers.iter().map(|v| v.file.clone()).collect();
+        expected.push(MANIFEST_FILE_NAME.to_string());
+        expected.sort();
+        assert_eq!(names, expected);
+
+        let contents =
+            std::io::read_to_string(archive.by_name(MANIFEST_FILE_NAME).unwrap()).unwrap();
+        let archived: Manifest = serde_json::from_str(&contents).unwrap();
+        assert_eq!(archived.stickers.len(), 3);
+    }
+
+    #[test]
+    fn perspective_margin_is_added_around_sheet() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let marker_image = |perspective_margin: u32| {
+            let config = ExtractionConfig {
+                save_marker_image: true,
+                perspective_margin,
+                ..Default::default()
+            };
+            let output = directory.path().join(format!("margin{perspective_margin}"));
+            extract_into(&photo, &output, &config).unwrap();
+            image::open(output.join("photo_markers.png"))
+                .unwrap()
+                .to_rgba8()
+        };
+
+        let without_margin = marker_image(0);
+        let with_margin = marker_image(10);
+        assert_eq!(with_margin.width(), without_margin.width() + 20);
+        assert_eq!(with_margin.height(), without_margin.height() + 20);
+        assert_eq!(with_margin.get_pixel(10, 10).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn thumbnails_are_written_next_to_stickers() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let config = ExtractionConfig {
+            thumbnail_size: Some(8),
+            ..Default::default()
+        };
+        let output = directory.path().join("out");
+        let (_, files) = extract_into(&photo, &output, &config).unwrap();
+
+        let thumbnails: Vec<&String> = files.iter().filter(|v| v.ends_with("_thumb.png")).collect();
+        assert_eq!(thumbnails.len(), 3);
+        assert_eq!(files.len(), 6);
+        for thumbnail in thumbnails {
+            let img = image::open(output.join(thumbnail)).unwrap();
+            assert_eq!(cmp::max(img.width(), img.height()), 8);
+        }
+    }
+
+    #[test]
+    fn fail_fast_stops_at_first_failure() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let broken = directory.path().join("broken.png");
+        fs::write(&broken, "not an image").unwrap();
+        let paths = [photo, broken.to_str().unwrap().to_string()];
+        let output = directory.path().join("out");
+        fs::create_dir(&output).unwrap();
+        let output = output.to_str().unwrap();
+
+        let results = extract_all(&paths, output, &ExtractionConfig::default(), false).unwrap();
+        assert!(results[0].is_ok());
+        let err = format!("{:#}", results[1].as_ref().err().unwrap());
+        assert!(err.starts_with("error processing"));
+
+        let config = ExtractionConfig::default();
+        assert!(extract_all(&paths, output, &config, true).is_err());
+        assert!(config.cancel.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn file_subcommand_writes_into_output_directory() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let output = directory.path().join("new").join("stickers");
+        let matches = command()
+            .try_get_matches_from([
+                "extractor",
+                "file",
+                "--manifest",
+                "--out",
+                output.to_str().unwrap(),
+                &photo,
+            ])
+            .unwrap();
+        let (_, sub_matches) = matches.subcommand().unwrap();
+
+        extract_file(sub_matches).unwrap();
+
+        let manifest = Manifest::load(output.join(MANIFEST_FILE_NAME)).unwrap();
+        assert_eq!(manifest.stickers.len(), 3);
+        for sticker in &manifest.stickers {
+            assert!(output.join(&sticker.file).exists());
+            assert!(!Path::new(&sticker.file).exists());
+        }
+        assert!(!Path::new(MANIFEST_FILE_NAME).exists());
+    }
+
+    // Records how many times each span was entered and the names of the fields recorded by
+    // each span.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct SpanCollector {
+        entered: Arc<Mutex<HashMap<&'static str, usize>>>,
+        fields: Arc<Mutex<HashMap<&'static str, Vec<String>>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl<S> tracing_subscriber::Layer<S> for SpanCollector
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Visitor(Vec<String>);
+            impl tracing::field::Visit for Visitor {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0.push(format!("{}={:?}", field.name(), value));
+                }
+            }
+
+            let mut visitor = Visitor(vec![]);
+            attrs.record(&mut visitor);
+            self.fields
+                .lock()
+                .unwrap()
+                .entry(attrs.metadata().name())
+                .or_default()
+                .extend(visitor.0);
+        }
+
+        fn on_enter(&self, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            let name = ctx.span(id).unwrap().name();
+            *self.entered.lock().unwrap().entry(name).or_default() += 1;
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn extraction_stages_are_traced() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let collector = SpanCollector::default();
+        let subscriber = tracing_subscriber::registry().with(collector.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            extract_into(
+                &photo,
+                &directory.path().join("out"),
+                &ExtractionConfig::default(),
+            )
+            .unwrap();
+        });
+
+        let entered = collector.entered.lock().unwrap();
+        for name in [
+            "extract",
+            "find_markers",
+            "extract_sheet",
+            "prepare_sheet",
+            "export_stickers",
+        ] {
+            assert_eq!(entered.get(name), Some(&1), "{name}");
+        }
+        let fields = collector.fields.lock().unwrap();
+        assert!(fields["export_stickers"].contains(&"stickers=3".to_string()));
+    }
+
+    #[test]
+    fn prepared_image_is_reused_with_different_removal_settings() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let photo = sheet_photo(directory.path());
+        let img = image::open(&photo).unwrap().to_rgba8();
+        let config = ExtractionConfig {
+            save_intermediate_images: true,
+            preview_directory: directory.path().join("previews"),
+            ..Default::default()
+        };
+        let markers = find_markers(&ImageWrapper::new(img.clone()), &config).unwrap();
+        assert_eq!(markers.len(), 1);
+
+        let mut preview = PreviewImagesSaver::new("photo", &config).unwrap();
+        let mut previews = vec![];
+        let image = PreparedImage::new(img, &markers[0], &config, |img, name| {
+            previews.push(name.to_string());
+            preview.save_image(img, name)
+        })
+        .unwrap();
+
+        let sheet = SheetSource::new(&photo, "photo");
+        let output = directory.path().to_str().unwrap();
+        let default = prepare_sheet(&image, None, &sheet, output, &config, &mut preview).unwrap();
+        let config = ExtractionConfig {
+            delta_e_threshold: Some(45.0),
+            ..config
+        };
+        let lab_delta_e =
+            prepare_sheet(&image, None, &sheet, output, &config, &mut preview).unwrap();
+
+        // Each stage which analyses the photo writes a preview so the previews written by
+        // both removals show that neither of them repeated the analysis.
+        let count = |suffix: &str| {
+            fs::read_dir(&config.preview_directory)
+                .unwrap()
+                .filter(|v| {
+                    let name = v.as_ref().unwrap().file_name();
+                    name.to_string_lossy().ends_with(suffix)
+                })
+                .count()
+        };
+        assert_eq!(previews, ["markers", "background_samples"]);
+        assert_eq!(count("_markers.png"), 1);
+        assert_eq!(count("_background_samples.png"), 1);
+        assert_eq!(count("_corrected_perspective.png"), 2);
+        let opaque = |img: &RgbaImage| img.pixels().filter(|v| v.0[3] == 255).count();
+        assert!(opaque(&lab_delta_e.img.img) < opaque(&default.img.img));
+    }
+}
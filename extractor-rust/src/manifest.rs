@@ -0,0 +1,149 @@
+use crate::errors::Result;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+// Describes the stickers extracted during a single run.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub stickers: Vec<ManifestSticker>,
+
+    // Sheets the stickers were extracted from.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sheets: Vec<ManifestSheet>,
+
+    // Areas used to sample the background color, only present if requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub background_samples: Vec<ManifestBackgroundSample>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestSticker {
+    // Name of the written image relative to the output directory or the root of the archive.
+    pub file: String,
+
+    // Path to the photo the sticker was extracted from.
+    pub source: String,
+
+    // Name of the sheet the sticker was extracted from.
+    #[serde(default)]
+    pub sheet: String,
+
+    // Position of the sticker in the sheet after correcting the perspective and cropping it.
+    #[serde(default)]
+    pub left: u32,
+    #[serde(default)]
+    pub top: u32,
+
+    pub column: usize,
+    pub row: usize,
+
+    // Position of the sticker in reading order starting from 1.
+    #[serde(default)]
+    pub index: usize,
+
+    // Most common color of the sticker formatted as #rrggbb.
+    pub dominant_hex: String,
+
+    pub width: u32,
+    pub height: u32,
+
+    // Number of pixels which aren't transparent.
+    pub opaque_pixels: usize,
+
+    // Physical size of the sticker, present if the size of the sheet was known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width_mm: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height_mm: Option<f32>,
+
+    // Corners of the sticker in the photo before correcting the perspective in the following
+    // order: top left, top right, bottom left, bottom right.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub photo_corners: Vec<ManifestPoint>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestSheet {
+    // Stickers are named after the sheet.
+    pub name: String,
+
+    // Path to the photo the sheet was found in.
+    pub source: String,
+
+    // Markers in the following order: top left, top right, bottom left, bottom right. The
+    // positions are given in the photo before rotating it if the sheet was sideways.
+    pub markers: Vec<ManifestMarker>,
+
+    // Average color of the background samples formatted as #rrggbb, used to find sheets
+    // photographed under different lighting. Not present if a chroma key was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_hex: Option<String>,
+
+    // Reliability of the extraction from 0 to 1 combining the quality of the markers, the shape
+    // of the quad they form, the sharpness of the photo and how much of the background remained.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestMarker {
+    pub x: f32,
+    pub y: f32,
+
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+
+    // Estimated markers weren't found in the photo but derived from the other markers.
+    pub estimated: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ManifestBackgroundSample {
+    // Name of the sheet the sample was taken from, stickers are named after it.
+    pub sheet: String,
+
+    // Path to the photo the sample was taken from.
+    pub source: String,
+
+    // Position and size of the sampled area in the photo, after rotating it if the sheet was
+    // photographed sideways.
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+
+    // Average color of the sampled area formatted as #rrggbb.
+    pub color_hex: String,
+}
+
+impl Manifest {
+    pub fn extend(&mut self, other: Manifest) {
+        self.stickers.extend(other.stickers);
+        self.sheets.extend(other.sheets);
+        self.background_samples.extend(other.background_samples);
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("error reading manifest {}", path.display()))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(path, contents)
+            .with_context(|| format!("error writing manifest {}", path.display()))?;
+        Ok(())
+    }
+}